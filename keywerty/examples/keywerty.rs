@@ -100,6 +100,7 @@ fn build_mapper() -> impl LayerMapper<u8, String> {
     let conf = keys::HoldKeyConf {
         tap: tap_action.into(),
         hold: hold_action.into(),
+        retro_tap: false,
     };
     map.insert((default_layer, 1), keys::KeyConf::Hold(conf));
 
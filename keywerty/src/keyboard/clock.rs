@@ -0,0 +1,53 @@
+/// Module defines the time sources keyboards rely on for time based behaviors.
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Source of the current time.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Clock reading the system's monotonic clock, used unless set otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock which only moves when told to, eg to replay recorded events faster than real time
+/// or to test time based behaviors without sleeping.
+///
+/// Clones share the same time, so a keyboard can be given a clone while the original
+/// is used to move the time forward.
+#[derive(Debug, Clone)]
+pub struct SimulatedClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl SimulatedClock {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Rc::new(Cell::new(start)),
+        }
+    }
+
+    /// Move the time to `now`, which must not be earlier than the current time.
+    pub fn set(&self, now: Instant) {
+        debug_assert!(now >= self.now.get(), "simulated time went backwards");
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
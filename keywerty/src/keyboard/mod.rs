@@ -2,13 +2,27 @@
 ///
 /// The logical keyboard interface was drawn out considering
 /// types which match an USB HID keyboard, that is, key scan codes are 1 byte.
+mod clock;
 mod smkb;
 
+use std::time::Instant;
+
+use crate::mapper::LayerId;
+
+pub use clock::Clock;
+pub use clock::SimulatedClock;
+pub use clock::SystemClock;
+pub use smkb::KeyOverride;
+pub use smkb::KeyRepeat;
+pub use smkb::RepeatRate;
 pub use smkb::SMKeyboard;
 pub use smkb::SMKeyboardSettings;
+pub use smkb::WordModeConf;
 
 /// Set of events that a keyboard respond to. (inputs)
 #[derive(PartialEq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Event<Id> {
     KeyPress(Id),
     KeyRelease(Id),
@@ -35,11 +49,44 @@ impl<Id> Event<Id> {
 
 /// Set of actions a keyboard perform as consequence of inputs. (outputs)
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Action<T> {
     SendCode(T),
     Stop(T),
 }
 
+/// Changes of a keyboard's state its users may want to display (eg in a status bar).
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Notification<KeyId> {
+    /// Keys are now mapped on the given layer
+    LayerChanged(LayerId),
+    /// Caps Word was turned on or off
+    CapsWord(bool),
+    /// Num Word was turned on with the given layer, or off
+    NumWord(Option<LayerId>),
+    /// The key's behavior waits for more events to decide what it does (eg a hold key)
+    BehaviorPending(KeyId),
+    /// The pending behavior of the key was decided, `None` if the keyboard was reset before
+    BehaviorResolved(KeyId, Option<Behavior>),
+}
+
+/// What a key with several behaviors (eg tap or hold) ended up doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Behavior {
+    Tap,
+    Hold,
+    /// One of the tiers of a long press key was reached
+    LongPress,
+}
+
+/// Called with each notification of a keyboard, see `Keyboard::set_notification_hook`.
+pub type NotificationHook<KeyId> = Box<dyn FnMut(Notification<KeyId>)>;
+
 /// Abstraction for a physical keyboard.
 /// Conceptually a keyboard contains keys, each identified with an id.
 ///
@@ -50,4 +97,35 @@ pub enum Action<T> {
 /// it goes to a different state and produces an output
 pub trait Keyboard<KeyId, T> {
     fn transition(&mut self, event: Event<KeyId>) -> Vec<Action<T>>;
+
+    /// Return the instant by which the keyboard should receive a `Poll` event, if any.
+    /// Time based behaviors (eg auto repeat) rely on being polled in a timely manner,
+    /// runtimes are expected to poll the keyboard no later than the returned deadline.
+    fn next_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Bring the keyboard back to its initial state, as if no key had ever been pressed.
+    /// Return the actions stopping every output the keyboard was holding.
+    ///
+    /// Runtimes reset keyboards whose keys can no longer be released,
+    /// eg when the physical keyboard is unplugged.
+    fn reset(&mut self) -> Vec<Action<T>> {
+        Vec::new()
+    }
+
+    /// Return the layer keys are currently mapped on, `None` for keyboards without layers.
+    fn active_layer(&self) -> Option<LayerId> {
+        None
+    }
+
+    /// Make `layer` the layer keys are mapped on when no other layer is pushed.
+    /// Return whether the keyboard has layers, keyboards without layers ignore the call.
+    fn set_default_layer(&mut self, _layer: LayerId) -> bool {
+        false
+    }
+
+    /// Call `hook` whenever the keyboard's state changes, replacing the previous hook.
+    /// Keyboards without notifications ignore the call.
+    fn set_notification_hook(&mut self, _hook: NotificationHook<KeyId>) {}
 }
@@ -3,6 +3,7 @@ use std::time::{Duration, Instant};
 
 use super::KeyStateMachine;
 use crate::keyboard::smkb::helpers;
+use crate::keyboard::Behavior;
 use crate::keyboard::Event;
 use crate::keys::HoldKeyConf;
 use crate::keys::KeyActionSet;
@@ -23,6 +24,8 @@ pub struct EagerHoldKSM<KeyId, T> {
     key_conf: HoldKeyConf<T>,
     timer_start: Instant,
     release_delay: Duration,
+    interrupted: bool,
+    behavior: Option<Behavior>,
     cleanup_actions: [KeyActionSet<T>; 1],
 }
 
@@ -34,6 +37,8 @@ impl<KeyId, T> EagerHoldKSM<KeyId, T> {
             timer_start: Instant::now(),
             state: State::Created,
             key_conf: conf,
+            interrupted: false,
+            behavior: None,
             cleanup_actions: [KeyActionSet::default()],
         };
     }
@@ -52,7 +57,7 @@ where
         matches!(self.state, State::Finished)
     }
 
-    fn transition(&mut self, event: &Event<KeyId>) -> Option<KeyActionSet<T>> {
+    fn transition(&mut self, event: &Event<KeyId>, now: Instant) -> Option<KeyActionSet<T>> {
         if self.is_finished() {
             return None;
         }
@@ -63,7 +68,7 @@ where
             State::Created => {
                 if helpers::is_watched_key_pressed(self, event) {
                     // send hold action
-                    self.timer_start = Instant::now();
+                    self.timer_start = now;
                     self.state = State::Waiting;
                     let action = &self.key_conf.hold;
                     self.cleanup_actions[0] = action.invert();
@@ -75,16 +80,19 @@ where
             State::Waiting => {
                 // held till timeout or other key was pressed
                 // noop
-                if (Instant::now() - self.timer_start) >= self.release_delay
-                    || matches!(event, Event::KeyPress(key_id) if key_id != watched_key)
-                {
+                let other_key_pressed =
+                    matches!(event, Event::KeyPress(key_id) if key_id != watched_key);
+                if (now - self.timer_start) >= self.release_delay || other_key_pressed {
                     self.state = State::Hold;
+                    self.behavior = Some(Behavior::Hold);
+                    self.interrupted = other_key_pressed;
                     None
                 }
                 // key released before timer means tap
                 // undo the held key
                 else if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
                     self.state = State::Released;
+                    self.behavior = Some(Behavior::Tap);
                     Some(self.key_conf.hold.invert())
                 } else {
                     None
@@ -98,10 +106,23 @@ where
             }
             State::Hold => {
                 // if key was held, wait until its released
-                if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
-                    self.state = State::Finished;
+                if matches!(event, Event::KeyPress(key_id) if key_id != watched_key) {
+                    self.interrupted = true;
+                    None
+                }
+                // a lone long press undoes the hold, tap is sent as if released early
+                else if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
+                    if self.key_conf.retro_tap && !self.interrupted {
+                        self.state = State::Released;
+                        self.behavior = Some(Behavior::Tap);
+                        Some(self.key_conf.hold.invert())
+                    } else {
+                        self.state = State::Finished;
+                        None
+                    }
+                } else {
+                    None
                 }
-                None
             }
             State::Finished => None,
         }
@@ -110,6 +131,10 @@ where
     fn get_cleanup_actions(&self) -> &[KeyActionSet<T>] {
         &self.cleanup_actions
     }
+
+    fn behavior(&self) -> Option<Behavior> {
+        self.behavior
+    }
 }
 
 #[cfg(test)]
@@ -124,15 +149,19 @@ mod tests {
     const hold_key_code: u8 = 20;
 
     fn build_ksm() -> EagerHoldKSM<u8, u8> {
+        build_ksm_with_retro_tap(false)
+    }
+
+    fn build_ksm_with_retro_tap(retro_tap: bool) -> EagerHoldKSM<u8, u8> {
         let timeout = Duration::from_millis(2);
         let tap_action = KeyActionSet::Single(KeyAction::SendKey(tap_key_code));
         let hold_action = KeyActionSet::Single(KeyAction::SendKey(hold_key_code));
         let conf = HoldKeyConf {
             tap: tap_action,
             hold: hold_action,
+            retro_tap,
         };
-        let mut machine = EagerHoldKSM::new(timeout, watched_key, conf);
-        machine
+        EagerHoldKSM::new(timeout, watched_key, conf)
     }
 
     #[test]
@@ -140,7 +169,7 @@ mod tests {
         let mut machine = build_ksm();
 
         // When I transition machine by sending key press event
-        let opt = machine.transition(&Event::KeyPress(watched_key));
+        let opt = machine.transition(&Event::KeyPress(watched_key), Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
@@ -150,19 +179,19 @@ mod tests {
         // When I poll before timeout
         for i in [0..2] {
             sleep(Duration::from_nanos(500));
-            let opt = machine.transition(&Event::Poll);
+            let opt = machine.transition(&Event::Poll, Instant::now());
             assert!(opt.is_none());
             assert!(!machine.is_finished());
         }
 
         // When I poll after timeout
         sleep(Duration::from_millis(2));
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
         // when machine key is released
-        let opt = machine.transition(&Event::KeyRelease(watched_key));
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(machine.is_finished());
         // cleanup action is the inverse of hold action
@@ -179,7 +208,7 @@ mod tests {
         let mut machine = build_ksm();
 
         // When I transition machine by sending key press event
-        let opt = machine.transition(&Event::KeyPress(watched_key));
+        let opt = machine.transition(&Event::KeyPress(watched_key), Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
@@ -187,7 +216,7 @@ mod tests {
         assert!(!machine.is_finished());
 
         // When I release key then it undoes hold action
-        let opt = machine.transition(&Event::KeyRelease(watched_key));
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::StopKey(hold_key_code))
@@ -195,7 +224,7 @@ mod tests {
         assert!(!machine.is_finished());
 
         // when i poll then it sends tap action and machine is finished
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(tap_key_code))
@@ -210,4 +239,58 @@ mod tests {
             KeyActionSet::Single(KeyAction::StopKey(tap_key_code))
         );
     }
+
+    #[test]
+    fn test_retro_tap_releasing_after_timeout_undoes_hold_and_sends_tap() {
+        let mut machine = build_ksm_with_retro_tap(true);
+
+        // When I press the watched key and poll after timeout
+        machine.transition(&Event::KeyPress(watched_key), Instant::now());
+        sleep(Duration::from_millis(3));
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert!(opt.is_none());
+
+        // When I release the watched key then the eager hold is undone
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::StopKey(hold_key_code))
+        );
+        assert!(!machine.is_finished());
+
+        // when machine is polled then it taps and finishes
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(tap_key_code))
+        );
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(tap_key_code))
+        );
+    }
+
+    #[test]
+    fn test_retro_tap_is_skipped_if_other_key_was_pressed() {
+        let mut machine = build_ksm_with_retro_tap(true);
+
+        // When I press the watched key and poll after timeout
+        machine.transition(&Event::KeyPress(watched_key), Instant::now());
+        sleep(Duration::from_millis(3));
+        machine.transition(&Event::Poll, Instant::now());
+
+        // When another key is pressed while holding
+        let opt = machine.transition(&Event::KeyPress(255), Instant::now());
+        assert!(opt.is_none());
+
+        // when machine key is released then it finishes as a regular hold
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(hold_key_code))
+        );
+    }
 }
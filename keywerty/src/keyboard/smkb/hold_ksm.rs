@@ -2,6 +2,7 @@
 use std::time::{Duration, Instant};
 
 use super::KeyStateMachine;
+use crate::keyboard::Behavior;
 use crate::keyboard::Event;
 use crate::keys::HoldKeyConf;
 use crate::keys::KeyActionSet;
@@ -12,6 +13,7 @@ enum State {
     Waiting,
     Hold,
     Released,
+    RetroTap,
     Finished,
 }
 
@@ -22,6 +24,8 @@ pub struct HoldKSM<KeyId, T> {
     key_conf: HoldKeyConf<T>,
     timer_start: Instant,
     release_delay: Duration,
+    interrupted: bool,
+    behavior: Option<Behavior>,
     cleanup_actions: [KeyActionSet<T>; 1],
 }
 
@@ -33,6 +37,8 @@ impl<KeyId, T> HoldKSM<KeyId, T> {
             timer_start: Instant::now(),
             state: State::Created,
             key_conf: conf,
+            interrupted: false,
+            behavior: None,
             cleanup_actions: [KeyActionSet::default()],
         };
     }
@@ -51,7 +57,7 @@ where
         matches!(self.state, State::Finished)
     }
 
-    fn transition(&mut self, event: &Event<KeyId>) -> Option<KeyActionSet<T>> {
+    fn transition(&mut self, event: &Event<KeyId>, now: Instant) -> Option<KeyActionSet<T>> {
         if self.is_finished() {
             return None;
         }
@@ -63,7 +69,7 @@ where
         match self.state {
             State::Created => {
                 if matches!(event, Event::KeyPress(key_id) if key_id == watched_key) {
-                    self.timer_start = Instant::now();
+                    self.timer_start = now;
                     self.state = State::Waiting;
                 }
                 None
@@ -71,16 +77,19 @@ where
             State::Waiting => {
                 // pressed till timeout or other key was pressed
                 // hold
-                if (Instant::now() - self.timer_start) >= self.release_delay
-                    || matches!(event, Event::KeyPress(key_id) if key_id != watched_key)
-                {
+                let other_key_pressed =
+                    matches!(event, Event::KeyPress(key_id) if key_id != watched_key);
+                if (now - self.timer_start) >= self.release_delay || other_key_pressed {
                     self.state = State::Hold;
+                    self.behavior = Some(Behavior::Hold);
+                    self.interrupted = other_key_pressed;
                     self.cleanup_actions[0] = self.key_conf.hold.invert();
                     Some(self.key_conf.hold.clone())
                 }
                 // key released before timer means tap
                 else if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
                     self.state = State::Released;
+                    self.behavior = Some(Behavior::Tap);
                     self.cleanup_actions[0] = self.key_conf.tap.invert();
                     Some(self.key_conf.tap.clone())
                 } else {
@@ -94,10 +103,29 @@ where
             }
             State::Hold => {
                 // if key was held, wait until its released
-                if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
-                    self.state = State::Finished;
+                if matches!(event, Event::KeyPress(key_id) if key_id != watched_key) {
+                    self.interrupted = true;
+                    None
                 }
-                None
+                // a lone long press undoes the hold and taps on release
+                else if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
+                    if self.key_conf.retro_tap && !self.interrupted {
+                        self.state = State::RetroTap;
+                        self.behavior = Some(Behavior::Tap);
+                        Some(self.key_conf.hold.invert())
+                    } else {
+                        self.state = State::Finished;
+                        None
+                    }
+                } else {
+                    None
+                }
+            }
+            State::RetroTap => {
+                // hold was undone, send the tap action
+                self.state = State::Finished;
+                self.cleanup_actions[0] = self.key_conf.tap.invert();
+                Some(self.key_conf.tap.clone())
             }
             State::Finished => None,
         }
@@ -106,6 +134,10 @@ where
     fn get_cleanup_actions(&self) -> &[KeyActionSet<T>] {
         &self.cleanup_actions
     }
+
+    fn behavior(&self) -> Option<Behavior> {
+        self.behavior
+    }
 }
 
 #[cfg(test)]
@@ -120,15 +152,19 @@ mod tests {
     const hold_key_code: u8 = 20;
 
     fn build_ksm() -> HoldKSM<u8, u8> {
+        build_ksm_with_retro_tap(false)
+    }
+
+    fn build_ksm_with_retro_tap(retro_tap: bool) -> HoldKSM<u8, u8> {
         let timeout = Duration::from_millis(2);
         let tap_action = KeyActionSet::Single(KeyAction::SendKey(tap_key_code));
         let hold_action = KeyActionSet::Single(KeyAction::SendKey(hold_key_code));
         let conf = HoldKeyConf {
             tap: tap_action,
             hold: hold_action,
+            retro_tap,
         };
-        let mut machine = HoldKSM::new(timeout, watched_key, conf);
-        machine
+        HoldKSM::new(timeout, watched_key, conf)
     }
 
     #[test]
@@ -136,7 +172,7 @@ mod tests {
         let mut machine = build_ksm();
 
         // When I transition machine by sending key press event
-        let opt = machine.transition(&Event::KeyPress(watched_key));
+        let opt = machine.transition(&Event::KeyPress(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
@@ -144,14 +180,14 @@ mod tests {
         // And machine is polled
         for i in [0..2] {
             sleep(Duration::from_nanos(500));
-            let opt = machine.transition(&Event::Poll);
+            let opt = machine.transition(&Event::Poll, Instant::now());
             assert!(opt.is_none());
             assert!(!machine.is_finished());
         }
 
         // when i poll after timeout
         sleep(Duration::from_millis(2));
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
@@ -159,12 +195,12 @@ mod tests {
         assert!(!machine.is_finished());
 
         // when machine is polled
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
         // when machine key is released
-        let opt = machine.transition(&Event::KeyRelease(watched_key));
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(machine.is_finished());
     }
@@ -174,12 +210,12 @@ mod tests {
         let mut machine = build_ksm();
 
         // When I start machine by sending key press event
-        let opt = machine.transition(&Event::KeyPress(watched_key));
+        let opt = machine.transition(&Event::KeyPress(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
         // When another key is pressed
-        let opt = machine.transition(&Event::KeyPress(255));
+        let opt = machine.transition(&Event::KeyPress(255), Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
@@ -187,12 +223,12 @@ mod tests {
         assert!(!machine.is_finished());
 
         // when machine is polled
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
         // when machine key is released
-        let opt = machine.transition(&Event::KeyRelease(watched_key));
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(machine.is_finished());
     }
@@ -202,12 +238,12 @@ mod tests {
         let mut machine = build_ksm();
 
         // When I start machine by sending key press event
-        let opt = machine.transition(&Event::KeyPress(watched_key));
+        let opt = machine.transition(&Event::KeyPress(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(!machine.is_finished());
 
         // When I release the watched key
-        let opt = machine.transition(&Event::KeyRelease(watched_key));
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert_eq!(
             opt.unwrap(),
             KeyActionSet::Single(KeyAction::SendKey(tap_key_code))
@@ -215,7 +251,82 @@ mod tests {
         assert!(!machine.is_finished());
 
         // when machine is polled
-        let opt = machine.transition(&Event::Poll);
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn test_retro_tap_releasing_after_timeout_undoes_hold_and_sends_tap() {
+        let mut machine = build_ksm_with_retro_tap(true);
+
+        // When I press the watched key and poll after timeout
+        machine.transition(&Event::KeyPress(watched_key), Instant::now());
+        sleep(Duration::from_millis(3));
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
+        );
+
+        // When I release the watched key then the hold action is undone
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::StopKey(hold_key_code))
+        );
+        assert!(!machine.is_finished());
+
+        // when machine is polled then it taps and finishes
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(tap_key_code))
+        );
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(tap_key_code))
+        );
+    }
+
+    #[test]
+    fn test_retro_tap_is_skipped_if_other_key_was_pressed_during_hold() {
+        let mut machine = build_ksm_with_retro_tap(true);
+
+        // When I press the watched key and poll after timeout
+        machine.transition(&Event::KeyPress(watched_key), Instant::now());
+        sleep(Duration::from_millis(3));
+        machine.transition(&Event::Poll, Instant::now());
+
+        // When another key is pressed while holding
+        let opt = machine.transition(&Event::KeyPress(255), Instant::now());
+        assert!(opt.is_none());
+
+        // when machine key is released then it finishes as a regular hold
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(hold_key_code))
+        );
+    }
+
+    #[test]
+    fn test_retro_tap_is_skipped_if_hold_was_triggered_by_other_key() {
+        let mut machine = build_ksm_with_retro_tap(true);
+
+        // When other key is pressed, the hold action is triggered
+        machine.transition(&Event::KeyPress(watched_key), Instant::now());
+        let opt = machine.transition(&Event::KeyPress(255), Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(hold_key_code))
+        );
+
+        // when machine key is released then it finishes as a regular hold
+        let opt = machine.transition(&Event::KeyRelease(watched_key), Instant::now());
         assert!(opt.is_none());
         assert!(machine.is_finished());
     }
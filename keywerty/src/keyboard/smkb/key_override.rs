@@ -0,0 +1,28 @@
+//! Key overrides, which replace a key output while some modifier is held
+use crate::keys::KeyActionSet;
+
+/// Replaces the output of a key while any of the `mods` outputs is held.
+///
+/// The held modifiers are suppressed (ie stopped) while the replacement is performed
+/// and resent once the overridden key is released, which allows
+/// mappings such as "Shift + Backspace = Delete".
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KeyOverride<T> {
+    /// Modifier outputs that trigger the override, any of them suffices.
+    pub mods: Vec<T>,
+
+    /// Output to replace.
+    pub key: T,
+
+    /// Actions performed instead of `key`.
+    pub replacement: KeyActionSet<T>,
+}
+
+/// An override currently replacing a held output.
+#[derive(Debug)]
+pub struct ActiveOverride<T> {
+    pub key: T,
+    pub replacement: KeyActionSet<T>,
+    pub suppressed_mods: Vec<T>,
+}
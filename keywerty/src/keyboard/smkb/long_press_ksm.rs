@@ -0,0 +1,305 @@
+/// Module for Key State Machine implementation for the `LongPress` key configuration
+use std::time::{Duration, Instant};
+
+use super::KeyStateMachine;
+use crate::keyboard::smkb::helpers;
+use crate::keyboard::Behavior;
+use crate::keyboard::Event;
+use crate::keys::KeyActionSet;
+use crate::keys::LongPressKeyConf;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Created,
+    Waiting,
+    Released,
+    Held,
+    Finished,
+}
+
+#[derive(Debug)]
+pub struct LongPressKSM<KeyId, T> {
+    watched_key: KeyId,
+    state: State,
+    key_conf: LongPressKeyConf<T>,
+    timer_start: Instant,
+    behavior: Option<Behavior>,
+    cleanup_actions: [KeyActionSet<T>; 1],
+}
+
+impl<KeyId, T> LongPressKSM<KeyId, T> {
+    pub fn new(watched_key: KeyId, mut conf: LongPressKeyConf<T>) -> Self {
+        conf.tiers.sort_by_key(|tier| tier.threshold);
+        Self {
+            watched_key,
+            timer_start: Instant::now(),
+            state: State::Created,
+            key_conf: conf,
+            behavior: None,
+            cleanup_actions: [KeyActionSet::default()],
+        }
+    }
+
+    /// Return the action for the longest tier reached after `elapsed`,
+    /// defaulting to the tap action.
+    fn resolve_action(&self, elapsed: Duration) -> &KeyActionSet<T> {
+        self.key_conf
+            .tiers
+            .iter()
+            .rev()
+            .find(|tier| elapsed >= tier.threshold)
+            .map(|tier| &tier.action)
+            .unwrap_or(&self.key_conf.tap)
+    }
+
+    /// Return whether a tier was reached after `elapsed`, or the key was tapped.
+    fn resolve_behavior(&self, elapsed: Duration) -> Behavior {
+        if self
+            .key_conf
+            .tiers
+            .iter()
+            .any(|tier| elapsed >= tier.threshold)
+        {
+            Behavior::LongPress
+        } else {
+            Behavior::Tap
+        }
+    }
+
+    fn is_final_tier_reached(&self, elapsed: Duration) -> bool {
+        self.key_conf
+            .tiers
+            .last()
+            .map(|tier| elapsed >= tier.threshold)
+            .unwrap_or(false)
+    }
+}
+
+impl<KeyId, T> KeyStateMachine<KeyId, T> for LongPressKSM<KeyId, T>
+where
+    KeyId: PartialEq,
+    T: Clone,
+{
+    fn get_watched_key(&self) -> &KeyId {
+        &self.watched_key
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, State::Finished)
+    }
+
+    fn transition(&mut self, event: &Event<KeyId>, now: Instant) -> Option<KeyActionSet<T>> {
+        if self.is_finished() {
+            return None;
+        }
+
+        let watched_key = self.get_watched_key();
+
+        match self.state {
+            State::Created => {
+                if helpers::is_watched_key_pressed(self, event) {
+                    self.timer_start = now;
+                    self.state = State::Waiting;
+                }
+                None
+            }
+            State::Waiting => {
+                let elapsed = now - self.timer_start;
+                // released key resolves to the longest tier reached
+                if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
+                    let action = self.resolve_action(elapsed).clone();
+                    self.state = State::Released;
+                    self.behavior = Some(self.resolve_behavior(elapsed));
+                    self.cleanup_actions[0] = action.invert();
+                    Some(action)
+                }
+                // another key pressed resolves to the tier reached so far, so that the
+                // watched key isn't sent after it when typing fast
+                else if matches!(event, Event::KeyPress(key_id) if key_id != watched_key) {
+                    let action = self.resolve_action(elapsed).clone();
+                    self.state = State::Held;
+                    self.behavior = Some(self.resolve_behavior(elapsed));
+                    self.cleanup_actions[0] = action.invert();
+                    Some(action)
+                }
+                // there's nothing left to wait for once the final tier is reached
+                else if self.is_final_tier_reached(elapsed) {
+                    let action = self.resolve_action(elapsed).clone();
+                    self.state = State::Held;
+                    self.behavior = Some(Behavior::LongPress);
+                    self.cleanup_actions[0] = action.invert();
+                    Some(action)
+                } else {
+                    None
+                }
+            }
+            State::Released => {
+                // after released, go to finished
+                self.state = State::Finished;
+                None
+            }
+            State::Held => {
+                // action was sent while the key is down, wait until it's released
+                if matches!(event, Event::KeyRelease(key_id) if key_id == watched_key) {
+                    self.state = State::Finished;
+                }
+                None
+            }
+            State::Finished => None,
+        }
+    }
+
+    fn get_cleanup_actions(&self) -> &[KeyActionSet<T>] {
+        &self.cleanup_actions
+    }
+
+    fn behavior(&self) -> Option<Behavior> {
+        self.behavior
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyAction;
+    use crate::keys::LongPressTier;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    const WATCHED_KEY: u8 = 1;
+    const TAP_KEY_CODE: u8 = 10;
+    const FIRST_TIER_KEY_CODE: u8 = 20;
+    const FINAL_TIER_KEY_CODE: u8 = 30;
+
+    fn build_ksm() -> LongPressKSM<u8, u8> {
+        // tiers are declared out of order on purpose
+        let conf = LongPressKeyConf {
+            tap: KeyActionSet::Single(KeyAction::SendKey(TAP_KEY_CODE)),
+            tiers: vec![
+                LongPressTier {
+                    threshold: Duration::from_millis(20),
+                    action: KeyActionSet::Single(KeyAction::SendKey(FINAL_TIER_KEY_CODE)),
+                },
+                LongPressTier {
+                    threshold: Duration::from_millis(5),
+                    action: KeyActionSet::Single(KeyAction::SendKey(FIRST_TIER_KEY_CODE)),
+                },
+            ],
+        };
+        LongPressKSM::new(WATCHED_KEY, conf)
+    }
+
+    #[test]
+    fn test_releasing_before_first_tier_sends_tap() {
+        let mut machine = build_ksm();
+
+        let opt = machine.transition(&Event::KeyPress(WATCHED_KEY), Instant::now());
+        assert!(opt.is_none());
+        assert_eq!(machine.behavior(), None);
+
+        // When I release the watched key right away
+        let opt = machine.transition(&Event::KeyRelease(WATCHED_KEY), Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(TAP_KEY_CODE))
+        );
+        assert_eq!(machine.behavior(), Some(Behavior::Tap));
+        assert!(!machine.is_finished());
+
+        // when machine is polled then it's finished and cleanup undoes tap
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(TAP_KEY_CODE))
+        );
+    }
+
+    #[test]
+    fn test_releasing_between_tiers_sends_first_tier() {
+        let mut machine = build_ksm();
+        machine.transition(&Event::KeyPress(WATCHED_KEY), Instant::now());
+
+        // When I poll after the first threshold nothing is resolved yet
+        sleep(Duration::from_millis(6));
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert!(opt.is_none());
+
+        // When I release the watched key then the first tier is sent
+        let opt = machine.transition(&Event::KeyRelease(WATCHED_KEY), Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(FIRST_TIER_KEY_CODE))
+        );
+        assert_eq!(machine.behavior(), Some(Behavior::LongPress));
+
+        machine.transition(&Event::Poll, Instant::now());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(FIRST_TIER_KEY_CODE))
+        );
+    }
+
+    #[test]
+    fn test_reaching_final_tier_sends_it_while_key_is_held() {
+        let mut machine = build_ksm();
+        machine.transition(&Event::KeyPress(WATCHED_KEY), Instant::now());
+
+        // When I poll after the final threshold then the final tier is sent
+        sleep(Duration::from_millis(21));
+        let opt = machine.transition(&Event::Poll, Instant::now());
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(FINAL_TIER_KEY_CODE))
+        );
+        assert!(!machine.is_finished());
+
+        // When I release the watched key then the machine is finished
+        let opt = machine.transition(&Event::KeyRelease(WATCHED_KEY), Instant::now());
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(FINAL_TIER_KEY_CODE))
+        );
+    }
+
+    #[test]
+    fn test_pressing_another_key_resolves_tier_reached() {
+        let mut machine = build_ksm();
+        let start = Instant::now();
+        machine.transition(&Event::KeyPress(WATCHED_KEY), start);
+
+        // When I press another key before the first tier then the tap is sent right away
+        let opt = machine.transition(&Event::KeyPress(WATCHED_KEY + 1), start);
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(TAP_KEY_CODE))
+        );
+        assert_eq!(machine.behavior(), Some(Behavior::Tap));
+
+        // When I release the watched key then nothing else is sent
+        let opt = machine.transition(&Event::KeyRelease(WATCHED_KEY), start);
+        assert!(opt.is_none());
+        assert!(machine.is_finished());
+        assert_eq!(
+            machine.get_cleanup_actions()[0],
+            KeyActionSet::Single(KeyAction::StopKey(TAP_KEY_CODE))
+        );
+
+        // When another key is pressed after the first tier then the first tier is sent
+        let mut machine = build_ksm();
+        machine.transition(&Event::KeyPress(WATCHED_KEY), start);
+        let opt = machine.transition(
+            &Event::KeyPress(WATCHED_KEY + 1),
+            start + Duration::from_millis(6),
+        );
+        assert_eq!(
+            opt.unwrap(),
+            KeyActionSet::Single(KeyAction::SendKey(FIRST_TIER_KEY_CODE))
+        );
+        assert_eq!(machine.behavior(), Some(Behavior::LongPress));
+    }
+}
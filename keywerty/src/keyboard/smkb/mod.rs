@@ -1,6 +1,9 @@
 mod eager_hold_ksm;
 mod helpers;
 mod hold_ksm;
+mod key_override;
+mod long_press_ksm;
+mod repeat;
 /// Keyboard trait implementation using state machines
 ///
 /// Some key activation modes are stateful in nature and depends
@@ -11,23 +14,37 @@ mod hold_ksm;
 /// Each time a stateful key is pressed, a new state machine should be created
 /// to handle that state.
 mod tap_ksm;
+mod word_mode;
 //mod double_tap_ksm;
 //mod double_tap_hold_ksm;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::Action;
+use super::Behavior;
+use super::Clock;
 use super::Event;
 use super::Keyboard;
+use super::Notification;
+use super::NotificationHook;
+use super::SystemClock;
 use crate::keys;
 use crate::keys::KeyActionSet;
 use crate::mapper::LayerMapper;
 use eager_hold_ksm::EagerHoldKSM;
 use hold_ksm::HoldKSM;
+use key_override::ActiveOverride;
+pub use key_override::KeyOverride;
+use long_press_ksm::LongPressKSM;
+use repeat::ActiveRepeat;
+pub use repeat::{KeyRepeat, RepeatRate};
 use tap_ksm::TapKSM;
+pub use word_mode::WordModeConf;
+use word_mode::WordModes;
 //use double_tap_ksm::DoubleTapKSM;
 //use double_tap_hold_ksm::DoubleTapHoldKSM;
 
@@ -65,7 +82,8 @@ use log;
 pub trait KeyStateMachine<KeyId, T> {
     /// Steps the state machine from the current events
     /// Each step may return a KeyActionSet.
-    fn transition<'a>(&mut self, event: &Event<KeyId>) -> Option<KeyActionSet<T>>;
+    /// `now` is the time of the keyboard's clock, machines measure time with it only.
+    fn transition<'a>(&mut self, event: &Event<KeyId>, now: Instant) -> Option<KeyActionSet<T>>;
 
     /// Return the key for which the KSM is reponsible.
     fn get_watched_key(&self) -> &KeyId;
@@ -77,17 +95,43 @@ pub trait KeyStateMachine<KeyId, T> {
     /// Fetch actions that should performed to cleanup the state machine.
     /// Cleanup is done after a machine is finished and before it is dropped.
     fn get_cleanup_actions(&self) -> &[KeyActionSet<T>];
+
+    /// Return the behavior the key resolved to, `None` while it's undecided.
+    fn behavior(&self) -> Option<Behavior>;
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct SMKeyboardSettings {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
     pub hold_ksm_delay: Duration,
 
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
     pub dtksm_retap_delay: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
     pub dtksm_hold_delay: Duration,
 
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
     pub dthksm_retap_delay: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
     pub dthksm_hold_delay: Duration,
+
+    /// Auto repeat rate for key outputs.
+    /// `None` disables the keyboard's auto repeat, leaving it up to the OS.
+    pub repeat: Option<RepeatRate>,
+
+    /// Auto repeat configuration for Tap keys
+    pub tap_repeat: KeyRepeat,
+    /// Auto repeat configuration for Hold and Eager Hold keys,
+    /// disabled by default since their hold output is usually a modifier
+    pub hold_repeat: KeyRepeat,
+    /// Auto repeat configuration for Long Press keys
+    pub long_press_repeat: KeyRepeat,
+
+    /// Idle time after which active word modes (Caps Word, Num Word) are turned off
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
+    pub word_mode_timeout: Duration,
 }
 
 impl Default for SMKeyboardSettings {
@@ -100,6 +144,13 @@ impl Default for SMKeyboardSettings {
 
             dthksm_retap_delay: Duration::from_millis(100),
             dthksm_hold_delay: Duration::from_millis(100),
+
+            repeat: None,
+            tap_repeat: KeyRepeat::Inherit,
+            hold_repeat: KeyRepeat::Disabled,
+            long_press_repeat: KeyRepeat::Inherit,
+
+            word_mode_timeout: Duration::from_secs(5),
         }
     }
 }
@@ -117,6 +168,29 @@ impl Default for SMKeyboardSettings {
 /// Each machine may generate an action which shall be handled by SMKb.
 ///
 /// Once a KSM is finished, SMKb will perform any cleanup actions and proceed to drop it.
+///
+/// SMKb may also auto repeat the output of held keys.
+/// The last key to send an output is repeated, as long as it's held, according to its `KeyRepeat`
+/// configuration, which is looked up by key, then by behavior and finally in the keyboard settings.
+/// Repetitions are emitted as the keyboard transitions, therefore it must be polled
+/// by the instant given by `Keyboard::next_deadline`.
+///
+/// Lastly, SMKb implements the Caps Word and Num Word modes, which are activated through
+/// `KeyAction`s and configured with a `WordModeConf`.
+/// While Caps Word is active letters are capitalized and while Num Word is active its layer
+/// is kept in the layer stack.
+/// Both modes are turned off once a code that doesn't belong to the word is sent, or after
+/// being idle for `SMKeyboardSettings::word_mode_timeout`.
+///
+/// SMKb keeps track of the outputs sent by keys and not yet stopped ("held outputs").
+/// Held outputs are used to resolve `KeyOverride`s and `KeyConf::ModMorph` keys.
+///
+/// Releases of keys which SMKb never saw pressed (eg keys held down when the keyboard
+/// was created or reset) are ignored.
+///
+/// SMKb reports changes of its active layer and word modes through its notification hook,
+/// as well as keys whose behavior is pending: keys whose machine produced nothing when pressed
+/// (eg hold keys) are pending until their machine produces actions or finishes.
 pub struct SMKeyboard<KeyId, T, Mapper> {
     default_layer: keys::LayerId,
     layer_mapper: Mapper,
@@ -124,12 +198,26 @@ pub struct SMKeyboard<KeyId, T, Mapper> {
     state_machines: HashMap<KeyId, Box<dyn KeyStateMachine<KeyId, T>>>,
     state_machine_order: Vec<KeyId>,
     settings: SMKeyboardSettings,
+    key_repeat: HashMap<KeyId, KeyRepeat>,
+    repeat_rates: HashMap<KeyId, RepeatRate>,
+    active_repeat: Option<ActiveRepeat<KeyId, T>>,
+    word_conf: Option<WordModeConf<T>>,
+    word_modes: WordModes,
+    held_outputs: Vec<T>,
+    key_overrides: Vec<KeyOverride<T>>,
+    active_overrides: Vec<ActiveOverride<T>>,
+    pressed_keys: HashSet<KeyId>,
+    pending_keys: Vec<KeyId>,
+    notification_hook: Option<NotificationHook<KeyId>>,
+    /// Active layer and word modes as last notified
+    notified_state: (keys::LayerId, bool, Option<keys::LayerId>),
+    clock: Box<dyn Clock>,
 }
 
 impl<KeyId, T, Mapper> SMKeyboard<KeyId, T, Mapper>
 where
     KeyId: Copy + Eq + Hash + Debug + 'static,
-    T: Clone + 'static,
+    T: Clone + PartialEq + 'static,
     Mapper: LayerMapper<KeyId, T>,
 {
     pub fn new(
@@ -144,6 +232,134 @@ where
             state_machines: HashMap::new(),
             layer_stack: Vec::new(),
             state_machine_order: Vec::new(),
+            key_repeat: HashMap::new(),
+            repeat_rates: HashMap::new(),
+            active_repeat: None,
+            word_conf: None,
+            word_modes: WordModes::new(),
+            held_outputs: Vec::new(),
+            key_overrides: Vec::new(),
+            active_overrides: Vec::new(),
+            pressed_keys: HashSet::new(),
+            pending_keys: Vec::new(),
+            notification_hook: None,
+            notified_state: (default_layer, false, None),
+            clock: Box::new(SystemClock),
+        }
+    }
+
+    /// Add a key override rule.
+    /// Rules are matched in the order they were added.
+    pub fn add_key_override(&mut self, key_override: KeyOverride<T>) {
+        self.key_overrides.push(key_override);
+    }
+
+    fn is_any_output_held(&self, outputs: &[T]) -> bool {
+        outputs
+            .iter()
+            .any(|output| self.held_outputs.contains(output))
+    }
+
+    /// Set the configuration used by the Caps Word and Num Word modes.
+    pub fn set_word_mode_conf(&mut self, conf: WordModeConf<T>) {
+        self.word_conf = Some(conf);
+    }
+
+    /// Measure time with `clock` rather than the system's clock, eg a `SimulatedClock`
+    /// to replay recorded events. Deadlines given by `Keyboard::next_deadline` are on that clock.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set the auto repeat configuration for a key.
+    /// It takes precedence over the behavior and keyboard configuration.
+    pub fn set_key_repeat(&mut self, key_id: KeyId, repeat: KeyRepeat) {
+        self.key_repeat.insert(key_id, repeat);
+    }
+
+    /// Replace the keyboard's configuration (layer mapper, settings, default layer,
+    /// key repeat, word mode configuration and key overrides) with the one of `other`.
+    ///
+    /// The keyboard state is kept: active state machines finish with the configuration
+    /// they were created with, held outputs stay held and the layer stack is preserved.
+    /// Word modes are turned off if `other` has no word mode configuration.
+    pub fn reconfigure(&mut self, other: Self) {
+        self.default_layer = other.default_layer;
+        self.layer_mapper = other.layer_mapper;
+        self.settings = other.settings;
+        self.key_repeat = other.key_repeat;
+        self.word_conf = other.word_conf;
+        self.key_overrides = other.key_overrides;
+
+        if self.word_conf.is_none() {
+            self.word_modes.caps_word = false;
+            self.stop_num_word();
+        }
+        self.notify_state_changes();
+        log::debug!("keyboard reconfigured");
+    }
+
+    fn notify(&mut self, notification: Notification<KeyId>) {
+        if let Some(hook) = self.notification_hook.as_mut() {
+            hook(notification);
+        }
+    }
+
+    /// Notify the changes of the active layer and word modes since they were last notified.
+    fn notify_state_changes(&mut self) {
+        let (layer, caps_word, num_word) = self.notified_state;
+        self.notified_state = (
+            self.get_active_layer(),
+            self.word_modes.caps_word,
+            self.word_modes.num_word,
+        );
+
+        if self.word_modes.caps_word != caps_word {
+            self.notify(Notification::CapsWord(self.word_modes.caps_word));
+        }
+        if self.word_modes.num_word != num_word {
+            self.notify(Notification::NumWord(self.word_modes.num_word));
+        }
+        if self.get_active_layer() != layer {
+            self.notify(Notification::LayerChanged(self.get_active_layer()));
+        }
+    }
+
+    /// Track the keys whose behavior is pending.
+    ///
+    /// A key is pending if its machine, created for `new_machine`, produced no action,
+    /// and is resolved once its machine produces actions or finishes.
+    fn track_pending_behaviors(
+        &mut self,
+        new_machine: Option<KeyId>,
+        transition_actions: &[(KeyId, KeyActionSet<T>)],
+    ) {
+        let is_resolved = |key_id: &KeyId| {
+            transition_actions.iter().any(|(id, _)| id == key_id)
+                || self
+                    .state_machines
+                    .get(key_id)
+                    .map(|machine| machine.is_finished())
+                    .unwrap_or(true)
+        };
+
+        let (resolved, pending): (Vec<KeyId>, Vec<KeyId>) = self
+            .pending_keys
+            .iter()
+            .partition(|key_id| is_resolved(key_id));
+        let new_pending = new_machine.filter(|key_id| !is_resolved(key_id));
+
+        self.pending_keys = pending;
+        for key_id in resolved {
+            let behavior = self
+                .state_machines
+                .get(&key_id)
+                .and_then(|machine| machine.behavior());
+            self.notify(Notification::BehaviorResolved(key_id, behavior));
+        }
+        if let Some(key_id) = new_pending {
+            self.pending_keys.push(key_id);
+            self.notify(Notification::BehaviorPending(key_id));
         }
     }
 
@@ -154,22 +370,204 @@ where
             .unwrap_or(self.default_layer)
     }
 
-    /// receive key id and action, mutate keyboard and possibly generate action
-    fn handle_key_action(&mut self, key_action: &keys::KeyAction<T>) -> Option<Action<T>> {
+    /// receive key id and action, mutate keyboard and possibly generate actions
+    fn handle_key_action(&mut self, key_action: &keys::KeyAction<T>, actions: &mut Vec<Action<T>>) {
         match key_action {
-            keys::KeyAction::SendKey(data) => Some(Action::SendCode(data.clone())),
-            keys::KeyAction::StopKey(data) => Some(Action::Stop(data.clone())),
+            keys::KeyAction::SendKey(data) => {
+                self.held_outputs.push(data.clone());
+                if !self.start_key_override(data, actions) {
+                    self.handle_word_modes(data, actions);
+                }
+            }
+            keys::KeyAction::StopKey(data) => {
+                if let Some(position) = self.held_outputs.iter().position(|held| held == data) {
+                    self.held_outputs.remove(position);
+                }
+                self.stop_output(data, actions);
+            }
             keys::KeyAction::PushLayer(layer_id) => {
                 self.layer_stack.push(*layer_id);
-                None
             }
             keys::KeyAction::PopLayer(_) => {
                 // FIXME this is incorrect as it will only pop
                 // the last layer in the stack.
                 self.layer_stack.pop();
-                None
             }
-            keys::KeyAction::NoOp => None,
+            keys::KeyAction::CapsWord => {
+                if self.word_conf.is_none() {
+                    log::error!("Ignored Caps Word activation: missing word mode configuration");
+                    return;
+                }
+                self.word_modes.caps_word = !self.word_modes.caps_word;
+                self.word_modes.touch(self.clock.now());
+                log::debug!("caps word active: {:?}", self.word_modes.caps_word);
+            }
+            keys::KeyAction::NumWord(layer_id) => {
+                if self.word_modes.num_word.is_some() {
+                    self.stop_num_word();
+                } else {
+                    self.layer_stack.push(*layer_id);
+                    self.word_modes.num_word = Some(*layer_id);
+                    self.word_modes.touch(self.clock.now());
+                    log::debug!("num word active: layer_id={:?}", layer_id);
+                }
+            }
+            keys::KeyAction::NoOp => (),
+        }
+    }
+
+    /// Replace `data` if it matches a key override whose modifiers are held.
+    /// Return whether `data` was overridden.
+    fn start_key_override(&mut self, data: &T, actions: &mut Vec<Action<T>>) -> bool {
+        let key_override = self
+            .key_overrides
+            .iter()
+            .find(|key_override| {
+                key_override.key == *data && self.is_any_output_held(&key_override.mods)
+            })
+            .cloned();
+
+        let key_override = match key_override {
+            Some(key_override) => key_override,
+            None => return false,
+        };
+
+        // modifiers suppressed by other overrides are already stopped
+        let suppressed_mods = key_override
+            .mods
+            .iter()
+            .filter(|mod_data| self.held_outputs.contains(mod_data))
+            .filter(|mod_data| {
+                !self
+                    .active_overrides
+                    .iter()
+                    .any(|active| active.suppressed_mods.contains(mod_data))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        log::debug!("key override started");
+        for mod_data in suppressed_mods.iter() {
+            actions.push(Action::Stop(mod_data.clone()));
+        }
+        for replacement_action in key_override.replacement.get_actions().iter() {
+            self.handle_replacement_action(replacement_action, actions);
+        }
+
+        self.active_overrides.push(ActiveOverride {
+            key: data.clone(),
+            replacement: key_override.replacement,
+            suppressed_mods,
+        });
+        true
+    }
+
+    /// Stop `data`, undoing its override, if any, and resending modifiers it suppressed.
+    fn stop_output(&mut self, data: &T, actions: &mut Vec<Action<T>>) {
+        let is_suppressed = self
+            .active_overrides
+            .iter()
+            .any(|active| active.suppressed_mods.contains(data));
+        let override_position = self
+            .active_overrides
+            .iter()
+            .position(|active| active.key == *data);
+
+        if let Some(position) = override_position {
+            log::debug!("key override stopped");
+            let active = self.active_overrides.remove(position);
+            for replacement_action in active.replacement.invert().get_actions().iter() {
+                self.handle_replacement_action(replacement_action, actions);
+            }
+            for mod_data in active.suppressed_mods.into_iter() {
+                if self.held_outputs.contains(&mod_data) {
+                    actions.push(Action::SendCode(mod_data));
+                }
+            }
+        } else if is_suppressed {
+            // a suppressed modifier was released, it has already been stopped
+            for active in self.active_overrides.iter_mut() {
+                active.suppressed_mods.retain(|mod_data| mod_data != data);
+            }
+        } else {
+            actions.push(Action::Stop(data.clone()));
+        }
+    }
+
+    /// Perform an action from an override replacement.
+    /// Replacement outputs are sent as is, other actions are handled as usual.
+    fn handle_replacement_action(
+        &mut self,
+        key_action: &keys::KeyAction<T>,
+        actions: &mut Vec<Action<T>>,
+    ) {
+        match key_action {
+            keys::KeyAction::SendKey(data) => actions.push(Action::SendCode(data.clone())),
+            keys::KeyAction::StopKey(data) => actions.push(Action::Stop(data.clone())),
+            other => self.handle_key_action(other, actions),
+        }
+    }
+
+    /// Send `data`, applying the active word modes and turning them off if `data` breaks the word.
+    fn handle_word_modes(&mut self, data: &T, actions: &mut Vec<Action<T>>) {
+        let conf = match &self.word_conf {
+            Some(conf) if self.word_modes.is_active() => conf,
+            _ => {
+                actions.push(Action::SendCode(data.clone()));
+                return;
+            }
+        };
+
+        if self.word_modes.caps_word {
+            if conf.caps_word_letters.contains(data) {
+                actions.push(Action::SendCode(conf.shift.clone()));
+                actions.push(Action::SendCode(data.clone()));
+                actions.push(Action::Stop(conf.shift.clone()));
+            } else {
+                if !conf.caps_word_continue.contains(data) {
+                    log::debug!("caps word active: false");
+                    self.word_modes.caps_word = false;
+                }
+                actions.push(Action::SendCode(data.clone()));
+            }
+        } else {
+            actions.push(Action::SendCode(data.clone()));
+        }
+
+        let stop_num_word =
+            self.word_modes.num_word.is_some() && !conf.num_word_continue.contains(data);
+        if stop_num_word {
+            self.stop_num_word();
+        }
+        self.word_modes.touch(self.clock.now());
+    }
+
+    /// Turn Num Word off, removing its layer from the stack
+    fn stop_num_word(&mut self) {
+        if let Some(layer_id) = self.word_modes.num_word.take() {
+            if let Some(position) = self
+                .layer_stack
+                .iter()
+                .rposition(|layer| *layer == layer_id)
+            {
+                self.layer_stack.remove(position);
+            }
+            log::debug!("num word active: false");
+        }
+    }
+
+    /// Turn off word modes which have been idle for longer than the timeout
+    fn expire_word_modes(&mut self) {
+        let is_expired = self
+            .word_modes
+            .deadline(self.settings.word_mode_timeout)
+            .map(|deadline| self.clock.now() >= deadline)
+            .unwrap_or(false);
+
+        if is_expired {
+            log::debug!("word modes timed out");
+            self.word_modes.caps_word = false;
+            self.stop_num_word();
         }
     }
 
@@ -188,6 +586,9 @@ where
         if self.state_machines.contains_key(key_id) {
             log::debug!("active state machine for key {:?}", key_id);
         } else if let Some(conf) = self.layer_mapper.get_conf(&self.get_active_layer(), key_id) {
+            if let Some(rate) = self.get_repeat_rate(key_id, &conf) {
+                self.repeat_rates.insert(*key_id, rate);
+            }
             let machine = self.build_machine(key_id, conf);
             self.state_machines.insert(*key_id, machine);
             self.state_machine_order.push(*key_id);
@@ -219,11 +620,93 @@ where
                 let ksm = EagerHoldKSM::new(self.settings.hold_ksm_delay, *key_id, conf);
                 Box::new(ksm)
             }
+            keys::KeyConf::LongPress(conf) => {
+                let ksm = LongPressKSM::new(*key_id, conf);
+                Box::new(ksm)
+            }
+            keys::KeyConf::ModMorph(conf) => {
+                let tap = if self.is_any_output_held(&conf.mods) {
+                    conf.morphed
+                } else {
+                    conf.default
+                };
+                let ksm = TapKSM::new(*key_id, keys::TapKeyConf { tap });
+                Box::new(ksm)
+            }
             keys::KeyConf::DoubleTap(_) => todo!(),
             keys::KeyConf::DoubleTapHold(_) => todo!(),
         }
     }
 
+    /// Resolve the auto repeat rate for a key, going from the most to the least specific configuration.
+    fn get_repeat_rate(&self, key_id: &KeyId, key_conf: &keys::KeyConf<T>) -> Option<RepeatRate> {
+        let behavior_repeat = match key_conf {
+            keys::KeyConf::Tap(_) | keys::KeyConf::ModMorph(_) => self.settings.tap_repeat,
+            keys::KeyConf::Hold(_) | keys::KeyConf::EagerHold(_) => self.settings.hold_repeat,
+            keys::KeyConf::LongPress(_) => self.settings.long_press_repeat,
+            _ => KeyRepeat::Inherit,
+        };
+        let key_repeat = self.key_repeat.get(key_id).copied().unwrap_or_default();
+
+        key_repeat.resolve(behavior_repeat.resolve(self.settings.repeat))
+    }
+
+    /// Start, stop or step the auto repeat.
+    ///
+    /// Any output sent by a key interrupts the current repetition,
+    /// the key then starts repeating if it's held and allowed to repeat.
+    /// Repetition stops once the repeating key is released.
+    fn handle_auto_repeat(
+        &mut self,
+        event: &Event<KeyId>,
+        transition_actions: &[(KeyId, KeyActionSet<T>)],
+        transition_outputs: &[Vec<Action<T>>],
+    ) -> Vec<Action<T>> {
+        let now = self.clock.now();
+        for ((key_id, key_actions), output) in transition_actions.iter().zip(transition_outputs) {
+            let sends_key = key_actions
+                .get_actions()
+                .iter()
+                .any(|key_action| matches!(key_action, keys::KeyAction::SendKey(_)));
+
+            if !sends_key {
+                continue;
+            }
+
+            // the output is repeated as sent, with overrides and word modes applied
+            let is_released = matches!(event, Event::KeyRelease(id) if id == key_id);
+            self.active_repeat = match self.repeat_rates.get(key_id) {
+                Some(rate) if !is_released => Some(ActiveRepeat::new(*key_id, output, *rate, now))
+                    .filter(|repeat| !repeat.is_empty()),
+                _ => None,
+            };
+        }
+
+        let is_repeat_finished = |repeat: &ActiveRepeat<KeyId, T>| {
+            matches!(event, Event::KeyRelease(id) if *id == repeat.key_id)
+                || self
+                    .state_machines
+                    .get(&repeat.key_id)
+                    .map(|machine| machine.is_finished())
+                    .unwrap_or(true)
+        };
+
+        if self
+            .active_repeat
+            .as_ref()
+            .map(is_repeat_finished)
+            .unwrap_or(false)
+        {
+            log::debug!("stopped auto repeat");
+            self.active_repeat = None;
+        }
+
+        self.active_repeat
+            .as_mut()
+            .map(|repeat| repeat.poll(now))
+            .unwrap_or_default()
+    }
+
     fn drop_finished_machines(&mut self) {
         let finished_machines = self
             .state_machines
@@ -239,6 +722,7 @@ where
         for key_id in finished_machines.into_iter() {
             log::debug!("dropped state machine for key: {:?}", key_id);
             self.state_machines.remove(&key_id);
+            self.repeat_rates.remove(&key_id);
         }
     }
 }
@@ -246,22 +730,41 @@ where
 impl<KeyId, T, Mapper> Keyboard<KeyId, T> for SMKeyboard<KeyId, T, Mapper>
 where
     KeyId: Hash + Copy + Eq + Debug + 'static,
-    T: Clone + 'static + Debug,
+    T: Clone + PartialEq + 'static + Debug,
     Mapper: LayerMapper<KeyId, T>,
 {
     fn transition(&mut self, event: Event<KeyId>) -> Vec<Action<T>> {
         log::debug!("handling event: {:?}", event);
+        match event {
+            Event::KeyPress(key_id) => {
+                self.pressed_keys.insert(key_id);
+            }
+            Event::KeyRelease(key_id) if !self.pressed_keys.remove(&key_id) => {
+                log::debug!("ignored release of key which wasn't pressed: {:?}", key_id);
+                return Vec::new();
+            }
+            _ => (),
+        }
+
         let mut actions = Vec::new();
         let mut pending_action_q = Vec::with_capacity(10);
 
-        if matches!(event, Event::KeyPress(_)) {
+        self.expire_word_modes();
+
+        let mut new_machine = None;
+        if let Event::KeyPress(key_id) = event {
+            let had_machine = self.state_machines.contains_key(&key_id);
             self.handle_key_press_event(&event);
+            if !had_machine && self.state_machines.contains_key(&key_id) {
+                new_machine = Some(key_id);
+            }
         }
 
         // map state machine steps into pending key actions
+        let now = self.clock.now();
         for key_id in self.state_machine_order.iter() {
             let machine = self.state_machines.get_mut(key_id).unwrap();
-            if let Some(key_actions) = machine.transition(&event) {
+            if let Some(key_actions) = machine.transition(&event, now) {
                 log::debug!(
                     "transition actions: key_id={:?} actionset={:?}",
                     key_id,
@@ -271,6 +774,8 @@ where
             }
         }
 
+        let transition_count = pending_action_q.len();
+
         // add cleanup action for finished machines
         for (key_id, machine) in self.state_machines.iter_mut() {
             if machine.is_finished() {
@@ -280,27 +785,104 @@ where
             }
         }
 
-        // map pending key actions into actions
-        for (_, key_actions) in pending_action_q.iter() {
+        // map pending key actions into actions, keeping the output of each transition
+        let mut transition_outputs = Vec::with_capacity(transition_count);
+        for (position, (_, key_actions)) in pending_action_q.iter().enumerate() {
+            let start = actions.len();
             for key_action in key_actions.get_actions().iter() {
-                if let Some(action) = self.handle_key_action(key_action) {
-                    actions.push(action);
-                }
+                self.handle_key_action(key_action, &mut actions);
+            }
+            if position < transition_count {
+                transition_outputs.push(actions[start..].to_vec());
             }
         }
 
+        let repeat_actions = self.handle_auto_repeat(
+            &event,
+            &pending_action_q[..transition_count],
+            &transition_outputs,
+        );
+        actions.extend(repeat_actions);
+
+        self.track_pending_behaviors(new_machine, &pending_action_q[..transition_count]);
         log::debug!("state machine count: {:?}", self.state_machines.len());
         self.drop_finished_machines();
+        self.notify_state_changes();
 
         actions
     }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let repeat_deadline = self.active_repeat.as_ref().map(|repeat| repeat.deadline());
+        let word_mode_deadline = self.word_modes.deadline(self.settings.word_mode_timeout);
+
+        match (repeat_deadline, word_mode_deadline) {
+            (Some(repeat), Some(word_mode)) => Some(repeat.min(word_mode)),
+            (repeat, word_mode) => repeat.or(word_mode),
+        }
+    }
+
+    fn reset(&mut self) -> Vec<Action<T>> {
+        let mut actions = Vec::new();
+        let mut stop = |data: T| {
+            let action = Action::Stop(data);
+            if !actions.contains(&action) {
+                actions.push(action);
+            }
+        };
+
+        for active in self.active_overrides.drain(..) {
+            for key_action in active.replacement.get_actions().iter() {
+                if let keys::KeyAction::SendKey(data) = key_action {
+                    stop(data.clone());
+                }
+            }
+        }
+        for data in self.held_outputs.drain(..) {
+            stop(data);
+        }
+
+        self.state_machines.clear();
+        self.state_machine_order.clear();
+        self.pressed_keys.clear();
+        self.repeat_rates.clear();
+        self.active_repeat = None;
+        self.layer_stack.clear();
+        self.word_modes = WordModes::new();
+        for key_id in std::mem::take(&mut self.pending_keys) {
+            self.notify(Notification::BehaviorResolved(key_id, None));
+        }
+        self.notify_state_changes();
+        log::debug!("keyboard reset");
+
+        actions
+    }
+
+    fn active_layer(&self) -> Option<keys::LayerId> {
+        Some(self.get_active_layer())
+    }
+
+    fn set_default_layer(&mut self, layer: keys::LayerId) -> bool {
+        self.default_layer = layer;
+        self.notify_state_changes();
+        log::debug!("default layer set to {}", layer);
+        true
+    }
+
+    fn set_notification_hook(&mut self, hook: NotificationHook<KeyId>) {
+        self.notification_hook = Some(hook);
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::keyboard::SimulatedClock;
+    use crate::mapper::MapOrEchoMapper;
     use crate::mapper::SimpleMapper;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     /*
     #[test]
@@ -320,4 +902,642 @@ mod tests {
 
     // test layer setting
     // test state machine
+
+    fn build_repeating_keyboard() -> SMKeyboard<u8, u8, SimpleMapper> {
+        let settings = SMKeyboardSettings {
+            repeat: Some(RepeatRate {
+                delay: Duration::from_millis(5),
+                interval: Duration::from_millis(2),
+            }),
+            ..SMKeyboardSettings::default()
+        };
+        SMKeyboard::new(0, SimpleMapper {}, settings)
+    }
+
+    #[test]
+    fn test_held_key_is_auto_repeated_after_delay() {
+        let mut keyboard = build_repeating_keyboard();
+
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(1)),
+            vec![Action::SendCode(1)]
+        );
+        assert!(keyboard.next_deadline().is_some());
+
+        // polling before the delay does nothing
+        assert!(keyboard.transition(Event::Poll).is_empty());
+
+        // polling after the delay repeats the output
+        std::thread::sleep(Duration::from_millis(6));
+        assert_eq!(
+            keyboard.transition(Event::Poll),
+            vec![Action::Stop(1), Action::SendCode(1)]
+        );
+
+        // releasing the key stops the repetition
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(1)),
+            vec![Action::Stop(1)]
+        );
+        assert!(keyboard.next_deadline().is_none());
+        std::thread::sleep(Duration::from_millis(6));
+        assert!(keyboard.transition(Event::Poll).is_empty());
+    }
+
+    #[test]
+    fn test_held_modifier_is_not_auto_repeated() {
+        let settings = SMKeyboardSettings {
+            hold_ksm_delay: Duration::from_millis(2),
+            ..build_repeating_keyboard().settings
+        };
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 30),
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Single(keys::KeyAction::SendKey(SHIFT)),
+                retro_tap: false,
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), settings);
+
+        keyboard.transition(Event::KeyPress(30));
+        std::thread::sleep(Duration::from_millis(3));
+        assert_eq!(
+            keyboard.transition(Event::Poll),
+            vec![Action::SendCode(SHIFT)]
+        );
+
+        // the modifier stays down rather than being released and pressed again
+        std::thread::sleep(Duration::from_millis(6));
+        assert!(keyboard.transition(Event::Poll).is_empty());
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(30)),
+            vec![Action::Stop(SHIFT)]
+        );
+    }
+
+    #[test]
+    fn test_pressing_another_key_interrupts_auto_repeat() {
+        let mut keyboard = build_repeating_keyboard();
+
+        keyboard.transition(Event::KeyPress(1));
+        keyboard.transition(Event::KeyPress(2));
+        keyboard.transition(Event::KeyRelease(2));
+
+        // key 2 was released and key 1 was interrupted, nothing repeats
+        std::thread::sleep(Duration::from_millis(6));
+        assert!(keyboard.transition(Event::Poll).is_empty());
+    }
+
+    #[test]
+    fn test_key_repeat_overrides_behavior_and_keyboard_settings() {
+        let mut keyboard = build_repeating_keyboard();
+        keyboard.settings.tap_repeat = KeyRepeat::Disabled;
+        keyboard.set_key_repeat(
+            2,
+            KeyRepeat::Enabled(RepeatRate {
+                delay: Duration::from_millis(1),
+                interval: Duration::from_millis(1),
+            }),
+        );
+
+        // tap keys opted out of auto repeat
+        keyboard.transition(Event::KeyPress(1));
+        std::thread::sleep(Duration::from_millis(6));
+        assert!(keyboard.transition(Event::Poll).is_empty());
+        keyboard.transition(Event::KeyRelease(1));
+
+        // but key 2 opted in
+        keyboard.transition(Event::KeyPress(2));
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(
+            keyboard.transition(Event::Poll),
+            vec![Action::Stop(2), Action::SendCode(2)]
+        );
+    }
+
+    const CAPS_WORD_KEY: u8 = 50;
+    const NUM_WORD_KEY: u8 = 51;
+    const NUM_LAYER_KEY: u8 = 8;
+    const SHIFT: u8 = 100;
+    const SPACE: u8 = 7;
+
+    fn build_word_mode_keyboard(
+        settings: SMKeyboardSettings,
+    ) -> SMKeyboard<u8, u8, MapOrEchoMapper<u8>> {
+        let tap = |action| {
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(action),
+            })
+        };
+        let mut map = HashMap::new();
+        map.insert((0, CAPS_WORD_KEY), tap(keys::KeyAction::CapsWord));
+        map.insert((0, NUM_WORD_KEY), tap(keys::KeyAction::NumWord(1)));
+        map.insert((1, NUM_LAYER_KEY), tap(keys::KeyAction::SendKey(3)));
+
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), settings);
+        keyboard.set_word_mode_conf(WordModeConf {
+            shift: SHIFT,
+            caps_word_letters: vec![1, 2],
+            caps_word_continue: vec![6],
+            num_word_continue: vec![3],
+        });
+        keyboard
+    }
+
+    fn tap_key(keyboard: &mut impl Keyboard<u8, u8>, key_id: u8) -> Vec<Action<u8>> {
+        let mut actions = keyboard.transition(Event::KeyPress(key_id));
+        actions.extend(keyboard.transition(Event::KeyRelease(key_id)));
+        actions
+    }
+
+    #[test]
+    fn test_caps_word_shifts_letters_until_word_breaks() {
+        let mut keyboard = build_word_mode_keyboard(SMKeyboardSettings::default());
+
+        assert!(tap_key(&mut keyboard, CAPS_WORD_KEY).is_empty());
+
+        // letters are shifted
+        assert_eq!(
+            tap_key(&mut keyboard, 1),
+            vec![
+                Action::SendCode(SHIFT),
+                Action::SendCode(1),
+                Action::Stop(SHIFT),
+                Action::Stop(1)
+            ]
+        );
+
+        // continuing codes are sent as is
+        assert_eq!(
+            tap_key(&mut keyboard, 6),
+            vec![Action::SendCode(6), Action::Stop(6)]
+        );
+
+        // other codes break the word
+        assert_eq!(
+            tap_key(&mut keyboard, SPACE),
+            vec![Action::SendCode(SPACE), Action::Stop(SPACE)]
+        );
+        assert_eq!(
+            tap_key(&mut keyboard, 2),
+            vec![Action::SendCode(2), Action::Stop(2)]
+        );
+    }
+
+    #[test]
+    fn test_auto_repeat_repeats_caps_word_shifted_letters() {
+        let settings = SMKeyboardSettings {
+            repeat: Some(RepeatRate {
+                delay: Duration::from_millis(5),
+                interval: Duration::from_millis(2),
+            }),
+            ..SMKeyboardSettings::default()
+        };
+        let mut keyboard = build_word_mode_keyboard(settings);
+
+        tap_key(&mut keyboard, CAPS_WORD_KEY);
+        keyboard.transition(Event::KeyPress(1));
+
+        // the letter is repeated shifted
+        std::thread::sleep(Duration::from_millis(6));
+        assert_eq!(
+            keyboard.transition(Event::Poll),
+            vec![
+                Action::Stop(1),
+                Action::SendCode(SHIFT),
+                Action::SendCode(1),
+                Action::Stop(SHIFT)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_num_word_keeps_layer_active_until_word_breaks() {
+        let mut keyboard = build_word_mode_keyboard(SMKeyboardSettings::default());
+
+        tap_key(&mut keyboard, NUM_WORD_KEY);
+
+        // num layer remains active between keys
+        assert_eq!(
+            tap_key(&mut keyboard, NUM_LAYER_KEY),
+            vec![Action::SendCode(3), Action::Stop(3)]
+        );
+        assert_eq!(
+            tap_key(&mut keyboard, NUM_LAYER_KEY),
+            vec![Action::SendCode(3), Action::Stop(3)]
+        );
+
+        // breaking the word deactivates the layer
+        assert_eq!(
+            tap_key(&mut keyboard, SPACE),
+            vec![Action::SendCode(SPACE), Action::Stop(SPACE)]
+        );
+        assert_eq!(
+            tap_key(&mut keyboard, NUM_LAYER_KEY),
+            vec![Action::SendCode(NUM_LAYER_KEY), Action::Stop(NUM_LAYER_KEY)]
+        );
+    }
+
+    #[test]
+    fn test_word_modes_are_turned_off_after_idle_timeout() {
+        let settings = SMKeyboardSettings {
+            word_mode_timeout: Duration::from_millis(2),
+            ..SMKeyboardSettings::default()
+        };
+        let mut keyboard = build_word_mode_keyboard(settings);
+
+        tap_key(&mut keyboard, CAPS_WORD_KEY);
+        tap_key(&mut keyboard, NUM_WORD_KEY);
+        assert!(keyboard.next_deadline().is_some());
+
+        std::thread::sleep(Duration::from_millis(3));
+        keyboard.transition(Event::Poll);
+        assert!(keyboard.next_deadline().is_none());
+
+        assert_eq!(
+            tap_key(&mut keyboard, 1),
+            vec![Action::SendCode(1), Action::Stop(1)]
+        );
+        assert_eq!(
+            tap_key(&mut keyboard, NUM_LAYER_KEY),
+            vec![Action::SendCode(NUM_LAYER_KEY), Action::Stop(NUM_LAYER_KEY)]
+        );
+    }
+
+    const SHIFT_KEY: u8 = 10;
+    const BACKSPACE: u8 = 14;
+    const DELETE: u8 = 111;
+    const MOD_MORPH_KEY: u8 = 12;
+
+    fn build_override_keyboard() -> SMKeyboard<u8, u8, MapOrEchoMapper<u8>> {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, SHIFT_KEY),
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(SHIFT)),
+            }),
+        );
+        map.insert(
+            (0, MOD_MORPH_KEY),
+            keys::KeyConf::ModMorph(keys::ModMorphKeyConf {
+                default: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                morphed: KeyActionSet::Single(keys::KeyAction::SendKey(41)),
+                mods: vec![SHIFT],
+            }),
+        );
+
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+        keyboard.add_key_override(KeyOverride {
+            mods: vec![SHIFT],
+            key: BACKSPACE,
+            replacement: KeyActionSet::Single(keys::KeyAction::SendKey(DELETE)),
+        });
+        keyboard
+    }
+
+    #[test]
+    fn test_key_override_replaces_key_and_suppresses_modifier() {
+        let mut keyboard = build_override_keyboard();
+
+        // without the modifier the key is sent as is
+        assert_eq!(
+            tap_key(&mut keyboard, BACKSPACE),
+            vec![Action::SendCode(BACKSPACE), Action::Stop(BACKSPACE)]
+        );
+
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(SHIFT_KEY)),
+            vec![Action::SendCode(SHIFT)]
+        );
+
+        // modifier is stopped while the replacement is held
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(BACKSPACE)),
+            vec![Action::Stop(SHIFT), Action::SendCode(DELETE)]
+        );
+
+        // modifier is resent once the overridden key is released
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(BACKSPACE)),
+            vec![Action::Stop(DELETE), Action::SendCode(SHIFT)]
+        );
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(SHIFT_KEY)),
+            vec![Action::Stop(SHIFT)]
+        );
+    }
+
+    #[test]
+    fn test_auto_repeat_repeats_key_override_replacement() {
+        let mut keyboard = build_override_keyboard();
+        keyboard.settings.repeat = Some(RepeatRate {
+            delay: Duration::from_millis(5),
+            interval: Duration::from_millis(2),
+        });
+
+        keyboard.transition(Event::KeyPress(SHIFT_KEY));
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(BACKSPACE)),
+            vec![Action::Stop(SHIFT), Action::SendCode(DELETE)]
+        );
+
+        // the replacement is repeated, the suppressed modifier isn't stopped again
+        std::thread::sleep(Duration::from_millis(6));
+        assert_eq!(
+            keyboard.transition(Event::Poll),
+            vec![Action::Stop(DELETE), Action::SendCode(DELETE)]
+        );
+    }
+
+    #[test]
+    fn test_releasing_suppressed_modifier_before_overridden_key() {
+        let mut keyboard = build_override_keyboard();
+
+        keyboard.transition(Event::KeyPress(SHIFT_KEY));
+        keyboard.transition(Event::KeyPress(BACKSPACE));
+
+        // modifier was already stopped
+        assert!(keyboard.transition(Event::KeyRelease(SHIFT_KEY)).is_empty());
+
+        // and it's not resent
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(BACKSPACE)),
+            vec![Action::Stop(DELETE)]
+        );
+    }
+
+    #[test]
+    fn test_reconfigure_lets_active_machines_finish_with_previous_conf() {
+        let hold_key = |hold| {
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Single(keys::KeyAction::SendKey(hold)),
+                retro_tap: false,
+            })
+        };
+        let build_keyboard = |hold| {
+            let mut map = HashMap::new();
+            map.insert((0, 5), hold_key(hold));
+            SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default())
+        };
+        let mut keyboard = build_keyboard(2);
+
+        assert!(keyboard.transition(Event::KeyPress(5)).is_empty());
+        keyboard.reconfigure(build_keyboard(3));
+
+        // the pressed key is held with the configuration it was pressed with
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(8)),
+            vec![Action::SendCode(2), Action::SendCode(8)]
+        );
+        keyboard.transition(Event::KeyRelease(8));
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(5)),
+            vec![Action::Stop(2)]
+        );
+
+        // new presses use the new configuration
+        keyboard.transition(Event::KeyPress(5));
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(8)),
+            vec![Action::SendCode(3), Action::SendCode(8)]
+        );
+    }
+
+    #[test]
+    fn test_reset_stops_held_outputs_and_clears_state() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Double(
+                    keys::KeyAction::SendKey(2),
+                    keys::KeyAction::PushLayer(1),
+                ),
+                retro_tap: false,
+            }),
+        );
+        map.insert(
+            (1, 8),
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(9)),
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+
+        keyboard.transition(Event::KeyPress(5));
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(6)),
+            vec![Action::SendCode(2), Action::SendCode(6)]
+        );
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(8)),
+            vec![Action::SendCode(9)]
+        );
+
+        assert_eq!(
+            keyboard.reset(),
+            vec![Action::Stop(2), Action::Stop(6), Action::Stop(9)]
+        );
+        assert!(keyboard.reset().is_empty());
+
+        // releases of keys pressed before the reset are ignored
+        // and the layer pushed by the hold key is gone
+        assert!(keyboard.transition(Event::KeyRelease(5)).is_empty());
+        assert_eq!(
+            tap_key(&mut keyboard, 8),
+            vec![Action::SendCode(8), Action::Stop(8)]
+        );
+    }
+
+    #[test]
+    fn test_set_default_layer_is_below_pushed_layers() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::PushLayer(2)),
+            }),
+        );
+        map.insert(
+            (1, 8),
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(9)),
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+        assert_eq!(keyboard.active_layer(), Some(0));
+
+        assert!(keyboard.set_default_layer(1));
+        assert_eq!(keyboard.active_layer(), Some(1));
+        assert_eq!(
+            tap_key(&mut keyboard, 8),
+            vec![Action::SendCode(9), Action::Stop(9)]
+        );
+
+        assert!(keyboard.set_default_layer(0));
+        keyboard.transition(Event::KeyPress(5));
+        assert!(keyboard.set_default_layer(1));
+        assert_eq!(keyboard.active_layer(), Some(2));
+    }
+
+    #[test]
+    fn test_notification_hook_reports_layers_word_modes_and_pending_keys() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Single(keys::KeyAction::PushLayer(1)),
+                retro_tap: false,
+            }),
+        );
+        map.insert(
+            (0, CAPS_WORD_KEY),
+            keys::KeyConf::Tap(keys::TapKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::CapsWord),
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+        keyboard.set_word_mode_conf(WordModeConf {
+            shift: SHIFT,
+            caps_word_letters: vec![1, 2],
+            caps_word_continue: vec![],
+            num_word_continue: vec![],
+        });
+        let notifications = Rc::new(RefCell::new(Vec::new()));
+        let hook_notifications = Rc::clone(&notifications);
+        keyboard.set_notification_hook(Box::new(move |notification| {
+            hook_notifications.borrow_mut().push(notification)
+        }));
+        let take = || notifications.borrow_mut().drain(..).collect::<Vec<_>>();
+
+        keyboard.transition(Event::KeyPress(5));
+        assert_eq!(take(), vec![Notification::BehaviorPending(5)]);
+        keyboard.transition(Event::KeyPress(8));
+        assert_eq!(
+            take(),
+            vec![
+                Notification::BehaviorResolved(5, Some(Behavior::Hold)),
+                Notification::LayerChanged(1)
+            ]
+        );
+        keyboard.transition(Event::KeyRelease(8));
+        keyboard.transition(Event::KeyRelease(5));
+        assert_eq!(take(), vec![Notification::LayerChanged(0)]);
+
+        tap_key(&mut keyboard, CAPS_WORD_KEY);
+        assert_eq!(take(), vec![Notification::CapsWord(true)]);
+        keyboard.reset();
+        assert_eq!(take(), vec![Notification::CapsWord(false)]);
+
+        assert!(keyboard.set_default_layer(2));
+        assert_eq!(take(), vec![Notification::LayerChanged(2)]);
+    }
+
+    #[test]
+    fn test_simulated_clock_drives_hold_timeout() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Single(keys::KeyAction::SendKey(2)),
+                retro_tap: false,
+            }),
+        );
+        let settings = SMKeyboardSettings {
+            hold_ksm_delay: Duration::from_secs(60),
+            ..SMKeyboardSettings::default()
+        };
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), settings);
+        let clock = SimulatedClock::new(Instant::now());
+        keyboard.set_clock(clock.clone());
+
+        assert!(keyboard.transition(Event::KeyPress(5)).is_empty());
+        clock.advance(Duration::from_secs(59));
+        assert!(keyboard.transition(Event::Poll).is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(keyboard.transition(Event::Poll), vec![Action::SendCode(2)]);
+    }
+
+    #[test]
+    fn test_rolling_over_long_press_key_keeps_typing_order() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::LongPress(keys::LongPressKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                tiers: vec![keys::LongPressTier {
+                    threshold: Duration::from_secs(60),
+                    action: KeyActionSet::Single(keys::KeyAction::SendKey(2)),
+                }],
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+
+        // pressing the next key before releasing the long press key sends its tap first
+        assert!(keyboard.transition(Event::KeyPress(5)).is_empty());
+        assert_eq!(
+            keyboard.transition(Event::KeyPress(6)),
+            vec![Action::SendCode(1), Action::SendCode(6)]
+        );
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(5)),
+            vec![Action::Stop(1)]
+        );
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(6)),
+            vec![Action::Stop(6)]
+        );
+    }
+
+    #[test]
+    fn test_releases_of_keys_never_pressed_are_ignored() {
+        let mut map = HashMap::new();
+        map.insert(
+            (0, 5),
+            keys::KeyConf::Hold(keys::HoldKeyConf {
+                tap: KeyActionSet::Single(keys::KeyAction::SendKey(1)),
+                hold: KeyActionSet::Single(keys::KeyAction::SendKey(2)),
+                retro_tap: false,
+            }),
+        );
+        let mut keyboard = SMKeyboard::new(0, MapOrEchoMapper(map), SMKeyboardSettings::default());
+
+        assert!(keyboard.transition(Event::KeyRelease(8)).is_empty());
+
+        // the orphan release doesn't interrupt the hold key
+        keyboard.transition(Event::KeyPress(5));
+        assert!(keyboard.transition(Event::KeyRelease(8)).is_empty());
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(5)),
+            vec![Action::SendCode(1)]
+        );
+        assert_eq!(keyboard.transition(Event::Poll), vec![Action::Stop(1)]);
+    }
+
+    #[test]
+    fn test_mod_morph_key_morphs_while_modifier_is_held() {
+        let mut keyboard = build_override_keyboard();
+
+        assert_eq!(
+            tap_key(&mut keyboard, MOD_MORPH_KEY),
+            vec![Action::SendCode(1), Action::Stop(1)]
+        );
+
+        // modifier is kept while the morphed action is performed
+        keyboard.transition(Event::KeyPress(SHIFT_KEY));
+        assert_eq!(
+            tap_key(&mut keyboard, MOD_MORPH_KEY),
+            vec![Action::SendCode(41), Action::Stop(41)]
+        );
+        assert_eq!(
+            keyboard.transition(Event::KeyRelease(SHIFT_KEY)),
+            vec![Action::Stop(SHIFT)]
+        );
+    }
 }
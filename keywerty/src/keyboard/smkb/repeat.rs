@@ -0,0 +1,113 @@
+//! Software auto repeat for key outputs
+use std::time::{Duration, Instant};
+
+use crate::keyboard::Action;
+
+/// Timing for an auto repeating key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RepeatRate {
+    /// How long a key must be held before it starts repeating.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
+    pub delay: Duration,
+
+    /// Time between repetitions.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
+    pub interval: Duration,
+}
+
+/// Auto repeat configuration for a key or for a key behavior.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum KeyRepeat {
+    /// Use the next, less specific, configuration.
+    Inherit,
+
+    /// Never repeat the key output.
+    Disabled,
+
+    /// Repeat the key output with the given rate.
+    Enabled(RepeatRate),
+}
+
+impl KeyRepeat {
+    /// Resolve the repeat rate, using `fallback` if the configuration is inherited.
+    pub fn resolve(self, fallback: Option<RepeatRate>) -> Option<RepeatRate> {
+        match self {
+            KeyRepeat::Inherit => fallback,
+            KeyRepeat::Disabled => None,
+            KeyRepeat::Enabled(rate) => Some(rate),
+        }
+    }
+}
+
+impl Default for KeyRepeat {
+    /// KeyRepeat defaults to Inherit
+    fn default() -> Self {
+        KeyRepeat::Inherit
+    }
+}
+
+/// Outputs being repeated on behalf of a held key.
+#[derive(Debug)]
+pub struct ActiveRepeat<KeyId, T> {
+    pub key_id: KeyId,
+    /// Codes held by the key's output
+    held: Vec<T>,
+    /// Actions resending the key's output
+    output: Vec<Action<T>>,
+    rate: RepeatRate,
+    deadline: Instant,
+}
+
+impl<KeyId, T: Clone + PartialEq> ActiveRepeat<KeyId, T> {
+    /// Repeat `output`, the actions sent when the key was pressed, after overrides and word
+    /// modes were applied. Stops of codes `output` didn't send (eg modifiers suppressed by
+    /// an override) are not repeated.
+    pub fn new(key_id: KeyId, output: &[Action<T>], rate: RepeatRate, now: Instant) -> Self {
+        let mut held = Vec::new();
+        let mut sent = Vec::new();
+        let mut repeated = Vec::new();
+        for action in output.iter() {
+            match action {
+                Action::SendCode(code) => {
+                    held.push(code.clone());
+                    sent.push(code.clone());
+                }
+                Action::Stop(code) if sent.contains(code) => held.retain(|held| held != code),
+                Action::Stop(_) => continue,
+            }
+            repeated.push(action.clone());
+        }
+        Self {
+            key_id,
+            held,
+            output: repeated,
+            rate,
+            deadline: now + rate.delay,
+        }
+    }
+
+    /// Whether there's anything to repeat, ie the output holds some code.
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty()
+    }
+
+    /// Instant of the next repetition
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Return the actions for a repetition if it is due and schedule the next one.
+    /// A repetition stops the held codes and resends the output.
+    pub fn poll(&mut self, now: Instant) -> Vec<Action<T>> {
+        if now < self.deadline {
+            return Vec::new();
+        }
+        self.deadline = now + self.rate.interval;
+
+        let stops = self.held.iter().cloned().map(Action::Stop);
+        stops.chain(self.output.iter().cloned()).collect()
+    }
+}
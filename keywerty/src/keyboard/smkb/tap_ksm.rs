@@ -1,6 +1,8 @@
 use std::fmt::Debug;
+use std::time::Instant;
 
 use super::KeyStateMachine;
+use crate::keyboard::Behavior;
 use crate::keyboard::Event;
 use crate::keys::KeyActionSet;
 use crate::keys::TapKeyConf;
@@ -32,7 +34,7 @@ where
     KeyId: PartialEq + Debug,
     T: Clone,
 {
-    fn transition<'a>(&mut self, event: &Event<KeyId>) -> Option<KeyActionSet<T>> {
+    fn transition<'a>(&mut self, event: &Event<KeyId>, _now: Instant) -> Option<KeyActionSet<T>> {
         if self.is_finished() {
             return None;
         }
@@ -60,4 +62,8 @@ where
     fn get_cleanup_actions(&self) -> &[KeyActionSet<T>] {
         &self.cleanup_actions
     }
+
+    fn behavior(&self) -> Option<Behavior> {
+        Some(Behavior::Tap)
+    }
 }
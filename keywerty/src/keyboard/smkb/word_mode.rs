@@ -0,0 +1,60 @@
+//! Caps Word and Num Word keyboard modes
+use std::time::{Duration, Instant};
+
+use crate::keys::LayerId;
+
+/// Configuration for the word modes.
+///
+/// Word modes stay active as long as the keyboard outputs codes that belong to the word,
+/// any other output breaks the word and turns the mode off.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WordModeConf<T> {
+    /// Code used by Caps Word to capitalize letters (ie Shift).
+    pub shift: T,
+
+    /// Codes capitalized by Caps Word.
+    pub caps_word_letters: Vec<T>,
+
+    /// Codes that continue a Caps Word without being capitalized (eg digits, backspace or `-`).
+    pub caps_word_continue: Vec<T>,
+
+    /// Codes that continue a Num Word (eg digits, `.` or backspace).
+    pub num_word_continue: Vec<T>,
+}
+
+/// Word modes state for a keyboard.
+#[derive(Debug)]
+pub struct WordModes {
+    pub caps_word: bool,
+    pub num_word: Option<LayerId>,
+    last_activity: Instant,
+}
+
+impl WordModes {
+    pub fn new() -> Self {
+        Self {
+            caps_word: false,
+            num_word: None,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.caps_word || self.num_word.is_some()
+    }
+
+    /// Reset the idle timer
+    pub fn touch(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Instant at which the active modes time out
+    pub fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        if self.is_active() {
+            Some(self.last_activity + timeout)
+        } else {
+            None
+        }
+    }
+}
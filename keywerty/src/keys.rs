@@ -1,9 +1,13 @@
 //! Module with definitions for Key configurations
+use std::time::Duration;
+
 pub use crate::mapper::LayerId;
 
 /// A Key may have different different activation mechanisms.
 /// KeyConf indicates a key's behavior once it's activated (ie a KeyPress event)
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "behavior", rename_all = "snake_case"))]
 pub enum KeyConf<T> {
     /// A Tap represents a key as most people are used to.
     /// Once it's pressed (key down) it performs an action.
@@ -37,10 +41,29 @@ pub enum KeyConf<T> {
     /// This key configuration is often used to map the Caps Lock key into Ctrl for `hold`,
     /// ESC for `tap` and Caps Lock for `double_tap`
     DoubleTapHold(DoubleTapHoldKeyConf<T>),
+
+    /// A Long Press key generalizes the Hold key to many duration thresholds (tiers).
+    /// The key performs the action of the longest tier it was held for,
+    /// or the `tap` action if released before the first threshold.
+    /// The action is resolved once the key is released or, if the key is kept pressed,
+    /// as soon as the final tier is reached.
+    ///
+    /// This is useful for accent input, eg: `a` on tap, `á` after 300ms and `à` after 800ms.
+    LongPress(LongPressKeyConf<T>),
+
+    /// A Mod Morph key behaves like a Tap key whose action depends on the keyboard's modifiers.
+    /// If any of the `mods` outputs is held when the key is pressed, it performs the `morphed`
+    /// action, otherwise it performs the `default` action.
+    /// Unlike key overrides, the held modifiers are not suppressed.
+    ///
+    /// eg: `Esc` by default and `~` with Shift, by morphing into the grave key.
+    ModMorph(ModMorphKeyConf<T>),
 }
 
 /// KeyAction models the different side effects a Key can have when activated.
 #[derive(PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum KeyAction<T> {
     /// Indicates that the Keyboard should send some data for `T`.
     /// Should be equivalent to an `Action::SendKey`.
@@ -56,7 +79,15 @@ pub enum KeyAction<T> {
     /// Remove the first occurence of `LayerId` from the layer stack.
     PopLayer(LayerId),
 
+    /// Toggle Caps Word, which capitalizes letters until the end of the current word.
+    CapsWord,
+
+    /// Toggle Num Word, which keeps the layer given by `LayerId` active
+    /// until the end of the current word.
+    NumWord(LayerId),
+
     /// No operation action
+    #[cfg_attr(feature = "serde", serde(rename = "noop"))]
     NoOp,
 }
 
@@ -78,6 +109,9 @@ impl<T: Clone> KeyAction<T> {
             Self::StopKey(data) => Self::SendKey(data.clone()),
             Self::PushLayer(layer_id) => Self::PopLayer(*layer_id),
             Self::PopLayer(layer_id) => Self::PushLayer(*layer_id),
+            // word modes turn themselves off, releasing the key does nothing.
+            Self::CapsWord => Self::NoOp,
+            Self::NumWord(_) => Self::NoOp,
             Self::NoOp => Self::NoOp,
         }
     }
@@ -149,6 +183,7 @@ impl<T> Default for KeyActionSet<T> {
 
 /// Configuration for a Tap keyconf, tap keys have a single action.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TapKeyConf<T> {
     pub tap: KeyActionSet<T>,
 }
@@ -164,9 +199,17 @@ impl<T> Default for TapKeyConf<T> {
 /// Actions for a hold or eager hold key conf.
 /// These configurations perform two actions, one for tap and another for hold.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HoldKeyConf<T> {
     pub tap: KeyActionSet<T>,
     pub hold: KeyActionSet<T>,
+
+    /// Enables "retro tapping".
+    /// When set, a key that is held past the hold threshold and released
+    /// without any other key being pressed undoes the hold action and
+    /// performs the tap action instead.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub retro_tap: bool,
 }
 
 impl<T> Default for HoldKeyConf<T> {
@@ -174,6 +217,7 @@ impl<T> Default for HoldKeyConf<T> {
         Self {
             tap: KeyActionSet::default(),
             hold: KeyActionSet::default(),
+            retro_tap: false,
         }
     }
 }
@@ -181,6 +225,7 @@ impl<T> Default for HoldKeyConf<T> {
 /// Actions for a Double tap key configuration.
 /// One action for a key press another for a tap, release and retap cycle.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DoubleTapKeyConf<T> {
     pub tap: KeyActionSet<T>,
     pub double_tap: KeyActionSet<T>,
@@ -198,6 +243,7 @@ impl<T> Default for DoubleTapKeyConf<T> {
 /// Actions for a double-tap-hold configuration.
 /// one action for a tap, one for a hold and another for a double tap activation.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DoubleTapHoldKeyConf<T> {
     pub tap: KeyActionSet<T>,
     pub double_tap: KeyActionSet<T>,
@@ -214,7 +260,57 @@ impl<T> Default for DoubleTapHoldKeyConf<T> {
     }
 }
 
+/// A duration threshold for a Long Press key and the action it performs.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LongPressTier<T> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::duration"))]
+    pub threshold: Duration,
+    pub action: KeyActionSet<T>,
+}
+
+/// Actions for a Long Press key configuration.
+/// One action for a tap and one action for each tier.
+/// Tiers are resolved by their threshold regardless of the order they're declared in.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LongPressKeyConf<T> {
+    pub tap: KeyActionSet<T>,
+    pub tiers: Vec<LongPressTier<T>>,
+}
+
+impl<T> Default for LongPressKeyConf<T> {
+    fn default() -> Self {
+        Self {
+            tap: KeyActionSet::default(),
+            tiers: Vec::new(),
+        }
+    }
+}
+
+/// Actions for a Mod Morph key configuration.
+/// One action for when the key is pressed by itself,
+/// another for when it's pressed while any of `mods` is held.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ModMorphKeyConf<T> {
+    pub default: KeyActionSet<T>,
+    pub morphed: KeyActionSet<T>,
+    pub mods: Vec<T>,
+}
+
+impl<T> Default for ModMorphKeyConf<T> {
+    fn default() -> Self {
+        Self {
+            default: KeyActionSet::default(),
+            morphed: KeyActionSet::default(),
+            mods: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeadKeyConf<T> {
     pub activation: KeyActionSet<T>,
     pub retap: KeyActionSet<T>,
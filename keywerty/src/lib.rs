@@ -2,3 +2,5 @@
 pub mod keyboard;
 pub mod keys;
 pub mod mapper;
+#[cfg(feature = "serde")]
+pub mod serialization;
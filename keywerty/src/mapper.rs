@@ -54,7 +54,7 @@ where
         };
         self.0
             .get(&(*layer, *key))
-            .map(|v| *v)
+            .cloned()
             .or(Some(supplier(*key)))
     }
 }
@@ -0,0 +1,267 @@
+//! Serde support for keywerty's configuration types, enabled by the `serde` feature.
+//!
+//! The external representation is meant to be stable and readable by humans.
+//! Names are `snake_case` and enums are tagged:
+//!
+//! - `KeyAction` is externally tagged: `"caps_word"`, `"noop"`, `{"send_key": T}`,
+//!   `{"stop_key": T}`, `{"push_layer": 1}`, `{"pop_layer": 1}` or `{"num_word": 2}`.
+//! - `KeyActionSet` is a list of one to three actions, eg `[{"send_key": T}, {"push_layer": 1}]`.
+//! - `KeyConf` is tagged by a `behavior` field next to the fields of its configuration,
+//!   eg `{"behavior": "hold", "tap": [...], "hold": [...], "retro_tap": false}`.
+//! - `Event` and `Action` are externally tagged: `{"key_press": Id}`, `"poll"`, `{"send_code": T}`.
+//! - `KeyRepeat` is either `"inherit"`, `"disabled"` or `{"enabled": {"delay": ..., "interval": ...}}`.
+//!
+//! Durations are written as integers of milliseconds, strings with a unit (eg `"750ms"`
+//! or `"5s"`) are read as well.
+//! This format is keywerty's own: applications may read their configuration differently
+//! and convert it (eg vkwrty's TOML keymaps, which take single actions and plain milliseconds).
+//! Missing `SMKeyboardSettings` fields take their default values.
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+
+use crate::keys::{KeyAction, KeyActionSet};
+
+impl<T: Serialize> Serialize for KeyActionSet<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let actions = match self {
+            KeyActionSet::Single(a1) => vec![a1],
+            KeyActionSet::Double(a1, a2) => vec![a1, a2],
+            KeyActionSet::Triple(a1, a2, a3) => vec![a1, a2, a3],
+        };
+
+        let mut seq = serializer.serialize_seq(Some(actions.len()))?;
+        for action in actions {
+            seq.serialize_element(action)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for KeyActionSet<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(KeyActionSetVisitor(PhantomData))
+    }
+}
+
+struct KeyActionSetVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for KeyActionSetVisitor<T> {
+    type Value = KeyActionSet<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a list of one to three key actions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut actions: Vec<KeyAction<T>> = Vec::new();
+        while let Some(action) = seq.next_element()? {
+            if actions.len() == 3 {
+                return Err(de::Error::invalid_length(actions.len() + 1, &self));
+            }
+            actions.push(action);
+        }
+
+        let mut actions = actions.into_iter();
+        match (actions.next(), actions.next(), actions.next()) {
+            (Some(a1), None, None) => Ok(KeyActionSet::Single(a1)),
+            (Some(a1), Some(a2), None) => Ok(KeyActionSet::Double(a1, a2)),
+            (Some(a1), Some(a2), Some(a3)) => Ok(KeyActionSet::Triple(a1, a2, a3)),
+            _ => Err(de::Error::invalid_length(0, &self)),
+        }
+    }
+}
+
+/// Human friendly (de)serialization for durations, used through `#[serde(with)]`.
+pub(crate) mod duration {
+    use std::fmt;
+    use std::time::Duration;
+
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::ser::{self, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        let millis = u64::try_from(duration.as_millis()).map_err(|_| {
+            ser::Error::custom("duration is too long to be written in milliseconds")
+        })?;
+        serializer.serialize_u64(millis)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(DurationVisitor)
+    }
+
+    struct DurationVisitor;
+
+    impl<'de> Visitor<'de> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "a duration such as \"750ms\" or \"5s\", or a number of milliseconds"
+            )
+        }
+
+        fn visit_u64<E: de::Error>(self, millis: u64) -> Result<Duration, E> {
+            Ok(Duration::from_millis(millis))
+        }
+
+        fn visit_i64<E: de::Error>(self, millis: i64) -> Result<Duration, E> {
+            u64::try_from(millis)
+                .map(Duration::from_millis)
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(millis), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Duration, E> {
+            let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+                (number, 1)
+            } else if let Some(number) = value.strip_suffix('s') {
+                (number, 1000)
+            } else {
+                return Err(E::invalid_value(de::Unexpected::Str(value), &self));
+            };
+
+            number
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .and_then(|number| number.checked_mul(scale))
+                .map(Duration::from_millis)
+                .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::time::Duration;
+
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    use super::*;
+    use crate::keyboard::{Action, Event, KeyRepeat, RepeatRate, SMKeyboardSettings};
+    use crate::keys::{HoldKeyConf, KeyConf, LongPressKeyConf, LongPressTier, TapKeyConf};
+
+    fn assert_round_trip<V>(value: &V, expected: serde_json::Value)
+    where
+        V: Serialize + DeserializeOwned + Debug,
+    {
+        let serialized = serde_json::to_value(value).unwrap();
+        assert_eq!(serialized, expected);
+
+        let deserialized: V = serde_json::from_value(serialized).unwrap();
+        assert_eq!(format!("{:?}", &deserialized), format!("{:?}", value));
+    }
+
+    #[test]
+    fn test_key_action_set_round_trip() {
+        let action_set = KeyActionSet::Double(KeyAction::SendKey(4u8), KeyAction::PushLayer(1));
+        assert_round_trip(&action_set, json!([{"send_key": 4}, {"push_layer": 1}]));
+
+        assert_round_trip(
+            &KeyActionSet::<u8>::Single(KeyAction::CapsWord),
+            json!(["caps_word"]),
+        );
+    }
+
+    #[test]
+    fn test_key_action_set_rejects_invalid_lengths() {
+        assert!(serde_json::from_value::<KeyActionSet<u8>>(json!([])).is_err());
+        assert!(serde_json::from_value::<KeyActionSet<u8>>(json!([
+            "noop", "noop", "noop", "noop"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_key_conf_round_trip() {
+        let hold = KeyConf::Hold(HoldKeyConf {
+            tap: KeyActionSet::Single(KeyAction::SendKey(1u8)),
+            hold: KeyActionSet::Single(KeyAction::SendKey(2u8)),
+            retro_tap: true,
+        });
+        assert_round_trip(
+            &hold,
+            json!({
+                "behavior": "hold",
+                "tap": [{"send_key": 1}],
+                "hold": [{"send_key": 2}],
+                "retro_tap": true,
+            }),
+        );
+
+        let long_press = KeyConf::LongPress(LongPressKeyConf {
+            tap: KeyActionSet::Single(KeyAction::SendKey(1u8)),
+            tiers: vec![LongPressTier {
+                threshold: Duration::from_millis(300),
+                action: KeyActionSet::Single(KeyAction::NumWord(2)),
+            }],
+        });
+        assert_round_trip(
+            &long_press,
+            json!({
+                "behavior": "long_press",
+                "tap": [{"send_key": 1}],
+                "tiers": [{"threshold": 300, "action": [{"num_word": 2}]}],
+            }),
+        );
+
+        let tap: KeyConf<u8> = KeyConf::Tap(TapKeyConf::default());
+        assert_round_trip(&tap, json!({"behavior": "tap", "tap": ["noop"]}));
+    }
+
+    #[test]
+    fn test_events_and_actions_round_trip() {
+        assert_round_trip(&Event::KeyPress(3u8), json!({"key_press": 3}));
+        assert_round_trip(&Event::<u8>::Poll, json!("poll"));
+        assert_round_trip(&Action::Stop(3u8), json!({"stop": 3}));
+    }
+
+    #[test]
+    fn test_settings_round_trip_with_millisecond_durations() {
+        let settings = SMKeyboardSettings {
+            hold_ksm_delay: Duration::from_millis(250),
+            repeat: Some(RepeatRate {
+                delay: Duration::from_millis(300),
+                interval: Duration::from_millis(30),
+            }),
+            hold_repeat: KeyRepeat::Disabled,
+            ..SMKeyboardSettings::default()
+        };
+        let serialized = serde_json::to_value(settings).unwrap();
+
+        assert_eq!(serialized["hold_ksm_delay"], json!(250));
+        assert_eq!(serialized["word_mode_timeout"], json!(5000));
+        assert_eq!(serialized["repeat"], json!({"delay": 300, "interval": 30}));
+        assert_eq!(serialized["hold_repeat"], json!("disabled"));
+
+        let deserialized: SMKeyboardSettings = serde_json::from_value(serialized).unwrap();
+        assert_eq!(format!("{:?}", deserialized), format!("{:?}", settings));
+    }
+
+    #[test]
+    fn test_settings_fill_missing_fields_with_defaults() {
+        let settings: SMKeyboardSettings =
+            serde_json::from_value(json!({"hold_ksm_delay": 200, "dtksm_retap_delay": "1s"}))
+                .unwrap();
+        let defaults = SMKeyboardSettings::default();
+
+        assert_eq!(settings.hold_ksm_delay, Duration::from_millis(200));
+        assert_eq!(settings.dtksm_retap_delay, Duration::from_secs(1));
+        assert_eq!(settings.word_mode_timeout, defaults.word_mode_timeout);
+        assert_eq!(settings.tap_repeat, defaults.tap_repeat);
+    }
+
+    #[test]
+    fn test_overflowing_durations_are_rejected() {
+        let settings = serde_json::from_value::<SMKeyboardSettings>(
+            json!({"hold_ksm_delay": "18446744073709551615s"}),
+        );
+        assert!(settings.is_err());
+    }
+}
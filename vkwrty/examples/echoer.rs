@@ -5,9 +5,7 @@
 //! will be the same as the emitted one.
 
 use std::time::Duration;
-use std::ffi::CString;
-use std::os::unix::io::FromRawFd;
-use std::fs;
+use std::path::Path;
 
 use keywerty::keyboard::Action;
 use keywerty::keyboard::Event;
@@ -15,10 +13,12 @@ use keywerty::keyboard::Keyboard;
 use clap::Arg;
 use clap::App;
 
+use vkwrty::Attach;
 use vkwrty::Error;
 use vkwrty::Runtime;
+use vkwrty::devices::DeviceInfo;
+use vkwrty::logger;
 use vkwrty::virtual_dev::UInputKeyboard;
-use vkwrty::monitor::EventIter;
 
 
 /// Implementation of Keyboard trait that echoes the input event data as an action.
@@ -44,24 +44,17 @@ fn main() {
              .takes_value(true))
         .get_matches();
 
-    let ev_file = matches.value_of("event source").unwrap();
-    let ev_file = open_dev(&ev_file);
+    logger::init(log::LevelFilter::Info);
 
-    let event_iter = EventIter::new(ev_file).unwrap();
+    let ev_file = matches.value_of("event source").unwrap();
+    let device = DeviceInfo::read(Path::new(ev_file)).unwrap();
 
     let virtual_dev = UInputKeyboard::new(&"Echoer keyboard").unwrap();
 
     let keyboard = EchoerKb {};
 
-    let mut runtime = Runtime::new(event_iter, virtual_dev, keyboard, Duration::from_secs(300)).unwrap();
-    runtime.run()
-}
-
-fn open_dev(path: &str) -> fs::File {
-    unsafe {
-        let flags = libc::O_NONBLOCK | libc::O_RDONLY;
-        let path = CString::new(path).unwrap();
-        let fd = libc::open(path.as_ptr(), flags);
-        fs::File::from_raw_fd(fd)
-    }
+    let mut runtime: Runtime<_> = Runtime::new(virtual_dev, keyboard, Duration::from_secs(300)).unwrap();
+    runtime.attach(device, Attach::Shared).unwrap();
+    runtime.stop_on_signals().unwrap();
+    runtime.run().unwrap()
 }
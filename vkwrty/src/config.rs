@@ -0,0 +1,825 @@
+//! Declarative keymap configuration.
+//!
+//! Keymaps are described in TOML files, which are loaded into a `Config`.
+//! A `Config` is then used to build the keyboards used by vkwrty's runtime,
+//! either a single keymap or one per device.
+//!
+//! The file format is documented in the README, and `examples/keymap.toml`
+//! contains a sample configuration.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::{KeyOverride, KeyRepeat, RepeatRate, SMKeyboard, SMKeyboardSettings, WordModeConf};
+use keywerty::keys::{
+    HoldKeyConf, KeyAction, KeyActionSet, KeyConf, LongPressKeyConf, LongPressTier, ModMorphKeyConf,
+    TapKeyConf,
+};
+use keywerty::mapper::{LayerId, MapOrEchoMapper};
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use toml::value::{Table, Value};
+
+use crate::devices::{DeviceInfo, DeviceSelector};
+use crate::emergency::{self, EmergencyAction, EmergencyConf};
+use crate::keynames;
+
+
+/// Error found while parsing a configuration file.
+/// The error message includes the line and column where the error was found.
+#[derive(Debug)]
+pub struct ConfigError {
+    path: Option<PathBuf>,
+    error: toml::de::Error,
+}
+
+impl ConfigError {
+    /// Line and column (starting at 1) where the error was found, if known.
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        self.error.line_col().map(|(line, col)| (line + 1, col + 1))
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+
+/// Keymap of a keyboard.
+///
+/// Keys which are not mapped in a layer send themselves,
+/// as per `MapOrEchoMapper`.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    pub default_layer: LayerId,
+    pub settings: SMKeyboardSettings,
+    pub keys: HashMap<(LayerId, EV_KEY), KeyConf<EV_KEY>>,
+    pub key_repeat: HashMap<EV_KEY, KeyRepeat>,
+    pub word_mode: Option<WordModeConf<EV_KEY>>,
+    pub overrides: Vec<KeyOverride<EV_KEY>>,
+}
+
+impl Keymap {
+    /// Build the layer mapper described by the keymap.
+    pub fn build_mapper(&self) -> MapOrEchoMapper<EV_KEY> {
+        MapOrEchoMapper(self.keys.clone())
+    }
+
+    /// Build a keyboard with the keymap's mapper, settings and keyboard features.
+    pub fn build_keyboard(&self) -> SMKeyboard<EV_KEY, EV_KEY, MapOrEchoMapper<EV_KEY>> {
+        let mut keyboard = SMKeyboard::new(self.default_layer, self.build_mapper(), self.settings);
+
+        for (key, repeat) in self.key_repeat.iter() {
+            keyboard.set_key_repeat(*key, *repeat);
+        }
+        if let Some(word_mode) = &self.word_mode {
+            keyboard.set_word_mode_conf(word_mode.clone());
+        }
+        for key_override in self.overrides.iter() {
+            keyboard.add_key_override(key_override.clone());
+        }
+        keyboard
+    }
+}
+
+/// Keymap used by the devices matched by `selector`.
+#[derive(Debug, Clone)]
+pub struct DeviceKeymap {
+    pub selector: DeviceSelector,
+    pub keymap: Keymap,
+}
+
+/// Configuration for vkwrty.
+///
+/// Devices matched by one of the `devices` entries use its keymap,
+/// the first matching entry wins. Other devices use the default `keymap`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub keymap: Keymap,
+    pub devices: Vec<DeviceKeymap>,
+    pub emergency: EmergencyConf,
+}
+
+impl Config {
+    /// Read and parse the configuration file at `path`.
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        content.parse().map_err(|err: ConfigError| {
+            ConfigError { path: Some(path.to_path_buf()), ..err }.into()
+        })
+    }
+
+    /// Keymap of the first `devices` entry matching `device`, if any.
+    pub fn device_keymap(&self, device: &DeviceInfo) -> Option<&Keymap> {
+        self.devices.iter()
+            .find(|entry| entry.selector.matches(device))
+            .map(|entry| &entry.keymap)
+    }
+
+    /// Keymap used by `device`, either its own or the default one.
+    pub fn keymap_for(&self, device: &DeviceInfo) -> &Keymap {
+        self.device_keymap(device).unwrap_or(&self.keymap)
+    }
+}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        toml::from_str::<ConfigFile>(content)
+            .map(ConfigFile::into_config)
+            .map_err(|error| ConfigError { path: None, error })
+    }
+}
+
+
+// The types below model the configuration file itself.
+// They are deserialized from the file and converted into `Config`.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    default_layer: LayerId,
+    #[serde(default)]
+    settings: SettingsFile,
+    #[serde(default)]
+    layers: Vec<LayerFile>,
+    #[serde(default)]
+    repeat: HashMap<KeyName, RepeatFile>,
+    word_mode: Option<WordModeFile>,
+    #[serde(default)]
+    overrides: Vec<OverrideFile>,
+    #[serde(default)]
+    devices: Vec<DeviceFile>,
+    #[serde(default)]
+    emergency: EmergencyFile,
+}
+
+impl ConfigFile {
+    fn into_config(self) -> Config {
+        Config {
+            keymap: build_keymap(self.default_layer, self.settings, self.layers, self.repeat, self.word_mode, self.overrides),
+            devices: self.devices.into_iter()
+                .map(DeviceFile::into_device_keymap)
+                .collect(),
+            emergency: self.emergency.into_conf(),
+        }
+    }
+}
+
+/// Keymap bound to the devices matched by `match`, with the same keys as the default keymap.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceFile {
+    #[serde(rename = "match")]
+    selector: String,
+    #[serde(default)]
+    default_layer: LayerId,
+    #[serde(default)]
+    settings: SettingsFile,
+    #[serde(default)]
+    layers: Vec<LayerFile>,
+    #[serde(default)]
+    repeat: HashMap<KeyName, RepeatFile>,
+    word_mode: Option<WordModeFile>,
+    #[serde(default)]
+    overrides: Vec<OverrideFile>,
+}
+
+impl DeviceFile {
+    fn into_device_keymap(self) -> DeviceKeymap {
+        DeviceKeymap {
+            // parsing a selector can't fail, anything which isn't a path or ids is a name
+            selector: self.selector.parse().unwrap(),
+            keymap: build_keymap(self.default_layer, self.settings, self.layers, self.repeat, self.word_mode, self.overrides),
+        }
+    }
+}
+
+fn build_keymap(
+    default_layer: LayerId,
+    settings: SettingsFile,
+    layers: Vec<LayerFile>,
+    repeat: HashMap<KeyName, RepeatFile>,
+    word_mode: Option<WordModeFile>,
+    overrides: Vec<OverrideFile>,
+) -> Keymap {
+    let mut keys = HashMap::new();
+    for layer in layers.into_iter() {
+        for (KeyName(key), conf) in layer.keys.into_iter() {
+            keys.insert((layer.id, key), conf.0);
+        }
+    }
+
+    Keymap {
+        default_layer,
+        settings: settings.into_settings(),
+        keys,
+        key_repeat: repeat.into_iter()
+            .map(|(KeyName(key), repeat)| (key, repeat.into()))
+            .collect(),
+        word_mode: word_mode.map(WordModeFile::into_conf),
+        overrides: overrides.into_iter()
+            .map(OverrideFile::into_override)
+            .collect(),
+    }
+}
+
+/// Emergency chord, its keys are physical keys whatever the keymap maps them to.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmergencyFile {
+    #[serde(default = "default_emergency_chord")]
+    chord: Vec<KeyName>,
+    #[serde(default = "default_emergency_action")]
+    action: EmergencyActionFile,
+}
+
+impl Default for EmergencyFile {
+    fn default() -> Self {
+        Self { chord: default_emergency_chord(), action: default_emergency_action() }
+    }
+}
+
+impl EmergencyFile {
+    fn into_conf(self) -> EmergencyConf {
+        EmergencyConf {
+            chord: self.chord.into_iter().map(|KeyName(key)| key).collect(),
+            action: match self.action {
+                EmergencyActionFile::Exit => EmergencyAction::Exit,
+                EmergencyActionFile::Passthrough => EmergencyAction::Passthrough,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EmergencyActionFile {
+    Exit,
+    Passthrough,
+}
+
+fn default_emergency_chord() -> Vec<KeyName> {
+    emergency::DEFAULT_CHORD.iter().copied().map(KeyName).collect()
+}
+
+fn default_emergency_action() -> EmergencyActionFile {
+    EmergencyActionFile::Exit
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerFile {
+    id: LayerId,
+    #[serde(default)]
+    keys: HashMap<KeyName, KeyConfFile>,
+}
+
+/// Keyboard settings, durations are given in milliseconds.
+/// Missing settings take their default values.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    hold_ksm_delay: Option<u64>,
+    dtksm_retap_delay: Option<u64>,
+    dtksm_hold_delay: Option<u64>,
+    dthksm_retap_delay: Option<u64>,
+    dthksm_hold_delay: Option<u64>,
+    repeat: Option<RateFile>,
+    tap_repeat: Option<RepeatFile>,
+    hold_repeat: Option<RepeatFile>,
+    long_press_repeat: Option<RepeatFile>,
+    word_mode_timeout: Option<u64>,
+}
+
+impl SettingsFile {
+    fn into_settings(self) -> SMKeyboardSettings {
+        let defaults = SMKeyboardSettings::default();
+        let millis_or = |millis: Option<u64>, default| millis.map(Duration::from_millis).unwrap_or(default);
+
+        SMKeyboardSettings {
+            hold_ksm_delay: millis_or(self.hold_ksm_delay, defaults.hold_ksm_delay),
+            dtksm_retap_delay: millis_or(self.dtksm_retap_delay, defaults.dtksm_retap_delay),
+            dtksm_hold_delay: millis_or(self.dtksm_hold_delay, defaults.dtksm_hold_delay),
+            dthksm_retap_delay: millis_or(self.dthksm_retap_delay, defaults.dthksm_retap_delay),
+            dthksm_hold_delay: millis_or(self.dthksm_hold_delay, defaults.dthksm_hold_delay),
+            repeat: self.repeat.map(RateFile::into_rate).or(defaults.repeat),
+            tap_repeat: self.tap_repeat.map(Into::into).unwrap_or(defaults.tap_repeat),
+            hold_repeat: self.hold_repeat.map(Into::into).unwrap_or(defaults.hold_repeat),
+            long_press_repeat: self.long_press_repeat.map(Into::into).unwrap_or(defaults.long_press_repeat),
+            word_mode_timeout: millis_or(self.word_mode_timeout, defaults.word_mode_timeout),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateFile {
+    delay: u64,
+    interval: u64,
+}
+
+impl RateFile {
+    fn into_rate(self) -> RepeatRate {
+        RepeatRate {
+            delay: Duration::from_millis(self.delay),
+            interval: Duration::from_millis(self.interval),
+        }
+    }
+}
+
+/// Repeat configuration, either `"inherit"`, `"disabled"` or a rate table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RepeatFile {
+    Mode(RepeatMode),
+    Rate(RateFile),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RepeatMode {
+    Inherit,
+    Disabled,
+}
+
+impl From<RepeatFile> for KeyRepeat {
+    fn from(repeat: RepeatFile) -> KeyRepeat {
+        match repeat {
+            RepeatFile::Mode(RepeatMode::Inherit) => KeyRepeat::Inherit,
+            RepeatFile::Mode(RepeatMode::Disabled) => KeyRepeat::Disabled,
+            RepeatFile::Rate(rate) => KeyRepeat::Enabled(rate.into_rate()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WordModeFile {
+    shift: KeyName,
+    #[serde(default = "default_caps_word_letters")]
+    caps_word_letters: Vec<KeyName>,
+    #[serde(default = "default_caps_word_continue")]
+    caps_word_continue: Vec<KeyName>,
+    #[serde(default = "default_num_word_continue")]
+    num_word_continue: Vec<KeyName>,
+}
+
+impl WordModeFile {
+    fn into_conf(self) -> WordModeConf<EV_KEY> {
+        let keys = |names: Vec<KeyName>| names.into_iter().map(|KeyName(key)| key).collect();
+        WordModeConf {
+            shift: self.shift.0,
+            caps_word_letters: keys(self.caps_word_letters),
+            caps_word_continue: keys(self.caps_word_continue),
+            num_word_continue: keys(self.num_word_continue),
+        }
+    }
+}
+
+const DIGIT_KEYS: [EV_KEY; 10] = [
+    EV_KEY::KEY_1, EV_KEY::KEY_2, EV_KEY::KEY_3, EV_KEY::KEY_4, EV_KEY::KEY_5,
+    EV_KEY::KEY_6, EV_KEY::KEY_7, EV_KEY::KEY_8, EV_KEY::KEY_9, EV_KEY::KEY_0,
+];
+
+fn default_caps_word_letters() -> Vec<KeyName> {
+    [
+        EV_KEY::KEY_A, EV_KEY::KEY_B, EV_KEY::KEY_C, EV_KEY::KEY_D, EV_KEY::KEY_E, EV_KEY::KEY_F,
+        EV_KEY::KEY_G, EV_KEY::KEY_H, EV_KEY::KEY_I, EV_KEY::KEY_J, EV_KEY::KEY_K, EV_KEY::KEY_L,
+        EV_KEY::KEY_M, EV_KEY::KEY_N, EV_KEY::KEY_O, EV_KEY::KEY_P, EV_KEY::KEY_Q, EV_KEY::KEY_R,
+        EV_KEY::KEY_S, EV_KEY::KEY_T, EV_KEY::KEY_U, EV_KEY::KEY_V, EV_KEY::KEY_W, EV_KEY::KEY_X,
+        EV_KEY::KEY_Y, EV_KEY::KEY_Z,
+    ].iter().copied().map(KeyName).collect()
+}
+
+fn default_caps_word_continue() -> Vec<KeyName> {
+    DIGIT_KEYS.iter()
+        .chain([EV_KEY::KEY_MINUS, EV_KEY::KEY_BACKSPACE].iter())
+        .copied()
+        .map(KeyName)
+        .collect()
+}
+
+fn default_num_word_continue() -> Vec<KeyName> {
+    DIGIT_KEYS.iter()
+        .chain([EV_KEY::KEY_DOT, EV_KEY::KEY_COMMA, EV_KEY::KEY_BACKSPACE].iter())
+        .copied()
+        .map(KeyName)
+        .collect()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverrideFile {
+    mods: Vec<KeyName>,
+    key: KeyName,
+    replacement: ActionSetFile,
+}
+
+impl OverrideFile {
+    fn into_override(self) -> KeyOverride<EV_KEY> {
+        KeyOverride {
+            mods: self.mods.into_iter().map(|KeyName(key)| key).collect(),
+            key: self.key.0,
+            replacement: self.replacement.0,
+        }
+    }
+}
+
+/// A key behavior, given as a table tagged by `behavior`.
+///
+/// keywerty doesn't implement the double tap behaviors yet, so they aren't accepted here.
+#[derive(Deserialize)]
+#[serde(tag = "behavior", rename_all = "snake_case", deny_unknown_fields)]
+enum BehaviorFile {
+    Tap {
+        tap: ActionSetFile,
+    },
+    Hold {
+        tap: ActionSetFile,
+        hold: ActionSetFile,
+        #[serde(default)]
+        retro_tap: bool,
+    },
+    EagerHold {
+        tap: ActionSetFile,
+        hold: ActionSetFile,
+        #[serde(default)]
+        retro_tap: bool,
+    },
+    LongPress {
+        tap: ActionSetFile,
+        tiers: Vec<TierFile>,
+    },
+    ModMorph {
+        default: ActionSetFile,
+        morphed: ActionSetFile,
+        mods: Vec<KeyName>,
+    },
+}
+
+impl BehaviorFile {
+    fn into_conf(self) -> KeyConf<EV_KEY> {
+        match self {
+            BehaviorFile::Tap { tap } => KeyConf::Tap(TapKeyConf { tap: tap.0 }),
+            BehaviorFile::Hold { tap, hold, retro_tap } => {
+                KeyConf::Hold(HoldKeyConf { tap: tap.0, hold: hold.0, retro_tap })
+            }
+            BehaviorFile::EagerHold { tap, hold, retro_tap } => {
+                KeyConf::EagerHold(HoldKeyConf { tap: tap.0, hold: hold.0, retro_tap })
+            }
+            BehaviorFile::LongPress { tap, tiers } => KeyConf::LongPress(LongPressKeyConf {
+                tap: tap.0,
+                tiers: tiers.into_iter().map(TierFile::into_tier).collect(),
+            }),
+            BehaviorFile::ModMorph { default, morphed, mods } => KeyConf::ModMorph(ModMorphKeyConf {
+                default: default.0,
+                morphed: morphed.0,
+                mods: mods.into_iter().map(|KeyName(key)| key).collect(),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TierFile {
+    threshold: u64,
+    action: ActionSetFile,
+}
+
+impl TierFile {
+    fn into_tier(self) -> LongPressTier<EV_KEY> {
+        LongPressTier {
+            threshold: Duration::from_millis(self.threshold),
+            action: self.action.0,
+        }
+    }
+}
+
+/// `EV_KEY` deserialized from its name, see `keynames::parse`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct KeyName(EV_KEY);
+
+impl<'de> Deserialize<'de> for KeyName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        keynames::parse(&name)
+            .map(KeyName)
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Key configuration for a key in a layer.
+/// Either a behavior table or, as a shorthand for tap keys, an action set.
+struct KeyConfFile(KeyConf<EV_KEY>);
+
+impl<'de> Deserialize<'de> for KeyConfFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyConfVisitor)
+    }
+}
+
+struct KeyConfVisitor;
+
+impl KeyConfVisitor {
+    fn tap<E>(tap: KeyActionSet<EV_KEY>) -> Result<KeyConfFile, E> {
+        Ok(KeyConfFile(KeyConf::Tap(TapKeyConf { tap })))
+    }
+}
+
+impl<'de> Visitor<'de> for KeyConfVisitor {
+    type Value = KeyConfFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a behavior table or an action set")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Self::tap(ActionSetVisitor.visit_str(value)?.0)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        Self::tap(ActionSetVisitor.visit_seq(seq)?.0)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        // Tables are either behaviors or action tables (tap shorthand),
+        // they can only be told apart by the presence of the `behavior` tag.
+        let table = Table::deserialize(de::value::MapAccessDeserializer::new(map))?;
+        if table.contains_key("behavior") {
+            let behavior = BehaviorFile::deserialize(Value::Table(table)).map_err(de::Error::custom)?;
+            Ok(KeyConfFile(behavior.into_conf()))
+        } else {
+            let tap = ActionSetFile::deserialize(Value::Table(table)).map_err(de::Error::custom)?;
+            Self::tap(tap.0)
+        }
+    }
+}
+
+/// One to three actions, given either as a single action or as a list of actions.
+struct ActionSetFile(KeyActionSet<EV_KEY>);
+
+impl<'de> Deserialize<'de> for ActionSetFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ActionSetVisitor)
+    }
+}
+
+struct ActionSetVisitor;
+
+impl<'de> Visitor<'de> for ActionSetVisitor {
+    type Value = ActionSetFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an action or a list of one to three actions")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        let action = ActionVisitor.visit_str(value)?;
+        Ok(ActionSetFile(KeyActionSet::Single(action.0)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let action = ActionVisitor.visit_map(map)?;
+        Ok(ActionSetFile(KeyActionSet::Single(action.0)))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut actions = Vec::new();
+        while let Some(ActionFile(action)) = seq.next_element()? {
+            actions.push(action);
+        }
+
+        let action_set = match actions.as_slice() {
+            [a1] => KeyActionSet::Single(*a1),
+            [a1, a2] => KeyActionSet::Double(*a1, *a2),
+            [a1, a2, a3] => KeyActionSet::Triple(*a1, *a2, *a3),
+            _ => return Err(de::Error::invalid_length(actions.len(), &self)),
+        };
+        Ok(ActionSetFile(action_set))
+    }
+}
+
+/// A single action.
+/// Key names send the key, `"caps_word"` and `"noop"` name actions without arguments
+/// and tables with a single entry describe the other actions (eg `{ push_layer = 1 }`).
+struct ActionFile(KeyAction<EV_KEY>);
+
+impl<'de> Deserialize<'de> for ActionFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ActionVisitor)
+    }
+}
+
+struct ActionVisitor;
+
+impl<'de> Visitor<'de> for ActionVisitor {
+    type Value = ActionFile;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a key name, an action name or an action table")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        let action = match value {
+            "caps_word" => KeyAction::CapsWord,
+            "noop" => KeyAction::NoOp,
+            name => {
+                let key = keynames::parse(name).map_err(E::custom)?;
+                KeyAction::SendKey(key)
+            }
+        };
+        Ok(ActionFile(action))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        const ACTIONS: &[&str] = &["send", "stop", "push_layer", "pop_layer", "num_word"];
+
+        let name: String = map.next_key()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        let action = match name.as_str() {
+            "send" => KeyAction::SendKey(map.next_value::<KeyName>()?.0),
+            "stop" => KeyAction::StopKey(map.next_value::<KeyName>()?.0),
+            "push_layer" => KeyAction::PushLayer(map.next_value()?),
+            "pop_layer" => KeyAction::PopLayer(map.next_value()?),
+            "num_word" => KeyAction::NumWord(map.next_value()?),
+            other => return Err(de::Error::unknown_field(other, ACTIONS)),
+        };
+
+        if map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom("an action table must have a single entry"));
+        }
+        Ok(ActionFile(action))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_keymap_is_valid() {
+        let config: Config = include_str!("../examples/keymap.toml").parse().unwrap();
+
+        assert!(matches!(
+            config.keymap.keys.get(&(0, EV_KEY::KEY_CAPSLOCK)),
+            Some(KeyConf::Hold(_))
+        ));
+        assert!(config.keymap.word_mode.is_some());
+        assert_eq!(config.keymap.overrides.len(), 1);
+    }
+
+    #[test]
+    fn test_tap_shorthand_and_settings() {
+        let config: Config = r#"
+            default_layer = 1
+
+            [settings]
+            hold_ksm_delay = 200
+            repeat = { delay = 300, interval = 30 }
+            hold_repeat = "inherit"
+
+            [[layers]]
+            id = 1
+            [layers.keys]
+            KEY_J = "KEY_DOWN"
+            KEY_K = ["KEY_LEFTCTRL", { push_layer = 2 }]
+        "#.parse().unwrap();
+
+        let keymap = &config.keymap;
+        assert_eq!(keymap.default_layer, 1);
+        assert_eq!(keymap.settings.hold_ksm_delay, Duration::from_millis(200));
+        assert_eq!(keymap.settings.hold_repeat, KeyRepeat::Inherit);
+        assert_eq!(keymap.settings.repeat.unwrap().interval, Duration::from_millis(30));
+
+        match keymap.keys.get(&(1, EV_KEY::KEY_K)) {
+            Some(KeyConf::Tap(conf)) => assert_eq!(
+                conf.tap,
+                KeyActionSet::Double(KeyAction::SendKey(EV_KEY::KEY_LEFTCTRL), KeyAction::PushLayer(2))
+            ),
+            other => panic!("unexpected key conf: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_key_name_reports_position() {
+        let err = r#"
+[[layers]]
+id = 0
+[layers.keys]
+KEY_A = "KEY_NOPE"
+"#.parse::<Config>().unwrap_err();
+
+        assert!(err.to_string().contains("unknown key name `KEY_NOPE`"), "{}", err);
+        assert_eq!(err.line_col().map(|(line, _)| line), Some(5));
+    }
+
+    #[test]
+    fn test_malformed_behavior_is_rejected() {
+        let err = r#"
+[[layers]]
+id = 0
+[layers.keys]
+KEY_A = { behavior = "hold", tap = "KEY_ESC" }
+"#.parse::<Config>().unwrap_err();
+
+        assert!(err.to_string().contains("missing field `hold`"), "{}", err);
+        assert!(err.line_col().is_some());
+    }
+
+    #[test]
+    fn test_unimplemented_behavior_is_rejected() {
+        let err = r#"
+[[layers]]
+id = 0
+[layers.keys]
+KEY_A = { behavior = "double_tap", tap = "KEY_A", double_tap = "KEY_B" }
+"#.parse::<Config>().unwrap_err();
+
+        assert!(err.to_string().contains("unknown variant `double_tap`"), "{}", err);
+        assert_eq!(err.line_col().map(|(line, _)| line), Some(5));
+    }
+
+    #[test]
+    fn test_device_keymaps() {
+        let config: Config = r#"
+[[layers]]
+id = 0
+[layers.keys]
+KEY_CAPSLOCK = "KEY_ESC"
+
+[[devices]]
+match = "AT Translated"
+[devices.settings]
+hold_ksm_delay = 150
+[[devices.layers]]
+id = 0
+[devices.layers.keys]
+KEY_A = { behavior = "hold", tap = "KEY_A", hold = "KEY_LEFTMETA" }
+
+[[devices]]
+match = "feed:beef"
+"#.parse().unwrap();
+
+        let device = |name: &str, product_id| DeviceInfo {
+            path: PathBuf::from("/dev/input/event3"),
+            name: name.to_string(),
+            phys: None,
+            vendor_id: 0xfeed,
+            product_id,
+            has_keys: true,
+            is_keyboard: true,
+            by_id: Vec::new(),
+        };
+
+        let laptop = config.keymap_for(&device("AT Translated Set 2 keyboard", 0xbeef));
+        assert_eq!(laptop.settings.hold_ksm_delay, Duration::from_millis(150));
+        assert!(matches!(laptop.keys.get(&(0, EV_KEY::KEY_A)), Some(KeyConf::Hold(_))));
+        assert!(!laptop.keys.contains_key(&(0, EV_KEY::KEY_CAPSLOCK)));
+
+        let split = config.device_keymap(&device("Split keyboard", 0xbeef)).unwrap();
+        assert!(split.keys.is_empty());
+
+        let other = device("Other keyboard", 0x0001);
+        assert!(config.device_keymap(&other).is_none());
+        assert!(config.keymap_for(&other).keys.contains_key(&(0, EV_KEY::KEY_CAPSLOCK)));
+    }
+
+    #[test]
+    fn test_emergency_chord() {
+        let config: Config = "".parse().unwrap();
+        assert_eq!(config.emergency, EmergencyConf::default());
+
+        let config: Config = r#"
+[emergency]
+chord = ["KEY_LEFTCTRL", "KEY_RIGHTCTRL"]
+action = "passthrough"
+"#.parse().unwrap();
+        assert_eq!(config.emergency, EmergencyConf {
+            chord: vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_RIGHTCTRL],
+            action: EmergencyAction::Passthrough,
+        });
+    }
+}
@@ -0,0 +1,408 @@
+//! Control socket, to query and command a running runtime.
+//!
+//! Clients connect to a Unix domain socket and send requests as JSON objects, one per line,
+//! each of them being answered by a JSON object on a line of its own, eg:
+//!
+//! ```text
+//! {"command":"set_layer","layer":1}
+//! {"ok":true}
+//! {"command":"status"}
+//! {"ok":true,"paused":false,"keyboards":[{"keyboard":0,"layer":1}]}
+//! ```
+//!
+//! After a `subscribe` request, the client is also sent a `StatusEvent` line whenever the
+//! runtime's state changes, eg `{"event":"layer_changed","keyboard":0,"layer":1}`.
+//!
+//! The socket is only accessible to the user running vkwrty, since it lets clients type keys.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::Behavior;
+use keywerty::mapper::LayerId;
+use serde::{Deserialize, Serialize};
+
+use crate::keynames;
+use crate::keynames::UnknownKeyName;
+
+
+/// Longest request accepted, clients sending longer lines are disconnected
+/// rather than buffered without end.
+pub const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// Requests understood by the control socket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Report the active layer of every keyboard and whether remapping is paused
+    Status,
+    /// Make `layer` the default layer of the keyboard at index `keyboard`, or of every keyboard
+    SetLayer {
+        layer: LayerId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keyboard: Option<usize>,
+    },
+    /// Send the keys as they're typed, until resumed
+    Pause,
+    Resume,
+    /// Reload the configuration file
+    Reload,
+    /// Tap the keys one after the other, see `parse_chord` for their format
+    Type { keys: Vec<String> },
+    /// Send `StatusEvent`s to the client until it disconnects
+    Subscribe,
+}
+
+/// State of a keyboard run by the runtime
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyboardStatus {
+    /// Index of the keyboard, the shared keyboard being 0
+    pub keyboard: usize,
+    /// Name of the device owning the keyboard, `None` for the shared keyboard
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// `None` for keyboards without layers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<LayerId>,
+}
+
+/// Answer to a request, fields other than `ok` are only set when relevant.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keyboards: Vec<KeyboardStatus>,
+}
+
+/// Change of the runtime's state, sent to the subscribed clients.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StatusEvent {
+    /// Keys of the keyboard at index `keyboard` are now mapped on `layer`
+    LayerChanged { keyboard: usize, layer: LayerId },
+    CapsWord { keyboard: usize, active: bool },
+    /// Num Word was turned on with `layer`, or off
+    NumWord { keyboard: usize, layer: Option<LayerId> },
+    /// The behavior of `key` waits for more events (eg a hold key which may still be tapped)
+    BehaviorPending { keyboard: usize, key: String },
+    /// The behavior of `key` was decided, see `behavior_name`, `None` if the keyboard was reset before
+    BehaviorResolved {
+        keyboard: usize,
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        behavior: Option<String>,
+    },
+    Paused { paused: bool },
+    ConfigReloaded,
+    DeviceAttached { device: String, path: PathBuf },
+    DeviceDetached { device: String, path: PathBuf },
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Self { ok: true, ..Self::default() }
+    }
+
+    pub fn error(error: impl ToString) -> Self {
+        Self { ok: false, error: Some(error.to_string()), ..Self::default() }
+    }
+}
+
+/// Name of `behavior` in status events, eg `hold`.
+pub fn behavior_name(behavior: Behavior) -> &'static str {
+    match behavior {
+        Behavior::Tap => "tap",
+        Behavior::Hold => "hold",
+        Behavior::LongPress => "long_press",
+    }
+}
+
+/// Parse keys pressed together, given by their names joined with `+` (eg `leftctrl+c`).
+pub fn parse_chord(chord: &str) -> Result<Vec<EV_KEY>, UnknownKeyName> {
+    chord.split('+').map(|name| keynames::parse(name.trim())).collect()
+}
+
+/// Socket path used unless set otherwise, which depends on the user running vkwrty:
+/// `/run/user/<uid>/vkwrty.sock` if that directory exists, `/tmp/vkwrty-<uid>.sock` otherwise.
+pub fn default_socket_path() -> PathBuf {
+    let uid = unsafe { libc::geteuid() };
+    let runtime_dir = PathBuf::from(format!("/run/user/{}", uid));
+    if runtime_dir.is_dir() {
+        runtime_dir.join("vkwrty.sock")
+    } else {
+        PathBuf::from(format!("/tmp/vkwrty-{}.sock", uid))
+    }
+}
+
+
+/// Client connected to the control socket
+struct Client {
+    stream: UnixStream,
+    /// Bytes of a partially received request
+    pending: Vec<u8>,
+    /// Whether the client is sent status events
+    subscribed: bool,
+}
+
+/// Listens on the control socket and reads the requests of its clients.
+///
+/// The listener and the clients don't block, they're meant to be monitored through epoll.
+/// The socket file is removed when the server is dropped.
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+impl ControlServer {
+    /// Listen at `path`, replacing the socket left by a vkwrty which didn't shut down cleanly.
+    /// Fails if another process is listening at `path` already, or if `path` belongs to
+    /// another user (eg created beforehand in `/tmp`).
+    ///
+    /// The socket is only accessible to the user running vkwrty.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path).ok();
+        let uid = unsafe { libc::geteuid() };
+        if metadata.as_ref().is_some_and(|metadata| metadata.uid() != uid) {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, format!("{} belongs to another user", path.display())));
+        }
+        let is_socket = metadata.is_some_and(|metadata| metadata.file_type().is_socket());
+        if is_socket {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(ErrorKind::AddrInUse, format!("{} is in use", path.display())));
+            }
+            fs::remove_file(path)?;
+        }
+
+        // the socket is created with the permissions left by the umask
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path: path.to_path_buf(), clients: Vec::new() })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accept the pending connections, return the file descriptors of the new clients.
+    pub fn accept(&mut self) -> Vec<RawFd> {
+        let mut accepted = Vec::new();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = stream.set_nonblocking(true) {
+                        log::warn!("failed to set up control client: {}", err);
+                        continue;
+                    }
+                    accepted.push(stream.as_raw_fd());
+                    self.clients.push(Client { stream, pending: Vec::new(), subscribed: false });
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("failed to accept control client: {}", err);
+                    break;
+                }
+            }
+        }
+        accepted
+    }
+
+    /// File descriptors of the connected clients
+    pub fn clients(&self) -> Vec<RawFd> {
+        self.clients.iter().map(|client| client.stream.as_raw_fd()).collect()
+    }
+
+    /// Read the complete requests sent by the client `fd`, or the reason they're invalid.
+    ///
+    /// Clients which disconnected are dropped, closing their socket also removes it from epoll.
+    /// Clients sending a request longer than `MAX_REQUEST_LEN` are told so, then dropped
+    /// along with the requests they sent before.
+    pub fn read_requests(&mut self, fd: RawFd) -> Vec<Result<Request, String>> {
+        let position = match self.clients.iter().position(|client| client.stream.as_raw_fd() == fd) {
+            Some(position) => position,
+            None => return Vec::new(),
+        };
+        let client = &mut self.clients[position];
+
+        let mut buffer = [0u8; 1024];
+        let mut too_long = false;
+        let disconnected = loop {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => break true,
+                Ok(count) => {
+                    client.pending.extend_from_slice(&buffer[..count]);
+                    let line_start = client.pending.iter().rposition(|byte| *byte == b'\n').map_or(0, |end| end + 1);
+                    if client.pending.len() - line_start > MAX_REQUEST_LEN {
+                        too_long = true;
+                        break false;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break false,
+                Err(err) => {
+                    log::warn!("failed to read control request: {}", err);
+                    break true;
+                }
+            }
+        };
+
+        if too_long {
+            log::warn!("control request longer than {} bytes, disconnecting the client", MAX_REQUEST_LEN);
+            let response = Response::error(format!("request too long, requests are at most {} bytes", MAX_REQUEST_LEN));
+            if self.send_line(position, &response) {
+                self.clients.remove(position);
+            }
+            return Vec::new();
+        }
+
+        let mut requests = Vec::new();
+        while let Some(end) = client.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = client.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                requests.push(serde_json::from_str(&line).map_err(|err| format!("invalid request: {}", err)));
+            }
+        }
+        if disconnected {
+            self.clients.remove(position);
+        }
+        requests
+    }
+
+    /// Send `response` to the client `fd`, dropping the client if it can't take it.
+    pub fn respond(&mut self, fd: RawFd, response: &Response) {
+        if let Some(position) = self.clients.iter().position(|client| client.stream.as_raw_fd() == fd) {
+            self.send_line(position, response);
+        }
+    }
+
+    /// Send the status events to the client `fd` from now on.
+    pub fn subscribe(&mut self, fd: RawFd) {
+        if let Some(client) = self.clients.iter_mut().find(|client| client.stream.as_raw_fd() == fd) {
+            client.subscribed = true;
+        }
+    }
+
+    /// Send `event` to every subscribed client.
+    pub fn broadcast(&mut self, event: &StatusEvent) {
+        let mut position = 0;
+        while position < self.clients.len() {
+            if !self.clients[position].subscribed || self.send_line(position, event) {
+                position += 1;
+            }
+        }
+    }
+
+    /// Send `message` as a JSON line to the client at `position`.
+    /// Return whether it was sent, the client is dropped otherwise.
+    fn send_line(&mut self, position: usize, message: &impl Serialize) -> bool {
+        let mut line = serde_json::to_vec(message).expect("messages are always serializable");
+        line.push(b'\n');
+        // messages are small, a client whose socket is full isn't reading them
+        match self.clients[position].stream.write_all(&line) {
+            Ok(()) => true,
+            Err(err) => {
+                log::warn!("failed to write to control client, disconnecting it: {}", err);
+                self.clients.remove(position);
+                false
+            }
+        }
+    }
+}
+
+impl AsRawFd for ControlServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+/// Send `request` to the runtime listening at `path` and wait for its response.
+pub fn send_request(path: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+/// Subscribe to the status events of the runtime listening at `path`,
+/// calling `on_event` with each of them until the runtime goes away or `on_event` returns false.
+pub fn subscribe(path: &Path, mut on_event: impl FnMut(StatusEvent) -> bool) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(&Request::Subscribe)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let response: Response = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(ErrorKind::UnexpectedEof.into()),
+    };
+    if !response.ok {
+        return Err(io::Error::other(response.error.unwrap_or_default()));
+    }
+    for line in lines {
+        if !on_event(serde_json::from_str(&line?)?) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_and_responses_are_json_lines() {
+        let request: Request = serde_json::from_str(r#"{"command":"set_layer","layer":2}"#).unwrap();
+        assert_eq!(request, Request::SetLayer { layer: 2, keyboard: None });
+        let request: Request = serde_json::from_str(r#"{"command":"type","keys":["h","leftshift+i"]}"#).unwrap();
+        assert_eq!(request, Request::Type { keys: vec!["h".to_string(), "leftshift+i".to_string()] });
+        assert!(serde_json::from_str::<Request>(r#"{"command":"explode"}"#).is_err());
+
+        assert_eq!(serde_json::to_string(&Response::ok()).unwrap(), r#"{"ok":true}"#);
+        assert_eq!(
+            serde_json::to_string(&StatusEvent::LayerChanged { keyboard: 0, layer: 1 }).unwrap(),
+            r#"{"event":"layer_changed","keyboard":0,"layer":1}"#,
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::error("no")).unwrap(),
+            r#"{"ok":false,"error":"no"}"#,
+        );
+    }
+
+    #[test]
+    fn test_parse_chord() {
+        assert_eq!(parse_chord("leftctrl + c").unwrap(), vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_C]);
+        assert_eq!(parse_chord("a").unwrap(), vec![EV_KEY::KEY_A]);
+        assert!(parse_chord("leftctrl+nope").is_err());
+    }
+}
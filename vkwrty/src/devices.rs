@@ -0,0 +1,385 @@
+//! Input device discovery and selection.
+//!
+//! Devices are found under `/dev/input` and can be selected by path
+//! (including `/dev/input/by-id` links), by `vendor:product` id or by a name substring.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use evdev_rs::{Device, DeviceWrapper};
+use evdev_rs::enums::{EventCode, EventType, EV_KEY};
+
+use crate::Error;
+use crate::Result;
+
+
+/// Directory where input devices are found
+pub const INPUT_DIR: &str = "/dev/input";
+const BY_ID_DIR: &str = "/dev/input/by-id";
+
+
+/// Description of an input device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    /// Event file of the device, eg `/dev/input/event3`
+    pub path: PathBuf,
+    pub name: String,
+    pub phys: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Whether the device emits key events at all (eg mouse buttons)
+    pub has_keys: bool,
+    /// Whether the device has the keys of a typing keyboard
+    pub is_keyboard: bool,
+    /// Links in `/dev/input/by-id` pointing to the device
+    pub by_id: Vec<PathBuf>,
+}
+
+impl DeviceInfo {
+    /// Read the description of the device at `path`.
+    ///
+    /// Links (eg in `/dev/input/by-id`) are resolved, so that `path` is always the event file
+    /// the devices are told apart by.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let path = event_file(path)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)?;
+        let device = Device::new_from_file(file)?;
+
+        let has_key = |key| device.has_event_code(&EventCode::EV_KEY(key));
+        let is_keyboard = [EV_KEY::KEY_A, EV_KEY::KEY_Z, EV_KEY::KEY_SPACE, EV_KEY::KEY_ENTER]
+            .iter()
+            .all(|key| has_key(*key));
+
+        Ok(Self {
+            path,
+            name: device.name().unwrap_or("").to_string(),
+            phys: device.phys().map(str::to_string),
+            vendor_id: device.vendor_id(),
+            product_id: device.product_id(),
+            has_keys: device.has_event_type(&EventType::EV_KEY),
+            is_keyboard,
+            by_id: Vec::new(),
+        })
+    }
+
+    /// Whether `other` describes the same physical device, possibly at another path
+    /// (eg the same keyboard plugged back in).
+    pub fn is_same_device(&self, other: &DeviceInfo) -> bool {
+        self.vendor_id == other.vendor_id
+            && self.product_id == other.product_id
+            && self.name == other.name
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (self.is_keyboard, self.has_keys) {
+            (true, _) => "keyboard",
+            (false, true) => "keys",
+            (false, false) => "-",
+        };
+        write!(f, "{:<20} {:04x}:{:04x}  {:<8}  {}",
+            self.path.display(), self.vendor_id, self.product_id, kind, self.name)?;
+        if let Some(phys) = &self.phys {
+            write!(f, "  ({})", phys)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// List the event files under `/dev/input`, ordered by event number.
+pub fn event_files() -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(INPUT_DIR)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    paths.sort_by_key(|path| event_number(path));
+    Ok(paths)
+}
+
+/// List the event devices under `/dev/input`, ordered by event number.
+///
+/// Devices which can't be read are skipped, unless none of them can be read,
+/// in which case the error is returned (eg insufficient permissions).
+pub fn list_devices() -> io::Result<Vec<DeviceInfo>> {
+    let paths = event_files()?;
+
+    let by_id = read_by_id_links();
+    let mut devices = Vec::new();
+    let mut last_error = None;
+    for path in paths {
+        match DeviceInfo::read(&path) {
+            Ok(mut device) => {
+                device.by_id = by_id.get(&path).cloned().unwrap_or_default();
+                devices.push(device);
+            }
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    match last_error {
+        Some(err) if devices.is_empty() => Err(err),
+        _ => Ok(devices),
+    }
+}
+
+fn event_number(path: &Path) -> u32 {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.trim_start_matches("event").parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+/// Map of event device paths to the `/dev/input/by-id` links pointing to them
+fn read_by_id_links() -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut links: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let entries = match fs::read_dir(BY_ID_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return links,
+    };
+
+    for link in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        if let Ok(target) = fs::canonicalize(&link) {
+            links.entry(target).or_default().push(link);
+        }
+    }
+    links
+}
+
+
+/// Criteria used to select an input device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceSelector {
+    /// Event file path or a link to it (eg in `/dev/input/by-id`)
+    Path(PathBuf),
+    /// `vendor:product` ids, in hexadecimal
+    VendorProduct(u16, u16),
+    /// Case-insensitive substring of the device name
+    Name(String),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        if value.starts_with('/') || value.starts_with('.') {
+            return Ok(DeviceSelector::Path(PathBuf::from(value)));
+        }
+
+        let ids = value.split_once(':')
+            .filter(|(vendor, product)| vendor.len() == 4 && product.len() == 4)
+            .and_then(|(vendor, product)| {
+                let vendor = u16::from_str_radix(vendor, 16).ok()?;
+                let product = u16::from_str_radix(product, 16).ok()?;
+                Some((vendor, product))
+            });
+        match ids {
+            Some((vendor, product)) => Ok(DeviceSelector::VendorProduct(vendor, product)),
+            None => Ok(DeviceSelector::Name(value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Path(path) => write!(f, "{}", path.display()),
+            DeviceSelector::VendorProduct(vendor, product) => write!(f, "{:04x}:{:04x}", vendor, product),
+            DeviceSelector::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Path(path) => {
+                let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                device.path == canonical || device.by_id.contains(path)
+            }
+            DeviceSelector::VendorProduct(vendor, product) => {
+                device.vendor_id == *vendor && device.product_id == *product
+            }
+            DeviceSelector::Name(name) => {
+                device.name.to_lowercase().contains(&name.to_lowercase())
+            }
+        }
+    }
+
+    /// Find the single device among `devices` matched by the selector.
+    ///
+    /// When many devices match, keyboards are preferred over other devices
+    /// (eg the keyboard and mouse interfaces of a wireless receiver).
+    pub fn select<'a>(&self, devices: &'a [DeviceInfo]) -> Result<&'a DeviceInfo> {
+        let mut matches: Vec<&DeviceInfo> = devices.iter()
+            .filter(|device| self.matches(device))
+            .collect();
+        if matches.iter().any(|device| device.is_keyboard) {
+            matches.retain(|device| device.is_keyboard);
+        }
+
+        match matches.as_slice() {
+            [] => Err(Error::NoDevice(self.to_string())),
+            [device] => Ok(device),
+            _ => Err(Error::AmbiguousDevice(
+                self.to_string(),
+                matches.iter().map(|device| device.path.clone()).collect(),
+            )),
+        }
+    }
+}
+
+/// Resolve `path` to the event file it designates, following links.
+fn event_file(path: &Path) -> io::Result<PathBuf> {
+    fs::canonicalize(path)
+}
+
+/// Find the device matched by `selector`.
+///
+/// Paths are opened directly, other selectors are resolved against `list_devices`.
+pub fn find_device(selector: &DeviceSelector) -> Result<DeviceInfo> {
+    if let DeviceSelector::Path(path) = selector {
+        return DeviceInfo::read(path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => Error::NoDevice(selector.to_string()),
+            _ => err.into(),
+        });
+    }
+    let devices = list_devices()?;
+    selector.select(&devices).cloned()
+}
+
+/// Interactively choose keyboards, prompting through stderr and reading the choice from stdin.
+///
+/// Many keyboards can be chosen at once by separating their numbers with spaces or commas.
+pub fn choose_devices() -> Result<Vec<DeviceInfo>> {
+    let keyboards: Vec<DeviceInfo> = list_devices()?
+        .into_iter()
+        .filter(|device| device.is_keyboard)
+        .collect();
+    if keyboards.is_empty() {
+        return Err(Error::NoDevice("keyboard".to_string()));
+    }
+
+    eprintln!("Available keyboards:");
+    for (index, device) in keyboards.iter().enumerate() {
+        eprintln!("  [{}] {}", index + 1, device);
+    }
+
+    let stdin = io::stdin();
+    loop {
+        eprint!("Select keyboards [1-{}]: ", keyboards.len());
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(Error::NoDevice("keyboard".to_string()));
+        }
+        match parse_choices(&line, keyboards.len()) {
+            Some(choices) => {
+                return Ok(choices.into_iter().map(|choice| keyboards[choice - 1].clone()).collect());
+            }
+            None => eprintln!("invalid choice: {}", line.trim()),
+        }
+    }
+}
+
+/// Parse a non empty list of distinct numbers in `1..=max`, separated by spaces or commas.
+fn parse_choices(line: &str, max: usize) -> Option<Vec<usize>> {
+    let mut choices = Vec::new();
+    for choice in line.split(|c: char| c == ',' || c.is_whitespace()).filter(|choice| !choice.is_empty()) {
+        let choice: usize = choice.parse().ok().filter(|choice| (1..=max).contains(choice))?;
+        if !choices.contains(&choice) {
+            choices.push(choice);
+        }
+    }
+    Some(choices).filter(|choices| !choices.is_empty())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(path: &str, name: &str, is_keyboard: bool) -> DeviceInfo {
+        DeviceInfo {
+            path: PathBuf::from(path),
+            name: name.to_string(),
+            phys: None,
+            vendor_id: 0x046d,
+            product_id: 0xc52b,
+            has_keys: true,
+            is_keyboard,
+            by_id: vec![PathBuf::from("/dev/input/by-id/usb-Logitech-event-kbd")],
+        }
+    }
+
+    #[test]
+    fn test_selector_parsing() {
+        assert_eq!("/dev/input/event3".parse(), Ok(DeviceSelector::Path(PathBuf::from("/dev/input/event3"))));
+        assert_eq!("046d:C52B".parse(), Ok(DeviceSelector::VendorProduct(0x046d, 0xc52b)));
+        assert_eq!("logitech".parse(), Ok(DeviceSelector::Name("logitech".to_string())));
+        assert_eq!("usb:keyboard".parse(), Ok(DeviceSelector::Name("usb:keyboard".to_string())));
+    }
+
+    #[test]
+    fn test_select_prefers_keyboards_and_reports_ambiguity() {
+        let devices = vec![
+            device("/dev/input/event3", "Logitech USB Receiver", true),
+            device("/dev/input/event4", "Logitech USB Receiver Mouse", false),
+            device("/dev/input/event5", "AT Translated Set 2 keyboard", true),
+        ];
+
+        let selector = DeviceSelector::Name("LOGITECH".to_string());
+        assert_eq!(selector.select(&devices).unwrap().path, PathBuf::from("/dev/input/event3"));
+
+        let selector = DeviceSelector::Name("mouse".to_string());
+        assert_eq!(selector.select(&devices).unwrap().path, PathBuf::from("/dev/input/event4"));
+
+        let selector = DeviceSelector::Path(PathBuf::from("/dev/input/by-id/usb-Logitech-event-kbd"));
+        assert!(matches!(selector.select(&devices), Err(Error::AmbiguousDevice(_, _))));
+
+        let selector = DeviceSelector::VendorProduct(0x1234, 0x5678);
+        assert!(matches!(selector.select(&devices), Err(Error::NoDevice(_))));
+    }
+
+    #[test]
+    fn test_links_are_resolved_to_event_files() {
+        let dir = std::env::temp_dir().join(format!("vkwrty-devices-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("by-id")).unwrap();
+        fs::write(dir.join("event3"), "").unwrap();
+        let link = dir.join("by-id").join("usb-Logitech-event-kbd");
+        std::os::unix::fs::symlink("../event3", &link).unwrap();
+
+        let event = fs::canonicalize(dir.join("event3")).unwrap();
+        let resolved = event_file(&link);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolved.unwrap(), event);
+        assert_eq!(event.file_name().unwrap(), "event3");
+    }
+
+    #[test]
+    fn test_parse_choices() {
+        assert_eq!(parse_choices("2\n", 3), Some(vec![2]));
+        assert_eq!(parse_choices("3, 1 3", 3), Some(vec![3, 1]));
+        assert_eq!(parse_choices("1 4", 3), None);
+        assert_eq!(parse_choices("one", 3), None);
+        assert_eq!(parse_choices(" \n", 3), None);
+    }
+}
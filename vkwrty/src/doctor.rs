@@ -0,0 +1,191 @@
+//! Diagnostics for the permissions vkwrty needs.
+//!
+//! vkwrty reads input devices and writes to `/dev/uinput`, which are only accessible to root
+//! unless the system is set up otherwise. The checks below tell what is missing to run
+//! vkwrty as a regular user.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::privileges;
+use crate::virtual_dev::UINPUT_PATH;
+
+
+/// Present once the `uinput` module is loaded, or if it's built into the kernel
+const UINPUT_SYSFS_PATH: &str = "/sys/class/misc/uinput";
+
+/// udev rule giving the `uinput` group access to `/dev/uinput`.
+/// Input devices belong to the `input` group already on most distributions.
+pub const UDEV_RULE: &str = r#"KERNEL=="uinput", GROUP="uinput", MODE="0660", OPTIONS+="static_node=uinput""#;
+
+/// Result of a single check, along with how to fix it if it failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub description: String,
+    pub passed: bool,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(description: String, passed: bool, hint: impl Into<String>) -> Self {
+        Self { description, passed, hint: if passed { None } else { Some(hint.into()) } }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", if self.passed { " ok " } else { "FAIL" }, self.description)?;
+        if let Some(hint) = &self.hint {
+            for line in hint.lines() {
+                write!(f, "\n       {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run every check, `devices` being the event files of the input devices vkwrty should intercept.
+pub fn run_checks(devices: &[PathBuf]) -> Vec<Check> {
+    let mut checks: Vec<Check> = devices.iter().map(|path| check_device_access(path)).collect();
+    checks.push(check_uinput_module());
+    checks.push(check_uinput_access());
+
+    let groups = current_groups();
+    checks.push(check_group("input", group_id("input"), &groups));
+    checks.push(check_group("uinput", group_id("uinput"), &groups));
+    checks
+}
+
+/// Check what `user`, given to `--user`, can still do once vkwrty dropped root privileges:
+/// intercept the devices plugged later on and create their passthrough devices.
+pub fn check_user(user: &str) -> Vec<Check> {
+    let is_root = privileges::user_id(user) == Some(0);
+    let groups = privileges::user_groups(user).unwrap_or_default();
+    let is_member = |name| is_root || group_id(name).is_some_and(|gid| groups.contains(&gid));
+    vec![
+        Check::new(
+            format!("{} can open the devices plugged after vkwrty started (member of the `input` group)", user),
+            is_member("input"),
+            format!(
+                "with --user {}, devices plugged later on won't be intercepted, add the user to the `input` group:\n\
+                 sudo usermod -aG input {}",
+                user, user,
+            ),
+        ),
+        Check::new(
+            format!("{} can create passthrough devices (member of the `uinput` group)", user),
+            is_member("uinput"),
+            format!(
+                "with --user {}, devices plugged later on won't get a passthrough device, \
+                 their events other than keys are lost, add the user to the `uinput` group:\n\
+                 sudo usermod -aG uinput {}",
+                user, user,
+            ),
+        ),
+    ]
+}
+
+fn check_device_access(path: &Path) -> Check {
+    Check::new(
+        format!("read access to {}", path.display()),
+        has_access(path, libc::R_OK),
+        "add your user to the `input` group: sudo usermod -aG input $USER",
+    )
+}
+
+fn check_uinput_module() -> Check {
+    Check::new(
+        "uinput module loaded".to_string(),
+        Path::new(UINPUT_SYSFS_PATH).exists(),
+        "load it with `sudo modprobe uinput`, and on boot with:\n\
+         echo uinput | sudo tee /etc/modules-load.d/uinput.conf",
+    )
+}
+
+fn check_uinput_access() -> Check {
+    Check::new(
+        format!("write access to {}", UINPUT_PATH),
+        has_access(Path::new(UINPUT_PATH), libc::R_OK | libc::W_OK),
+        format!(
+            "create the `uinput` group, add your user to it and give it access to {} through udev:\n\
+             sudo groupadd -f uinput && sudo usermod -aG uinput $USER\n\
+             echo '{}' | sudo tee /etc/udev/rules.d/99-vkwrty.rules\n\
+             sudo udevadm control --reload-rules && sudo udevadm trigger",
+            UINPUT_PATH, UDEV_RULE,
+        ),
+    )
+}
+
+/// Check the membership to the group `name`, whose id is `gid` if it exists.
+/// Groups joined since the session started only count once logged in again.
+fn check_group(name: &str, gid: Option<libc::gid_t>, groups: &[libc::gid_t]) -> Check {
+    let hint = match gid {
+        Some(_) => format!("sudo usermod -aG {} $USER, then log in again", name),
+        None => format!("the `{}` group doesn't exist, create it with: sudo groupadd {}", name, name),
+    };
+    Check::new(
+        format!("member of the `{}` group", name),
+        gid.is_some_and(|gid| groups.contains(&gid)),
+        hint,
+    )
+}
+
+fn has_access(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Ids of the groups of the process, including its effective group
+fn current_groups() -> Vec<libc::gid_t> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups.push(unsafe { libc::getegid() });
+    groups
+}
+
+fn group_id(name: &str) -> Option<libc::gid_t> {
+    let name = CString::new(name).ok()?;
+    let group = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group.is_null() {
+        None
+    } else {
+        Some(unsafe { (*group).gr_gid })
+    }
+}
+
+/// Name of the user running vkwrty, as seen by the system
+pub fn current_user() -> Option<String> {
+    let passwd = unsafe { libc::getpwuid(libc::geteuid()) };
+    if passwd.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr((*passwd).pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_check_hints() {
+        let member = check_group("input", Some(104), &[100, 104]);
+        assert!(member.passed);
+        assert_eq!(member.hint, None);
+
+        let not_member = check_group("input", Some(104), &[100]);
+        assert!(!not_member.passed);
+        assert!(not_member.hint.unwrap().contains("usermod -aG input"));
+
+        let missing = check_group("uinput", None, &[100]);
+        assert!(!missing.passed);
+        assert!(missing.hint.unwrap().contains("groupadd uinput"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Dry runs, which show what a keymap does without taking over the devices.
+//!
+//! Devices are read without being grabbed, so their events keep reaching the OS, and the
+//! actions of the keyboards are discarded rather than emitted through a uinput device.
+//! The runtime's `Trace`s tell what happened to each event instead.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::{Action, Event};
+
+use crate::Result;
+use crate::Trace;
+use crate::control::StatusEvent;
+use crate::devices::DeviceInfo;
+use crate::io::{InputSource, OutputSink};
+use crate::keynames;
+use crate::monitor::EventIter;
+
+
+/// Input device read without being grabbed.
+pub struct UngrabbedDevice(EventIter);
+
+impl InputSource for UngrabbedDevice {
+    fn open(device: &DeviceInfo) -> Result<Self> {
+        EventIter::open_ungrabbed(&device.path).map(UngrabbedDevice)
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.0.is_disconnected()
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Iterator for UngrabbedDevice {
+    type Item = Event<EV_KEY>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next()
+    }
+}
+
+impl AsRawFd for UngrabbedDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+
+/// Output sink discarding the actions.
+pub struct DiscardOutput;
+
+impl OutputSink for DiscardOutput {
+    fn emit_events(&mut self, _actions: &[Action<EV_KEY>]) -> Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Describe `event`, eg `press KEY_A`.
+pub fn describe_event(event: &Event<EV_KEY>) -> String {
+    match event {
+        Event::KeyPress(key) => format!("press {}", keynames::name(*key)),
+        Event::KeyRelease(key) => format!("release {}", keynames::name(*key)),
+        Event::Poll => "poll".to_string(),
+    }
+}
+
+/// Describe the status changes of a dry run, the keyboard being told by the trace.
+fn describe_status(event: &StatusEvent) -> String {
+    match event {
+        StatusEvent::LayerChanged { layer, .. } => format!("layer changed to {}", layer),
+        StatusEvent::CapsWord { active: true, .. } => "caps word on".to_string(),
+        StatusEvent::CapsWord { active: false, .. } => "caps word off".to_string(),
+        StatusEvent::NumWord { layer: Some(layer), .. } => format!("num word on layer {}", layer),
+        StatusEvent::NumWord { layer: None, .. } => "num word off".to_string(),
+        StatusEvent::BehaviorPending { key, .. } => format!("{} pending", key),
+        StatusEvent::BehaviorResolved { key, behavior: Some(behavior), .. } => format!("{} resolved as {}", key, behavior),
+        StatusEvent::BehaviorResolved { key, behavior: None, .. } => format!("{} cancelled", key),
+        StatusEvent::Paused { paused: true } => "paused".to_string(),
+        StatusEvent::Paused { paused: false } => "resumed".to_string(),
+        StatusEvent::ConfigReloaded => "configuration reloaded".to_string(),
+        StatusEvent::DeviceAttached { device, .. } => format!("{} attached", device),
+        StatusEvent::DeviceDetached { device, .. } => format!("{} detached", device),
+    }
+}
+
+/// Describe `trace` on a single line: the device and event, the keyboard and its layer,
+/// the status changes and the actions, eg
+/// `event3  release KEY_CAPSLOCK  keyboard 0 layer 0  KEY_CAPSLOCK resolved as tap  [SendCode(KEY_ESC), Stop(KEY_ESC)]`.
+pub fn describe_trace(trace: &Trace) -> String {
+    let device = trace.device
+        .and_then(|device| device.path.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let layer = match trace.layer {
+        Some(layer) => format!("layer {}", layer),
+        None => "passthrough".to_string(),
+    };
+    let mut line = format!("{:<8} {:<28} keyboard {} {:<11}", device, describe_event(&trace.event), trace.keyboard, layer);
+    if !trace.status.is_empty() {
+        let status: Vec<String> = trace.status.iter().map(describe_status).collect();
+        line.push_str("  ");
+        line.push_str(&status.join(", "));
+    }
+    if !trace.actions.is_empty() {
+        line.push_str(&format!("  {:?}", trace.actions));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_describe_trace() {
+        let device = DeviceInfo {
+            path: PathBuf::from("/dev/input/event3"),
+            name: "AT Translated Set 2 keyboard".to_string(),
+            phys: None,
+            vendor_id: 1,
+            product_id: 1,
+            has_keys: true,
+            is_keyboard: true,
+            by_id: Vec::new(),
+        };
+        let status = [StatusEvent::BehaviorResolved {
+            keyboard: 0,
+            key: "KEY_CAPSLOCK".to_string(),
+            behavior: Some("hold".to_string()),
+        }];
+        let actions = [Action::SendCode(EV_KEY::KEY_LEFTCTRL)];
+        let trace = Trace {
+            device: Some(&device),
+            event: Event::KeyPress(EV_KEY::KEY_A),
+            keyboard: 0,
+            layer: Some(0),
+            status: &status,
+            actions: &actions,
+        };
+
+        assert_eq!(
+            describe_trace(&trace),
+            "event3   press KEY_A                  keyboard 0 layer 0      KEY_CAPSLOCK resolved as hold  [SendCode(KEY_LEFTCTRL)]"
+        );
+
+        let trace = Trace { device: None, event: Event::Poll, layer: None, status: &[], ..trace };
+        assert_eq!(describe_trace(&trace), "         poll                         keyboard 0 passthrough  [SendCode(KEY_LEFTCTRL)]");
+    }
+}
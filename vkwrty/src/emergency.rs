@@ -0,0 +1,131 @@
+//! Recovery from an unusable keymap.
+//!
+//! The emergency chord is detected on the raw events of the input devices, before they're
+//! fed into the keyboards, so that it works whatever the keymap does with its keys.
+//! Pressing it either stops vkwrty or toggles a passthrough mode, which sends the keys as they're typed.
+
+use std::collections::HashSet;
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::{Action, Event, Keyboard};
+
+
+/// Keys of the emergency chord unless configured otherwise
+pub const DEFAULT_CHORD: [EV_KEY; 3] = [EV_KEY::KEY_BACKSPACE, EV_KEY::KEY_ESC, EV_KEY::KEY_ENTER];
+
+/// What pressing the emergency chord does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmergencyAction {
+    /// Stop the runtime, releasing held keys and devices
+    Exit,
+    /// Toggle the passthrough mode, where keys are sent as they're typed
+    Passthrough,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmergencyConf {
+    pub chord: Vec<EV_KEY>,
+    pub action: EmergencyAction,
+}
+
+impl Default for EmergencyConf {
+    fn default() -> Self {
+        Self { chord: DEFAULT_CHORD.to_vec(), action: EmergencyAction::Exit }
+    }
+}
+
+
+/// Detects the emergency chord among raw key events.
+///
+/// The chord is pressed once all of its keys are down, whatever the order they were pressed in.
+/// An empty chord is never pressed.
+pub struct ChordDetector {
+    chord: Vec<EV_KEY>,
+    down: HashSet<EV_KEY>,
+}
+
+impl ChordDetector {
+    pub fn new(chord: Vec<EV_KEY>) -> Self {
+        Self { chord, down: HashSet::new() }
+    }
+
+    /// Track `event`, return whether it completes the chord.
+    pub fn feed(&mut self, event: &Event<EV_KEY>) -> bool {
+        match event {
+            Event::KeyPress(key) => {
+                self.down.insert(*key);
+                !self.chord.is_empty()
+                    && self.chord.contains(key)
+                    && self.chord.iter().all(|key| self.down.contains(key))
+            }
+            Event::KeyRelease(key) => {
+                self.down.remove(key);
+                false
+            }
+            Event::Poll => false,
+        }
+    }
+
+    /// Forget the keys which are down, eg when their device vanished.
+    pub fn clear(&mut self) {
+        self.down.clear();
+    }
+}
+
+
+/// Keyboard sending the keys as they're typed, like the `EchoerKb` example.
+///
+/// Releases of keys it didn't press are ignored, so that keys held when the passthrough
+/// mode is toggled aren't released twice.
+#[derive(Default)]
+pub struct PassthroughKeyboard {
+    pressed: Vec<EV_KEY>,
+}
+
+impl Keyboard<EV_KEY, EV_KEY> for PassthroughKeyboard {
+    fn transition(&mut self, event: Event<EV_KEY>) -> Vec<Action<EV_KEY>> {
+        match event {
+            Event::KeyPress(key) => {
+                if !self.pressed.contains(&key) {
+                    self.pressed.push(key);
+                }
+                vec![Action::SendCode(key)]
+            }
+            Event::KeyRelease(key) => match self.pressed.iter().position(|pressed| *pressed == key) {
+                Some(position) => {
+                    self.pressed.remove(position);
+                    vec![Action::Stop(key)]
+                }
+                None => Vec::new(),
+            },
+            Event::Poll => Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) -> Vec<Action<EV_KEY>> {
+        self.pressed.drain(..).map(Action::Stop).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chord_is_pressed_once_all_keys_are_down() {
+        let mut detector = ChordDetector::new(vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_ESC]);
+
+        assert!(!detector.feed(&Event::KeyPress(EV_KEY::KEY_ESC)));
+        assert!(!detector.feed(&Event::KeyPress(EV_KEY::KEY_A)));
+        assert!(detector.feed(&Event::KeyPress(EV_KEY::KEY_LEFTCTRL)));
+
+        assert!(!detector.feed(&Event::KeyRelease(EV_KEY::KEY_ESC)));
+        assert!(!detector.feed(&Event::KeyPress(EV_KEY::KEY_A)));
+        assert!(detector.feed(&Event::KeyPress(EV_KEY::KEY_ESC)));
+
+        detector.clear();
+        assert!(!detector.feed(&Event::KeyPress(EV_KEY::KEY_ESC)));
+        assert!(!ChordDetector::new(Vec::new()).feed(&Event::KeyPress(EV_KEY::KEY_ESC)));
+    }
+}
@@ -17,18 +17,16 @@ use num_traits::AsPrimitive;
 pub struct Epoll  {
     epoll_fd: RawFd,
     event_buff: Vec<libc::epoll_event>,
-    read_timeout: Duration,
 }
 
 impl Epoll {
 
     /// Create new epoll instance
-    pub fn new(event_buff_size: usize, read_timeout: Duration) -> Result<Self> {
+    pub fn new(event_buff_size: usize) -> Result<Self> {
         unsafe {
             let fd = libc::epoll_create1(0);
             if fd >= 1 {
                 let epoll = Self {
-                    read_timeout,
                     epoll_fd: fd,
                     event_buff: Vec::with_capacity(event_buff_size),
                 };
@@ -67,14 +65,29 @@ impl Epoll {
         }
     }
 
-    /// Perform an indefinite wait over the list of registered files.
-    /// `wait` blocks until any registered file is ready to read.
+    /// Remove file from Epoll's interest list.
+    pub fn unmonitor_file<F>(&mut self, file: &F) -> Result<()>
+    where F: AsRawFd
+    {
+        unsafe {
+            let result = libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_DEL, file.as_raw_fd(), std::ptr::null_mut());
+            if result < 0 {
+                Err(Error::last_os_error())
+            }
+            else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Perform a wait over the list of registered files.
+    /// `wait_timeout` blocks until any registered file is ready to read or for at most `timeout`.
     ///
     /// Return slice with file descriptors matching the ready files.
-    pub fn wait(&mut self) -> Result<impl Iterator<Item=RawFd> + '_> {
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<impl Iterator<Item=RawFd> + '_> {
         unsafe {
             // epoll timeout expects a number of milliseconds
-            let timeout: c_int = self.read_timeout.as_millis().as_();
+            let timeout: c_int = timeout.as_millis().as_();
             let event_count = libc::epoll_wait(self.epoll_fd, self.event_buff.as_mut_ptr(), self.event_buff.capacity().as_(), timeout);
             //eprintln!("epoll_wait result: {}", event_count);
             if event_count < 0 {
@@ -0,0 +1,87 @@
+//! Input device hotplug.
+//!
+//! Event devices appear and vanish from `/dev/input` as keyboards are plugged and unplugged.
+//! The directory is watched through `inotify`, so it can be monitored through epoll
+//! alongside the input devices themselves.
+
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+use std::path::{Path, PathBuf};
+
+use crate::inotify::Inotify;
+
+
+/// Change to the event devices of a directory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceChange {
+    /// An event device was created, or its permissions changed (eg set by udev after its creation)
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+/// Watches a directory, usually `/dev/input`, for event devices being created or removed.
+pub struct DeviceWatcher {
+    inotify: Inotify,
+    dir: PathBuf,
+}
+
+impl DeviceWatcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let mut inotify = Inotify::new()?;
+        inotify.add_watch(dir, libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_DELETE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM)?;
+        Ok(Self { inotify, dir: dir.to_path_buf() })
+    }
+
+    /// Consume pending notifications, return the changes to event devices in the order they happened.
+    pub fn drain(&mut self) -> Vec<DeviceChange> {
+        self.inotify.read_events()
+            .into_iter()
+            .filter(|(_, name)| name.to_str().is_some_and(|name| name.starts_with("event")))
+            .filter_map(|(mask, name)| {
+                let path = self.dir.join(name);
+                if mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                    Some(DeviceChange::Removed(path))
+                } else if mask & (libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_MOVED_TO) != 0 {
+                    Some(DeviceChange::Added(path))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl AsRawFd for DeviceWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_device_watcher_reports_event_devices_only() {
+        let dir = std::env::temp_dir().join(format!("vkwrty-hotplug-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut watcher = DeviceWatcher::new(&dir).unwrap();
+        assert!(watcher.drain().is_empty());
+
+        fs::write(dir.join("event7"), "").unwrap();
+        fs::write(dir.join("mouse0"), "").unwrap();
+        fs::remove_file(dir.join("event7")).unwrap();
+
+        assert_eq!(watcher.drain(), vec![
+            DeviceChange::Added(dir.join("event7")),
+            DeviceChange::Removed(dir.join("event7")),
+        ]);
+        assert!(watcher.drain().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
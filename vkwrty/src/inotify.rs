@@ -0,0 +1,81 @@
+//! Safe inotify wrap, watching directories for changes to their entries
+
+use std::ffi::{CString, OsString};
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+
+
+/// Safe interface around Linux's inotify.
+/// The file descriptor is non blocking, so it's meant to be monitored through epoll.
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+
+    /// Watch `path` for the events in `mask` (eg `libc::IN_CREATE`).
+    pub fn add_watch(&mut self, path: &Path, mask: u32) -> Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+        if unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Consume pending events, return the mask and file name of each of them.
+    pub fn read_events(&mut self) -> Vec<(u32, OsString)> {
+        let mut events = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let header_size = mem::size_of::<libc::inotify_event>();
+
+        loop {
+            let read = unsafe {
+                libc::read(self.fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+            };
+            if read <= 0 {
+                return events;
+            }
+
+            let mut offset = 0;
+            while offset + header_size <= read as usize {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + header_size;
+                let name = &buffer[name_start..name_start + event.len as usize];
+                // names are padded with null bytes
+                let name = name.split(|byte| *byte == 0).next().unwrap_or(&[]);
+
+                events.push((event.mask, OsString::from_vec(name.to_vec())));
+                offset = name_start + event.len as usize;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Interfaces between the `Runtime` and the OS.
+//!
+//! The runtime reads keyboard events from `InputSource`s and writes the keyboards' actions
+//! to an `OutputSink`. On Linux, sources are evdev devices (`EventIter`) and the sink is a
+//! uinput device (`UInputKeyboard`), in memory implementations are found in `memory`.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::{Action, Event};
+
+use crate::devices::DeviceInfo;
+use crate::monitor::EventIter;
+use crate::virtual_dev::UInputKeyboard;
+use crate::Result;
+
+
+/// Source of keyboard events, usually an input device.
+///
+/// Sources are monitored through epoll: their file descriptor is ready whenever
+/// events can be read, and iterating over the source returns the pending events.
+pub trait InputSource: AsRawFd + Iterator<Item = Event<EV_KEY>> + Sized {
+    /// Open and grab `device`, used when devices are attached by the runtime itself (eg hotplug).
+    fn open(device: &DeviceInfo) -> Result<Self>;
+
+    /// Whether the source was disconnected (eg unplugged), in which case it won't return events anymore.
+    fn is_disconnected(&self) -> bool;
+
+    /// Hand the source back to the OS, used when the runtime shuts down.
+    fn release(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl InputSource for EventIter {
+    fn open(device: &DeviceInfo) -> Result<Self> {
+        EventIter::open(&device.path)
+    }
+
+    fn is_disconnected(&self) -> bool {
+        EventIter::is_disconnected(self)
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        self.ungrab()
+    }
+}
+
+
+/// Destination of the keyboard actions, usually a virtual keyboard.
+pub trait OutputSink {
+    /// Emit `actions`, in order.
+    fn emit_events(&mut self, actions: &[Action<EV_KEY>]) -> Result<()>;
+}
+
+impl OutputSink for UInputKeyboard {
+    fn emit_events(&mut self, actions: &[Action<EV_KEY>]) -> Result<()> {
+        UInputKeyboard::emit_events(self, actions)
+    }
+}
@@ -0,0 +1,231 @@
+//! Human readable names for `EV_KEY` codes.
+//!
+//! Every key has a canonical name, as defined in linux's `input-event-codes.h` (eg `KEY_CAPSLOCK`).
+//! Names are looked up case-insensitively and may omit the `KEY_` prefix (eg `capslock`).
+//! Common short names, as used by QMK, keyd or kanata (eg `caps`, `lctl` or `KC_BSPC`), are accepted as aliases.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::sync::OnceLock;
+
+use evdev_rs::enums::{int_to_ev_key, EV_KEY};
+
+
+/// Short names accepted in addition to the canonical names.
+/// Aliases never replace the name of another key without its `KEY_` prefix.
+const ALIASES: &[(&str, EV_KEY)] = &[
+    ("esc", EV_KEY::KEY_ESC),
+    ("caps", EV_KEY::KEY_CAPSLOCK),
+    ("ent", EV_KEY::KEY_ENTER),
+    ("ret", EV_KEY::KEY_ENTER),
+    ("return", EV_KEY::KEY_ENTER),
+    ("spc", EV_KEY::KEY_SPACE),
+    ("bspc", EV_KEY::KEY_BACKSPACE),
+    ("del", EV_KEY::KEY_DELETE),
+    ("ins", EV_KEY::KEY_INSERT),
+    ("pgup", EV_KEY::KEY_PAGEUP),
+    ("pgdn", EV_KEY::KEY_PAGEDOWN),
+    ("grv", EV_KEY::KEY_GRAVE),
+    ("min", EV_KEY::KEY_MINUS),
+    ("eql", EV_KEY::KEY_EQUAL),
+    ("lbrc", EV_KEY::KEY_LEFTBRACE),
+    ("rbrc", EV_KEY::KEY_RIGHTBRACE),
+    ("bsls", EV_KEY::KEY_BACKSLASH),
+    ("scln", EV_KEY::KEY_SEMICOLON),
+    ("quot", EV_KEY::KEY_APOSTROPHE),
+    ("comm", EV_KEY::KEY_COMMA),
+    ("slsh", EV_KEY::KEY_SLASH),
+    ("ctrl", EV_KEY::KEY_LEFTCTRL),
+    ("control", EV_KEY::KEY_LEFTCTRL),
+    ("lctl", EV_KEY::KEY_LEFTCTRL),
+    ("lctrl", EV_KEY::KEY_LEFTCTRL),
+    ("rctl", EV_KEY::KEY_RIGHTCTRL),
+    ("rctrl", EV_KEY::KEY_RIGHTCTRL),
+    ("shift", EV_KEY::KEY_LEFTSHIFT),
+    ("lsft", EV_KEY::KEY_LEFTSHIFT),
+    ("lshift", EV_KEY::KEY_LEFTSHIFT),
+    ("rsft", EV_KEY::KEY_RIGHTSHIFT),
+    ("rshift", EV_KEY::KEY_RIGHTSHIFT),
+    ("alt", EV_KEY::KEY_LEFTALT),
+    ("lalt", EV_KEY::KEY_LEFTALT),
+    ("ralt", EV_KEY::KEY_RIGHTALT),
+    ("altgr", EV_KEY::KEY_RIGHTALT),
+    ("meta", EV_KEY::KEY_LEFTMETA),
+    ("super", EV_KEY::KEY_LEFTMETA),
+    ("lmet", EV_KEY::KEY_LEFTMETA),
+    ("lgui", EV_KEY::KEY_LEFTMETA),
+    ("lwin", EV_KEY::KEY_LEFTMETA),
+    ("rmet", EV_KEY::KEY_RIGHTMETA),
+    ("rgui", EV_KEY::KEY_RIGHTMETA),
+    ("rwin", EV_KEY::KEY_RIGHTMETA),
+    ("prnt", EV_KEY::KEY_SYSRQ),
+    ("prtsc", EV_KEY::KEY_SYSRQ),
+    ("slck", EV_KEY::KEY_SCROLLLOCK),
+    ("nlck", EV_KEY::KEY_NUMLOCK),
+    ("app", EV_KEY::KEY_COMPOSE),
+    ("volu", EV_KEY::KEY_VOLUMEUP),
+    ("vold", EV_KEY::KEY_VOLUMEDOWN),
+];
+
+/// Prefix of canonical key names which may be omitted.
+const KEY_PREFIX: &str = "key_";
+
+/// Prefix of QMK key names (eg `KC_A`) which is ignored.
+const QMK_PREFIX: &str = "kc_";
+
+
+/// Error for names which don't match any key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownKeyName {
+    pub name: String,
+    /// Known names similar to `name`, closest first.
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for UnknownKeyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown key name `{}`", self.name)?;
+        match self.suggestions.as_slice() {
+            [] => Ok(()),
+            [suggestion] => write!(f, ", did you mean `{}`?", suggestion),
+            suggestions => write!(f, ", did you mean one of: {}?", suggestions.join(", ")),
+        }
+    }
+}
+
+impl error::Error for UnknownKeyName {}
+
+
+/// Return iterator with every EV_KEY variant
+pub fn all_keys() -> impl Iterator<Item=EV_KEY> {
+    // This is kinda bad but...
+    // For some reason the evdev crate does not provide a method that returns
+    // all variants for `EV_KEY`
+    // EV_KEY events apparently range from 0 to ~750.
+    //
+    // Anyway, so instead copying the definition and goin line by line we can waste a few CPU
+    // cycles here and not repeat the whole thing
+    (0..1000)
+        .filter_map(int_to_ev_key)
+}
+
+/// Canonical name of `key`, eg `KEY_CAPSLOCK`.
+pub fn name(key: EV_KEY) -> String {
+    format!("{:?}", key)
+}
+
+/// Aliases of `key`, not including its canonical name.
+pub fn aliases(key: EV_KEY) -> impl Iterator<Item=&'static str> {
+    ALIASES.iter()
+        .filter(move |(_, alias_key)| *alias_key == key)
+        .map(|(alias, _)| *alias)
+}
+
+/// Look up the key for a canonical name, a name without its `KEY_` prefix or an alias.
+/// The lookup is case-insensitive.
+pub fn parse(name: &str) -> Result<EV_KEY, UnknownKeyName> {
+    let normalized = normalize(name);
+    lookup_table().get(normalized.as_str())
+        .copied()
+        .ok_or_else(|| UnknownKeyName {
+            name: name.to_string(),
+            suggestions: suggest(&normalized),
+        })
+}
+
+/// Lowercase the name and strip prefixes that don't take part in the lookup.
+fn normalize(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    match name.strip_prefix(QMK_PREFIX) {
+        Some(stripped) => stripped.to_string(),
+        None => name,
+    }
+}
+
+/// Map of every accepted (normalized) name to its key
+fn lookup_table() -> &'static HashMap<String, EV_KEY> {
+    static TABLE: OnceLock<HashMap<String, EV_KEY>> = OnceLock::new();
+
+    TABLE.get_or_init(|| {
+        let mut table = HashMap::new();
+        for key in all_keys() {
+            let canonical = name(key).to_lowercase();
+            if let Some(short) = canonical.strip_prefix(KEY_PREFIX) {
+                table.insert(short.to_string(), key);
+            }
+            table.insert(canonical, key);
+        }
+        for (alias, key) in ALIASES {
+            table.entry(alias.to_string()).or_insert(*key);
+        }
+        table
+    })
+}
+
+/// Up to 3 accepted names close to `normalized`, by edit distance.
+fn suggest(normalized: &str) -> Vec<String> {
+    let max_distance = (normalized.len() / 3).clamp(1, 3);
+
+    let mut candidates: Vec<(usize, &String)> = lookup_table().keys()
+        .map(|candidate| (edit_distance(normalized, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+
+    candidates.into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate.clone())
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accepts_canonical_short_and_alias_names() {
+        assert_eq!(parse("KEY_CAPSLOCK"), Ok(EV_KEY::KEY_CAPSLOCK));
+        assert_eq!(parse("capslock"), Ok(EV_KEY::KEY_CAPSLOCK));
+        assert_eq!(parse("Caps"), Ok(EV_KEY::KEY_CAPSLOCK));
+        assert_eq!(parse("lctl"), Ok(EV_KEY::KEY_LEFTCTRL));
+        assert_eq!(parse("KC_BSPC"), Ok(EV_KEY::KEY_BACKSPACE));
+        assert_eq!(parse("btn_right"), Ok(EV_KEY::BTN_RIGHT));
+    }
+
+    #[test]
+    fn test_canonical_names_round_trip() {
+        for key in all_keys() {
+            assert_eq!(parse(&name(key)), Ok(key));
+            if let Some(short) = name(key).strip_prefix("KEY_") {
+                assert_eq!(parse(short), Ok(key), "{}", short);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_names_have_suggestions() {
+        let err = parse("capslok").unwrap_err();
+
+        assert_eq!(err.suggestions.first().map(String::as_str), Some("capslock"));
+        assert!(err.to_string().starts_with("unknown key name `capslok`, did you mean"), "{}", err);
+        assert!(parse("definitely not a key").unwrap_err().suggestions.is_empty());
+    }
+}
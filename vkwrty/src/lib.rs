@@ -1,23 +1,57 @@
 mod epoll;
+mod inotify;
+pub mod config;
+pub mod control;
+pub mod devices;
+pub mod doctor;
+pub mod dry_run;
+pub mod emergency;
+pub mod hotplug;
+pub mod io;
+pub mod keynames;
+pub mod logger;
+pub mod memory;
 pub mod monitor;
+pub mod privileges;
+pub mod recording;
+pub mod reload;
 pub mod virtual_dev;
+pub mod watchdog;
 
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::Receiver;
 use std::time::Duration;
+use std::time::Instant;
 use std::fmt;
 use std::io::Error as IOError;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
 use std::time::SystemTimeError;
 use std::error;
 
 use keywerty::keyboard::Event;
 use keywerty::keyboard::Action;
 use keywerty::keyboard::Keyboard;
+use keywerty::keyboard::Notification;
+use keywerty::mapper::LayerId;
 use evdev_rs::enums::{EV_KEY};
 
+use config::ConfigError;
+use control::{ControlServer, KeyboardStatus, Request, Response, StatusEvent};
+use devices::DeviceInfo;
+use emergency::{ChordDetector, EmergencyAction, EmergencyConf, PassthroughKeyboard};
+use hotplug::{DeviceChange, DeviceWatcher};
+use io::{InputSource, OutputSink};
 use monitor::EventIter;
+use recording::RecordingError;
+use reload::{Reloader, SignalFd};
 use epoll::Epoll;
 use virtual_dev::UInputKeyboard;
+use watchdog::Watchdog;
 
 
 #[derive(Debug)]
@@ -25,6 +59,28 @@ pub enum Error {
     IO(IOError),
     Time(SystemTimeError),
     DeviceInit,
+    Config(ConfigError),
+    NoDevice(String),
+    AmbiguousDevice(String, Vec<PathBuf>),
+    /// Opening the device at the path failed
+    DeviceOpen(PathBuf, IOError),
+    /// Access to the device at the path was denied
+    Permission(PathBuf),
+    /// Grabbing the device at the path failed, eg because another program grabbed it
+    Grab(PathBuf, IOError),
+    /// No user has this name, see `privileges::drop_privileges`
+    UnknownUser(String),
+    Recording(RecordingError),
+}
+
+impl Error {
+    /// Error opening the device at `path`, which is a `Permission` error if access was denied.
+    pub fn device_open(path: &Path, err: IOError) -> Error {
+        match err.kind() {
+            std::io::ErrorKind::PermissionDenied => Error::Permission(path.to_path_buf()),
+            _ => Error::DeviceOpen(path.to_path_buf(), err),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -32,7 +88,18 @@ impl fmt::Display for Error {
         match self {
             Error::IO(io_err) => write!(f, "io err: {}", io_err),
             Error::Time(time_err) => write!(f, "error creating input event: {}", time_err),
-            Error::DeviceInit => write!(f, "Error initializing uinput device")
+            Error::DeviceInit => write!(f, "Error initializing uinput device"),
+            Error::Config(config_err) => write!(f, "invalid configuration: {}", config_err),
+            Error::NoDevice(selector) => write!(f, "no input device matches `{}`", selector),
+            Error::AmbiguousDevice(selector, paths) => {
+                let paths: Vec<String> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(f, "`{}` matches many input devices: {}", selector, paths.join(", "))
+            }
+            Error::DeviceOpen(path, err) => write!(f, "failed to open {}: {}", path.display(), err),
+            Error::Permission(path) => write!(f, "permission denied to access {}, see `vkwrty doctor`", path.display()),
+            Error::Grab(path, err) => write!(f, "failed to grab {}, is another program grabbing it? {}", path.display(), err),
+            Error::UnknownUser(user) => write!(f, "unknown user `{}`", user),
+            Error::Recording(recording_err) => write!(f, "{}", recording_err),
         }
     }
 }
@@ -42,6 +109,10 @@ impl error::Error for Error {
         match self {
             Error::IO(err) => Some(err),
             Error::Time(err) => Some(err),
+            Error::Config(err) => Some(err),
+            Error::DeviceOpen(_, err) => Some(err),
+            Error::Grab(_, err) => Some(err),
+            Error::Recording(err) => Some(err),
             _ => None
         }
     }
@@ -59,51 +130,711 @@ impl From<SystemTimeError> for Error {
     }
 }
 
+impl From<ConfigError> for Error {
+    fn from(config_err: ConfigError) -> Error {
+        Error::Config(config_err)
+    }
+}
+
+impl From<RecordingError> for Error {
+    fn from(recording_err: RecordingError) -> Error {
+        Error::Recording(recording_err)
+    }
+}
+
 
 type Result<T> = std::result::Result<T, Error>;
 
 
-pub struct Runtime {
-    emitter: EventIter,
-    virtual_dev: UInputKeyboard,
-    keyboard: Box<dyn Keyboard<EV_KEY, EV_KEY>>,
-    epoll: Epoll
-}
-
-impl Runtime {
-    pub fn new(emitter: EventIter, virtual_dev: UInputKeyboard, keyboard: impl Keyboard<EV_KEY, EV_KEY> + 'static, poll_period: Duration) -> Result<Self> {
-        let mut epoll = Epoll::new(10, poll_period)?;
-        epoll.monitor_file(&emitter)?;
-        
-        Ok(Self {
-            emitter: emitter,
-            virtual_dev: virtual_dev,
-            keyboard: Box::new(keyboard),
-            epoll: epoll
-        })
-    }
-
-    pub fn run(&mut self) {
-        loop {
-            {
-                if let Err(err) = self.epoll.wait() {
-                    eprintln!("epoll error'd during runtime: {}", err);
-                    continue;
+/// Keyboard a device is attached to, see `Runtime::attach`.
+pub enum Attach<K> {
+    /// The runtime's shared keyboard, fed by every device without a keyboard of its own
+    Shared,
+    /// A new keyboard, owned by the device
+    Own(K),
+}
+
+/// Keyboard run by the `Runtime`.
+pub struct RuntimeKeyboard<K> {
+    pub keyboard: K,
+    /// Device the keyboard was created for, `None` for the shared keyboard
+    pub owner: Option<DeviceInfo>,
+}
+
+/// Decides which keyboard a device plugged while running is attached to, if any.
+pub type AttachFn<K> = Box<dyn FnMut(&DeviceInfo) -> Option<Attach<K>>>;
+
+/// What the runtime did with an event, see `Runtime::set_tracer`.
+pub struct Trace<'a> {
+    /// Device the event was read from, `None` for polls
+    pub device: Option<&'a DeviceInfo>,
+    pub event: Event<EV_KEY>,
+    /// Index of the keyboard fed with the event, the shared keyboard being 0
+    pub keyboard: usize,
+    /// Layer of the keyboard after the event, `None` in passthrough mode
+    pub layer: Option<LayerId>,
+    /// Changes caused by the event, eg the behavior of a key being resolved
+    pub status: &'a [StatusEvent],
+    pub actions: &'a [Action<EV_KEY>],
+}
+
+/// Called with every event fed into the keyboards, see `Runtime::set_tracer`.
+type Tracer = Box<dyn FnMut(&Trace)>;
+
+/// Intercepted device, along with the index of the keyboard it feeds
+struct Input<I> {
+    emitter: I,
+    device: DeviceInfo,
+    keyboard: usize,
+}
+
+struct Hotplug<K> {
+    watcher: DeviceWatcher,
+    attach: AttachFn<K>,
+}
+
+/// Feeds the events of input devices into keyboards and emits the resulting actions
+/// through a single virtual device.
+///
+/// Devices feed either the shared keyboard or a keyboard of their own, devices sharing
+/// a keyboard share its state (eg held modifiers or active layers).
+/// When a device vanishes, the keyboard it fed is reset so that no output is left held,
+/// and likewise every keyboard is reset when the runtime is stopped.
+///
+/// The emergency chord is detected on the devices' events before they reach the keyboards,
+/// see `Runtime::set_emergency`, and it can be queried and commanded through a control socket,
+/// see `Runtime::listen`.
+///
+/// Devices are read through an `InputSource` and actions emitted through an `OutputSink`,
+/// evdev devices and a uinput device by default. See `memory` for in memory implementations.
+pub struct Runtime<K, I = EventIter, O = UInputKeyboard> {
+    inputs: Vec<Input<I>>,
+    /// Devices which vanished, along with the keyboard they fed, to reattach them when they return
+    detached: Vec<(DeviceInfo, usize)>,
+    virtual_dev: O,
+    /// The shared keyboard comes first
+    keyboards: Vec<RuntimeKeyboard<K>>,
+    epoll: Epoll,
+    poll_period: Duration,
+    reloader: Option<Reloader<Vec<RuntimeKeyboard<K>>>>,
+    hotplug: Option<Hotplug<K>>,
+    /// Signals stopping the runtime, see `Runtime::stop_on_signals`
+    stop_signals: Option<SignalFd>,
+    running: bool,
+    emergency_chord: ChordDetector,
+    emergency_action: EmergencyAction,
+    /// Replaces every keyboard while the passthrough mode is on
+    passthrough: Option<PassthroughKeyboard>,
+    watchdog: Option<Watchdog>,
+    control: Option<ControlServer>,
+    /// Status events not yet sent to the subscribed control clients, keyboards push theirs
+    /// through their notification hook
+    status_events: Rc<RefCell<Vec<StatusEvent>>>,
+    tracer: Option<Tracer>,
+}
+
+impl<K, I, O> Runtime<K, I, O>
+where
+    K: Keyboard<EV_KEY, EV_KEY>,
+    I: InputSource,
+    O: OutputSink,
+{
+    pub fn new(virtual_dev: O, keyboard: K, poll_period: Duration) -> Result<Self> {
+        let mut runtime = Self {
+            inputs: Vec::new(),
+            detached: Vec::new(),
+            virtual_dev,
+            keyboards: vec![RuntimeKeyboard { keyboard, owner: None }],
+            epoll: Epoll::new(10)?,
+            poll_period,
+            reloader: None,
+            hotplug: None,
+            stop_signals: None,
+            running: true,
+            emergency_chord: ChordDetector::new(EmergencyConf::default().chord),
+            emergency_action: EmergencyConf::default().action,
+            passthrough: None,
+            watchdog: None,
+            control: None,
+            status_events: Rc::new(RefCell::new(Vec::new())),
+            tracer: None,
+        };
+        runtime.hook_keyboard(0);
+        Ok(runtime)
+    }
+
+    /// Turn the notifications of the keyboard at `index` into status events.
+    fn hook_keyboard(&mut self, index: usize) {
+        let status_events = Rc::clone(&self.status_events);
+        self.keyboards[index].keyboard.set_notification_hook(Box::new(move |notification| {
+            let event = match notification {
+                Notification::LayerChanged(layer) => StatusEvent::LayerChanged { keyboard: index, layer },
+                Notification::CapsWord(active) => StatusEvent::CapsWord { keyboard: index, active },
+                Notification::NumWord(layer) => StatusEvent::NumWord { keyboard: index, layer },
+                Notification::BehaviorPending(key) => StatusEvent::BehaviorPending { keyboard: index, key: keynames::name(key) },
+                Notification::BehaviorResolved(key, behavior) => StatusEvent::BehaviorResolved {
+                    keyboard: index,
+                    key: keynames::name(key),
+                    behavior: behavior.map(|behavior| control::behavior_name(behavior).to_string()),
+                },
+            };
+            status_events.borrow_mut().push(event);
+        }));
+    }
+
+    fn publish(&self, event: StatusEvent) {
+        self.status_events.borrow_mut().push(event);
+    }
+
+    /// Intercept the events of `device` and feed them into the keyboard given by `attach`.
+    pub fn attach(&mut self, device: DeviceInfo, attach: Attach<K>) -> Result<()> {
+        let emitter = I::open(&device)?;
+        self.attach_source(emitter, device, attach)
+    }
+
+    /// Feed the events of an already opened `source` into the keyboard given by `attach`,
+    /// `device` describes the source.
+    pub fn attach_source(&mut self, source: I, device: DeviceInfo, attach: Attach<K>) -> Result<()> {
+        let keyboard = match attach {
+            Attach::Shared => 0,
+            Attach::Own(keyboard) => {
+                self.keyboards.push(RuntimeKeyboard { keyboard, owner: Some(device.clone()) });
+                self.hook_keyboard(self.keyboards.len() - 1);
+                self.keyboards.len() - 1
+            }
+        };
+        self.add_input(source, device, keyboard)
+    }
+
+    fn attach_to_keyboard(&mut self, device: DeviceInfo, keyboard: usize) -> Result<()> {
+        let emitter = I::open(&device)?;
+        self.add_input(emitter, device, keyboard)
+    }
+
+    fn add_input(&mut self, emitter: I, device: DeviceInfo, keyboard: usize) -> Result<()> {
+        self.epoll.monitor_file(&emitter)?;
+
+        log::info!("intercepting device: {}", device);
+        self.publish(StatusEvent::DeviceAttached { device: device.name.clone(), path: device.path.clone() });
+        self.inputs.push(Input { emitter, device, keyboard });
+        self.update_watchdog();
+        Ok(())
+    }
+
+    /// Stop intercepting the device of the input at `position`, resetting the keyboard it fed.
+    fn detach(&mut self, position: usize) {
+        let input = self.inputs.remove(position);
+        self.update_watchdog();
+        if let Err(err) = self.epoll.unmonitor_file(&input.emitter) {
+            log::warn!("failed to stop monitoring device: {}", err);
+        }
+        log::info!("released device: {}", input.device);
+        self.publish(StatusEvent::DeviceDetached { device: input.device.name.clone(), path: input.device.path.clone() });
+
+        // the keys held on the device won't be released
+        self.emergency_chord.clear();
+        let mut actions = self.keyboards[input.keyboard].keyboard.reset();
+        if let Some(passthrough) = self.passthrough.as_mut() {
+            actions.extend(passthrough.reset());
+        }
+        emit(&mut self.virtual_dev, &actions);
+        self.detached.push((input.device, input.keyboard));
+    }
+
+    /// Watch for devices being plugged and unplugged.
+    ///
+    /// Devices which vanished are reattached to their keyboard when they return,
+    /// other new devices are attached as decided by `attach`.
+    pub fn watch_devices(
+        &mut self,
+        watcher: DeviceWatcher,
+        attach: impl FnMut(&DeviceInfo) -> Option<Attach<K>> + 'static,
+    ) -> Result<()> {
+        self.epoll.monitor_file(&watcher)?;
+        self.hotplug = Some(Hotplug { watcher, attach: Box::new(attach) });
+        Ok(())
+    }
+
+    /// Reload the keyboards configuration through `reloader` whenever a reload is requested.
+    /// The shared keyboard comes first, followed by the keyboards owned by devices.
+    pub fn set_reloader(&mut self, reloader: Reloader<Vec<RuntimeKeyboard<K>>>) -> Result<()> {
+        for fd in reloader.fds().iter() {
+            self.epoll.monitor_file(fd)?;
+        }
+        self.reloader = Some(reloader);
+        Ok(())
+    }
+
+    /// Stop the runtime when `SIGINT` or `SIGTERM` is received.
+    pub fn stop_on_signals(&mut self) -> Result<()> {
+        let signals = SignalFd::new(&[libc::SIGINT, libc::SIGTERM])?;
+        self.epoll.monitor_file(&signals)?;
+        self.stop_signals = Some(signals);
+        Ok(())
+    }
+
+    /// Set the emergency chord and what it does, the chord is Backspace, Esc and Enter
+    /// stopping the runtime unless set otherwise.
+    pub fn set_emergency(&mut self, conf: EmergencyConf) {
+        self.emergency_chord = ChordDetector::new(conf.chord);
+        self.emergency_action = conf.action;
+    }
+
+    /// Call `tracer` with every event fed into the keyboards, and with the polls which
+    /// changed something, eg to show what a keymap does without emitting its actions.
+    pub fn set_tracer(&mut self, tracer: impl FnMut(&Trace) + 'static) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    /// Answer the requests sent to the control socket at `path`, see `control`.
+    pub fn listen(&mut self, path: &Path) -> Result<()> {
+        let control = ControlServer::bind(path)?;
+        self.epoll.monitor_file(&control)?;
+        log::info!("listening for control requests on {}", path.display());
+        self.control = Some(control);
+        Ok(())
+    }
+
+    /// Hand the devices back to the OS if the runtime loop stops making progress for `timeout`,
+    /// the runtime then stops as soon as it's making progress again.
+    ///
+    /// The watchdog runs in a thread of its own, hence it should be started after `stop_on_signals`.
+    pub fn start_watchdog(&mut self, timeout: Duration) -> Result<()> {
+        self.watchdog = Some(Watchdog::start(timeout)?);
+        self.update_watchdog();
+        Ok(())
+    }
+
+    /// Make `run` return once the current iteration is over.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Pause or resume remapping, keys are sent as they're typed while paused.
+    /// Keyboards are reset before pausing, and likewise the passthrough keyboard before resuming.
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.is_paused() {
+            return;
+        }
+        self.publish(StatusEvent::Paused { paused });
+        let actions = match self.passthrough.take() {
+            Some(mut passthrough) => passthrough.reset(),
+            None => {
+                self.passthrough = Some(PassthroughKeyboard::default());
+                self.keyboards.iter_mut()
+                    .flat_map(|keyboard| keyboard.keyboard.reset())
+                    .collect()
+            }
+        };
+        emit(&mut self.virtual_dev, &actions);
+    }
+
+    /// Whether remapping is paused, by `set_paused` or the emergency chord.
+    pub fn is_paused(&self) -> bool {
+        self.passthrough.is_some()
+    }
+
+    /// Active layer of every keyboard, the shared keyboard coming first.
+    pub fn keyboard_status(&self) -> Vec<KeyboardStatus> {
+        self.keyboards.iter()
+            .enumerate()
+            .map(|(index, keyboard)| KeyboardStatus {
+                keyboard: index,
+                device: keyboard.owner.as_ref().map(|device| device.name.clone()),
+                layer: keyboard.keyboard.active_layer(),
+            })
+            .collect()
+    }
+
+    /// Tap each chord in turn, bypassing the keyboards: the keys of a chord are pressed
+    /// in order, then released in reverse order.
+    pub fn type_keys(&mut self, chords: &[Vec<EV_KEY>]) -> Result<()> {
+        for chord in chords {
+            let mut actions: Vec<Action<EV_KEY>> = chord.iter().map(|key| Action::SendCode(*key)).collect();
+            actions.extend(chord.iter().rev().map(|key| Action::Stop(*key)));
+            self.virtual_dev.emit_events(&actions)?;
+        }
+        Ok(())
+    }
+
+    /// Run until stopped, then shut down.
+    pub fn run(&mut self) -> Result<()> {
+        while self.running {
+            if let Err(err) = self.run_once() {
+                log::error!("epoll error'd during runtime: {}", err);
+            }
+        }
+        self.shutdown()
+    }
+
+    /// Run a single iteration of the runtime loop: wait for input events or the next keyboard
+    /// deadline, at most for the poll period, then poll the keyboards and feed them the events.
+    pub fn run_once(&mut self) -> Result<()> {
+        let ready_fds: Vec<RawFd> = {
+            let timeout = self.get_poll_timeout();
+            self.epoll.wait_timeout(timeout)?.collect()
+        };
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            if watchdog.has_fired() {
+                log::error!("devices were released by the watchdog, stopping");
+                self.running = false;
+                return Ok(());
+            }
+            watchdog.beat();
+        }
+        self.handle_stop_signals(&ready_fds);
+        self.handle_reload(&ready_fds);
+        self.handle_hotplug(&ready_fds);
+        self.handle_control(&ready_fds);
+        self.emit_events(&ready_fds);
+        self.send_status_events();
+        Ok(())
+    }
+
+    /// Reset every keyboard, so that no output is left held, and release every device.
+    ///
+    /// Every device is released even if emitting the releases fails, the first error is returned.
+    pub fn shutdown(&mut self) -> Result<()> {
+        self.running = false;
+        // removes the socket file
+        self.control = None;
+        let mut result = Ok(());
+        for keyboard in self.keyboards.iter_mut() {
+            let actions = keyboard.keyboard.reset();
+            result = result.and(self.virtual_dev.emit_events(&actions));
+        }
+        if let Some(mut passthrough) = self.passthrough.take() {
+            result = result.and(self.virtual_dev.emit_events(&passthrough.reset()));
+        }
+
+        // devices released by the watchdog are handed back to the OS already
+        let released = self.watchdog.as_ref().is_some_and(|watchdog| watchdog.has_fired());
+        let inputs: Vec<Input<I>> = self.inputs.drain(..).collect();
+        self.update_watchdog();
+        for mut input in inputs {
+            if let Err(err) = self.epoll.unmonitor_file(&input.emitter) {
+                log::warn!("failed to stop monitoring device: {}", err);
+            }
+            if released {
+                continue;
+            }
+            if let Err(err) = input.emitter.release() {
+                log::error!("failed to release device {}: {}", input.device, err);
+                result = result.and(Err(err.into()));
+            } else {
+                log::info!("released device: {}", input.device);
+            }
+        }
+        result
+    }
+
+    /// Let the watchdog know which devices are grabbed.
+    fn update_watchdog(&self) {
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.set_grabbed(self.inputs.iter().map(|input| input.emitter.as_raw_fd()).collect());
+        }
+    }
+
+    fn handle_stop_signals(&mut self, ready_fds: &[RawFd]) {
+        if let Some(signals) = self.stop_signals.as_mut() {
+            if ready_fds.contains(&signals.as_raw_fd()) && signals.drain() {
+                log::info!("stopping");
+                self.running = false;
+            }
+        }
+    }
+
+    /// Reload the configuration if any of the reloader's files is ready.
+    fn handle_reload(&mut self, ready_fds: &[RawFd]) {
+        if let Some(reloader) = self.reloader.as_mut() {
+            if reloader.fds().iter().any(|fd| ready_fds.contains(fd)) && reloader.handle_requests(&mut self.keyboards) {
+                self.publish(StatusEvent::ConfigReloaded);
+            }
+        }
+    }
+
+    /// Attach and detach the devices which were plugged or unplugged.
+    fn handle_hotplug(&mut self, ready_fds: &[RawFd]) {
+        let changes = match self.hotplug.as_mut() {
+            Some(hotplug) if ready_fds.contains(&hotplug.watcher.as_raw_fd()) => hotplug.watcher.drain(),
+            _ => return,
+        };
+
+        for change in changes {
+            match change {
+                DeviceChange::Added(path) => self.handle_new_device(&path),
+                DeviceChange::Removed(path) => {
+                    if let Some(position) = self.inputs.iter().position(|input| input.device.path == path) {
+                        self.detach(position);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accept the new control clients and answer the requests of the ready ones.
+    fn handle_control(&mut self, ready_fds: &[RawFd]) {
+        let control = match self.control.as_mut() {
+            Some(control) => control,
+            None => return,
+        };
+        if ready_fds.contains(&control.as_raw_fd()) {
+            for fd in control.accept() {
+                if let Err(err) = self.epoll.monitor_file(&fd) {
+                    log::warn!("failed to monitor control client: {}", err);
+                }
+            }
+        }
+
+        let ready_clients: Vec<RawFd> = control.clients().into_iter()
+            .filter(|fd| ready_fds.contains(fd))
+            .collect();
+        for fd in ready_clients {
+            let requests = match self.control.as_mut() {
+                Some(control) => control.read_requests(fd),
+                None => return,
+            };
+            for request in requests {
+                let response = match request {
+                    Ok(request) => self.handle_control_request(fd, request),
+                    Err(err) => Response::error(err),
+                };
+                if let Some(control) = self.control.as_mut() {
+                    control.respond(fd, &response);
+                }
+            }
+        }
+    }
+
+    /// Send the pending status events to the subscribed control clients.
+    fn send_status_events(&mut self) {
+        let events: Vec<StatusEvent> = self.status_events.borrow_mut().drain(..).collect();
+        if let Some(control) = self.control.as_mut() {
+            for event in events.iter() {
+                control.broadcast(event);
+            }
+        }
+    }
+
+    /// Carry out the `request` of the control client `fd`.
+    fn handle_control_request(&mut self, fd: RawFd, request: Request) -> Response {
+        log::debug!("control request: {:?}", request);
+        match request {
+            Request::Status => Response {
+                paused: Some(self.is_paused()),
+                keyboards: self.keyboard_status(),
+                ..Response::ok()
+            },
+            Request::SetLayer { layer, keyboard } => {
+                let keyboards: Vec<&mut RuntimeKeyboard<K>> = match keyboard {
+                    Some(index) => match self.keyboards.get_mut(index) {
+                        Some(keyboard) => vec![keyboard],
+                        None => return Response::error(format!("no keyboard at index {}", index)),
+                    },
+                    None => self.keyboards.iter_mut().collect(),
+                };
+                let mut has_layers = false;
+                for keyboard in keyboards {
+                    has_layers |= keyboard.keyboard.set_default_layer(layer);
+                }
+                if has_layers {
+                    log::info!("default layer set to {}", layer);
+                    Response::ok()
+                } else {
+                    Response::error("the keyboard has no layers")
+                }
+            }
+            Request::Pause => {
+                log::info!("remapping paused");
+                self.set_paused(true);
+                Response::ok()
+            }
+            Request::Resume => {
+                log::info!("remapping resumed");
+                self.set_paused(false);
+                Response::ok()
+            }
+            Request::Reload => match self.reloader.as_mut() {
+                Some(reloader) => match reloader.reload(&mut self.keyboards) {
+                    Ok(()) => {
+                        self.publish(StatusEvent::ConfigReloaded);
+                        Response::ok()
+                    }
+                    Err(err) => Response::error(err),
+                },
+                None => Response::error("no configuration file to reload"),
+            },
+            Request::Type { keys } => {
+                let chords: std::result::Result<Vec<Vec<EV_KEY>>, _> = keys.iter()
+                    .map(|chord| control::parse_chord(chord))
+                    .collect();
+                match chords {
+                    Ok(chords) => match self.type_keys(&chords) {
+                        Ok(()) => Response::ok(),
+                        Err(err) => Response::error(err),
+                    },
+                    Err(err) => Response::error(err),
+                }
+            }
+            Request::Subscribe => {
+                if let Some(control) = self.control.as_mut() {
+                    control.subscribe(fd);
+                }
+                Response::ok()
+            }
+        }
+    }
+
+    fn handle_new_device(&mut self, path: &Path) {
+        if self.inputs.iter().any(|input| input.device.path == path) {
+            return;
+        }
+        // the device can't be read until udev sets its permissions, it's added again by then
+        let device = match DeviceInfo::read(path) {
+            Ok(device) => device,
+            Err(_) => return,
+        };
+        // the passthrough devices mirror the intercepted ones, intercepting them would loop
+        if virtual_dev::is_passthrough_device(&device) {
+            return;
+        }
+
+        let result = match self.detached.iter().position(|(detached, _)| detached.is_same_device(&device)) {
+            Some(position) => {
+                let keyboard = self.detached[position].1;
+                self.attach_to_keyboard(device.clone(), keyboard)
+                    .map(|_| { self.detached.remove(position); })
+            }
+            None => {
+                let attach = self.hotplug.as_mut().and_then(|hotplug| (hotplug.attach)(&device));
+                match attach {
+                    Some(attach) => self.attach(device.clone(), attach),
+                    None => return,
                 }
             }
-            self.emit_events();
+        };
+        if let Err(err) = result {
+            log::error!("failed to intercept device {}: {}", device, err);
         }
     }
 
-    fn emit_events(&mut self) {
+    /// Return how long to wait for input events before polling the keyboards.
+    /// Waits for the poll period unless a keyboard has an earlier deadline.
+    fn get_poll_timeout(&self) -> Duration {
+        self.keyboards.iter()
+            .filter_map(|keyboard| keyboard.keyboard.next_deadline())
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .map(|timeout| timeout.min(self.poll_period))
+            .unwrap_or(self.poll_period)
+    }
+
+    /// Feed the events of the ready input devices into their keyboard,
+    /// detaching the devices which were disconnected.
+    fn emit_events(&mut self, ready_fds: &[RawFd]) {
         // always poll first because there might be element in the device
         // file but the iterator has no relevant events for the keyboard
-        let actions = self.keyboard.transition(Event::Poll);
-        self.virtual_dev.emit_events(&actions).unwrap();
+        if self.passthrough.is_none() {
+            for keyboard in 0..self.keyboards.len() {
+                let status_start = self.status_events.borrow().len();
+                let actions = self.keyboards[keyboard].keyboard.transition(Event::Poll);
+                emit(&mut self.virtual_dev, &actions);
+                self.trace_event(None, keyboard, Event::Poll, status_start, &actions);
+            }
+        }
+
+        // the events are read one at a time, so that the events passed through by the inputs
+        // stay in order with the actions resulting from the key events
+        let ready: Vec<usize> = self.inputs.iter()
+            .enumerate()
+            .filter(|(_, input)| ready_fds.contains(&input.emitter.as_raw_fd()))
+            .map(|(position, _)| position)
+            .collect();
+        for position in ready {
+            while self.running {
+                let event = match self.inputs[position].emitter.next() {
+                    Some(event) => event,
+                    None => break,
+                };
+                self.handle_input_event(position, event);
+            }
+        }
+
+        while let Some(position) = self.inputs.iter().position(|input| input.emitter.is_disconnected()) {
+            self.detach(position);
+        }
+    }
+
+    /// Feed `event`, read from the input at `position`, into its keyboard.
+    fn handle_input_event(&mut self, position: usize, event: Event<EV_KEY>) {
+        // the event completing the chord is swallowed
+        if self.emergency_chord.feed(&event) {
+            self.handle_emergency();
+            return;
+        }
+        let keyboard = self.inputs[position].keyboard;
+        let status_start = self.status_events.borrow().len();
+        let actions = match self.passthrough.as_mut() {
+            Some(passthrough) => passthrough.transition(event),
+            None => self.keyboards[keyboard].keyboard.transition(event),
+        };
+        emit(&mut self.virtual_dev, &actions);
+        self.trace_event(Some(position), keyboard, event, status_start, &actions);
+    }
 
-        for event in &mut self.emitter {
-            let actions = self.keyboard.transition(event);
-            self.virtual_dev.emit_events(&actions).unwrap();
+    /// Report the `event` fed into `keyboard` to the tracer, along with the status events
+    /// published since `status_start`. Polls are only reported when they changed something.
+    fn trace_event(&mut self, input: Option<usize>, keyboard: usize, event: Event<EV_KEY>, status_start: usize, actions: &[Action<EV_KEY>]) {
+        let tracer = match self.tracer.as_mut() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let status_events = self.status_events.borrow();
+        let status = &status_events[status_start..];
+        if event == Event::Poll && status.is_empty() && actions.is_empty() {
+            return;
+        }
+        let layer = match self.passthrough {
+            Some(_) => None,
+            None => self.keyboards[keyboard].keyboard.active_layer(),
+        };
+        tracer(&Trace {
+            device: input.map(|position| &self.inputs[position].device),
+            event,
+            keyboard,
+            layer,
+            status,
+            actions,
+        });
+    }
+
+    /// Stop the runtime or toggle the passthrough mode, as configured.
+    fn handle_emergency(&mut self) {
+        match self.emergency_action {
+            EmergencyAction::Exit => {
+                log::warn!("emergency chord pressed, stopping");
+                self.running = false;
+            }
+            EmergencyAction::Passthrough => {
+                if self.is_paused() {
+                    log::warn!("emergency chord pressed, leaving passthrough mode");
+                } else {
+                    log::warn!("emergency chord pressed, passing keys through");
+                }
+                self.set_paused(!self.is_paused());
+            }
         }
     }
 }
+
+/// Emit `actions` through `output`, logging failures.
+/// The actions are dropped when emitting them fails, rather than stopping the runtime.
+fn emit<O: OutputSink>(output: &mut O, actions: &[Action<EV_KEY>]) {
+    if let Err(err) = output.emit_events(actions) {
+        log::error!("failed to emit {:?}: {}", actions, err);
+    }
+}
@@ -0,0 +1,66 @@
+//! Logger printing the records of the `log` facade to stderr.
+//!
+//! vkwrty and keywerty log through the facade, the level is set from the command line.
+
+use std::io::Write;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(std::io::stderr(), "{:<5} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Print the records up to `level` to stderr.
+/// Only the first call sets the logger, later calls only change the level.
+pub fn init(level: LevelFilter) {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Level for the `-v` and `-q` flags, each `-v` shows more records and each `-q` fewer,
+/// starting from `Info`.
+pub fn level_for(verbose: u64, quiet: u64) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let level = (3 + verbose as i64 - quiet as i64).clamp(0, LEVELS.len() as i64 - 1);
+    LEVELS[level as usize]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_for_verbosity_flags() {
+        assert_eq!(level_for(0, 0), LevelFilter::Info);
+        assert_eq!(level_for(1, 0), LevelFilter::Debug);
+        assert_eq!(level_for(5, 0), LevelFilter::Trace);
+        assert_eq!(level_for(0, 2), LevelFilter::Error);
+        assert_eq!(level_for(0, 5), LevelFilter::Off);
+        assert_eq!(level_for(1, 1), LevelFilter::Info);
+    }
+}
@@ -1,47 +1,368 @@
+use vkwrty::Attach;
 use vkwrty::Runtime;
+use vkwrty::RuntimeKeyboard;
 use vkwrty::Error;
-use vkwrty::monitor::EventIter;
+use vkwrty::config::Config;
+use vkwrty::control;
+use vkwrty::control::Request;
+use vkwrty::devices;
+use vkwrty::doctor;
+use vkwrty::dry_run;
+use vkwrty::dry_run::{DiscardOutput, UngrabbedDevice};
+use vkwrty::devices::{DeviceInfo, DeviceSelector};
+use vkwrty::hotplug::DeviceWatcher;
+use vkwrty::io::{InputSource, OutputSink};
+use vkwrty::keynames;
+use vkwrty::logger;
+use vkwrty::privileges;
+use vkwrty::recording;
+use vkwrty::recording::Recording;
+use vkwrty::reload::Reloader;
 use vkwrty::virtual_dev::UInputKeyboard;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
 use std::time::Duration;
-use std::fs;
-use std::ffi::CString;
-use std::os::unix::io::FromRawFd;
+use std::time::Instant;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 
 use keywerty::mapper::MapOrEchoMapper;
 use keywerty::keyboard::SMKeyboard;
 use keywerty::keyboard::SMKeyboardSettings;
+use keywerty::keyboard::SimulatedClock;
 use keywerty::keyboard::Action;
 use keywerty::keyboard::Event;
 use keywerty::keys;
 use keywerty::keyboard::Keyboard;
 use clap::App;
 use clap::Arg;
+use clap::ArgMatches;
+use clap::SubCommand;
 use evdev_rs::enums::EV_KEY;
+
+/// Keyboard built from a keymap
+type KeymapKeyboard = SMKeyboard<EV_KEY, EV_KEY, MapOrEchoMapper<EV_KEY>>;
+
+/// How often keyboards are polled when no input event is read
+const POLL_PERIOD: Duration = Duration::from_millis(100);
+
+/// How long the runtime may go without making progress before the devices are released
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn main() {
 
     let matches = App::new("Virtual Keyboard")
-        .arg(Arg::with_name("event source")
-             .required(true)
-             .value_name("EV_FILE")
-             .help("Linux input file from which events should be listened")
+        .arg(Arg::with_name("device")
+             .value_name("DEVICE")
+             .help("Input devices to intercept: an event file (eg /dev/input/event3 or a /dev/input/by-id link), \
+                    a vendor:product id or a name substring. Keyboards are chosen interactively if omitted")
+             .takes_value(true)
+             .multiple(true))
+        .arg(Arg::with_name("config")
+             .short("c")
+             .long("config")
+             .value_name("FILE")
+             .help("Keymap configuration file (TOML), the built-in keymap is used if omitted")
+             .takes_value(true)
+             .global(true))
+        .arg(Arg::with_name("verbose")
+             .short("v")
+             .long("verbose")
+             .multiple(true)
+             .help("Logs more details, repeat for more (eg -vv to log every event)"))
+        .arg(Arg::with_name("quiet")
+             .short("q")
+             .long("quiet")
+             .multiple(true)
+             .conflicts_with("verbose")
+             .help("Logs only warnings and errors, repeat for errors only"))
+        .arg(Arg::with_name("user")
+             .short("u")
+             .long("user")
+             .value_name("USER")
+             .help("Drops root privileges to USER once the devices are open")
              .takes_value(true))
+        .arg(Arg::with_name("dry-run")
+             .short("n")
+             .long("dry-run")
+             .help("Prints what the keymap does with the events of the devices instead of intercepting them"))
+        .arg(Arg::with_name("socket")
+             .short("s")
+             .long("socket")
+             .value_name("PATH")
+             .help("Control socket path, /run/user/<uid>/vkwrty.sock (or /tmp/vkwrty-<uid>.sock) by default")
+             .takes_value(true)
+             .global(true))
+        .subcommand(SubCommand::with_name("keys")
+             .about("Lists key names and their aliases, or looks up a key name")
+             .arg(Arg::with_name("name")
+                  .value_name("NAME")
+                  .help("Key name to look up")))
+        .subcommand(SubCommand::with_name("list-devices")
+             .about("Lists input devices and whether they are keyboards"))
+        .subcommand(SubCommand::with_name("doctor")
+             .about("Checks the permissions needed to run without root")
+             .arg(Arg::with_name("user")
+                  .short("u")
+                  .long("user")
+                  .value_name("USER")
+                  .help("Also checks what USER, given to --user, can do once root privileges are dropped"))
+             .arg(Arg::with_name("device")
+                  .value_name("DEVICE")
+                  .help("Input devices to check, every keyboard if omitted")
+                  .multiple(true)))
+        .subcommand(SubCommand::with_name("record")
+             .about("Records the events of an input device, without intercepting them, until interrupted")
+             .arg(Arg::with_name("device")
+                  .value_name("DEVICE")
+                  .help("Input device to record")
+                  .required(true))
+             .arg(Arg::with_name("output")
+                  .short("o")
+                  .long("output")
+                  .value_name("FILE")
+                  .help("File to write the recording to, standard output if omitted")
+                  .takes_value(true)))
+        .subcommand(SubCommand::with_name("replay")
+             .about("Feeds a recording through the keymap and prints the resulting actions")
+             .arg(Arg::with_name("recording")
+                  .value_name("FILE")
+                  .help("Recording made by `vkwrty record` or evemu-record")
+                  .required(true)))
+        .subcommand(SubCommand::with_name("ctl")
+             .about("Queries or commands a running vkwrty through its control socket")
+             .subcommand(SubCommand::with_name("status")
+                  .about("Prints the active layer of every keyboard and whether remapping is paused"))
+             .subcommand(SubCommand::with_name("set-layer")
+                  .about("Sets the default layer, of every keyboard unless one is given")
+                  .arg(Arg::with_name("layer")
+                       .value_name("LAYER")
+                       .required(true))
+                  .arg(Arg::with_name("keyboard")
+                       .short("k")
+                       .long("keyboard")
+                       .value_name("INDEX")
+                       .help("Keyboard index as listed by `status`, 0 being the shared keyboard")
+                       .takes_value(true)))
+             .subcommand(SubCommand::with_name("pause")
+                  .about("Sends keys as they're typed until resumed"))
+             .subcommand(SubCommand::with_name("resume")
+                  .about("Resumes remapping"))
+             .subcommand(SubCommand::with_name("reload")
+                  .about("Reloads the configuration file"))
+             .subcommand(SubCommand::with_name("subscribe")
+                  .about("Prints a JSON line whenever the state of vkwrty changes (eg the layer), for status bars"))
+             .subcommand(SubCommand::with_name("type")
+                  .about("Types keys, keys pressed together are joined with + (eg leftctrl+c)")
+                  .arg(Arg::with_name("keys")
+                       .value_name("KEYS")
+                       .required(true)
+                       .multiple(true))))
         .get_matches();
 
-    let ev_file = matches.value_of("event source").unwrap();
-    let ev_file = open_dev(&ev_file);
+    logger::init(logger::level_for(matches.occurrences_of("verbose"), matches.occurrences_of("quiet")));
+
+    if let Some(keys_matches) = matches.subcommand_matches("keys") {
+        list_keys(keys_matches);
+        return;
+    }
+    if matches.subcommand_matches("list-devices").is_some() {
+        list_devices();
+        return;
+    }
+    if let Some(doctor_matches) = matches.subcommand_matches("doctor") {
+        doctor(doctor_matches);
+        return;
+    }
+    if let Some(record_matches) = matches.subcommand_matches("record") {
+        record(record_matches);
+        return;
+    }
+    if let Some(replay_matches) = matches.subcommand_matches("replay") {
+        replay(replay_matches);
+        return;
+    }
+    if let Some(ctl_matches) = matches.subcommand_matches("ctl") {
+        ctl(ctl_matches);
+        return;
+    }
+
+    let config = matches.value_of("config").map(|path| or_exit(Config::load(Path::new(path))));
+    // the configuration is shared with the hotplug handler, and replaced on reloads
+    let config = Rc::new(RefCell::new(config));
 
-    let event_iter = EventIter::new(ev_file).unwrap();
+    let selectors: Vec<DeviceSelector> = matches.values_of("device")
+        .map(|selectors| selectors.map(|selector| selector.parse().unwrap()).collect())
+        .unwrap_or_default();
+
+    let selected = or_exit(select_devices(&selectors, config.borrow().as_ref()));
+
+    let shared_keyboard = match config.borrow().as_ref() {
+        Some(config) => config.keymap.build_keyboard(),
+        None => SMKeyboard::new(0, build_mapper(), SMKeyboardSettings::default()),
+    };
+    if matches.is_present("dry-run") {
+        let mut runtime: Runtime<_, UngrabbedDevice, _> = or_exit(Runtime::new(DiscardOutput, shared_keyboard, POLL_PERIOD));
+        runtime.set_tracer(|trace| println!("{}", dry_run::describe_trace(trace)));
+        run(runtime, &matches, config, selectors, selected);
+    } else {
+        let virtual_dev = or_exit(UInputKeyboard::new("Virtual keyboard"));
+        let runtime: Runtime<_> = or_exit(Runtime::new(virtual_dev, shared_keyboard, POLL_PERIOD));
+        run(runtime, &matches, config, selectors, selected);
+    }
+}
 
-    let virtual_dev = UInputKeyboard::new(&"Virtual keyboard").unwrap();
+/// Intercept the `selected` devices, and the devices matching `selectors` once plugged, until stopped.
+fn run<I, O>(
+    mut runtime: Runtime<KeymapKeyboard, I, O>,
+    matches: &ArgMatches,
+    config: Rc<RefCell<Option<Config>>>,
+    selectors: Vec<DeviceSelector>,
+    selected: Vec<DeviceInfo>,
+)
+where
+    I: InputSource,
+    O: OutputSink,
+{
+    if let Some(config) = config.borrow().as_ref() {
+        runtime.set_emergency(config.emergency.clone());
+    }
+
+    for device in selected {
+        let attach = attach_for(config.borrow().as_ref(), &device);
+        or_exit(runtime.attach(device, attach));
+    }
+
+    // devices plugged while running are intercepted if they match the selected devices
+    let watcher = or_exit(DeviceWatcher::new(Path::new(devices::INPUT_DIR))
+        .map_err(|err| format!("failed to watch {} for new devices: {}", devices::INPUT_DIR, err)));
+    let hotplug_config = Rc::clone(&config);
+    or_exit(runtime.watch_devices(watcher, move |device| {
+        let config = hotplug_config.borrow();
+        let is_selected = if selectors.is_empty() {
+            device.is_keyboard && config.as_ref().is_some_and(|config| config.device_keymap(device).is_some())
+        } else {
+            selectors.iter().any(|selector| is_selected_by(selector, device))
+        };
+        if is_selected {
+            Some(attach_for(config.as_ref(), device))
+        } else {
+            None
+        }
+    }));
+
+    // keymaps loaded from a file are reloaded on SIGHUP or when the file changes
+    if let Some(config_path) = matches.value_of("config") {
+        let path = PathBuf::from(config_path);
+        let reloader = Reloader::new(Path::new(config_path), move |keyboards: &mut Vec<RuntimeKeyboard<KeymapKeyboard>>| {
+            let new_config = Config::load(&path)?;
+            for keyboard in keyboards.iter_mut() {
+                let keymap = match &keyboard.owner {
+                    Some(device) => new_config.keymap_for(device),
+                    None => &new_config.keymap,
+                };
+                keyboard.keyboard.reconfigure(keymap.build_keyboard());
+            }
+            *config.borrow_mut() = Some(new_config);
+            log::info!("reloaded configuration from {}", path.display());
+            Ok(())
+        }).map_err(|err| format!("failed to watch {} for changes: {}", config_path, err));
+        or_exit(runtime.set_reloader(or_exit(reloader)));
+    }
+
+    // held keys are released and devices handed back to the OS on SIGINT and SIGTERM
+    or_exit(runtime.stop_on_signals());
+    // every device is open by now, as well as the uinput device
+    if let Some(user) = matches.value_of("user") {
+        or_exit(privileges::drop_privileges(user));
+        // the devices open so far keep working, those plugged later on may not
+        for check in doctor::check_user(user).into_iter().filter(|check| !check.passed) {
+            log::warn!("{}", check.hint.unwrap_or_default().replace(":\n", ": "));
+        }
+    }
+    // the socket belongs to the user vkwrty runs as, it's the only one allowed to use it
+    if !matches.is_present("dry-run") {
+        or_exit(runtime.listen(&socket_path(matches)));
+    }
+    // devices are handed back to the OS if the runtime hangs
+    or_exit(runtime.start_watchdog(WATCHDOG_TIMEOUT));
+    if let Err(err) = runtime.run() {
+        log::error!("failed to shut down cleanly: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Return the value of `result`, or log its error and exit.
+fn or_exit<T, E: fmt::Display>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|err| {
+        log::error!("{}", err);
+        process::exit(1);
+    })
+}
+
+fn socket_path(matches: &ArgMatches) -> PathBuf {
+    matches.value_of("socket").map(PathBuf::from).unwrap_or_else(control::default_socket_path)
+}
 
-    let settings = SMKeyboardSettings::default();
-    let mapper = build_mapper();
-    let keyboard = SMKeyboard::new(0, mapper, settings);
+/// Resolve the devices to intercept on startup.
+///
+/// Devices given on the command line are always intercepted, those which aren't plugged yet
+/// are waited for. Otherwise, when the configuration has device keymaps, every keyboard matching
+/// one of them is intercepted, leaving the others untouched, and as a last resort keyboards
+/// are chosen interactively.
+fn select_devices(selectors: &[DeviceSelector], config: Option<&Config>) -> Result<Vec<DeviceInfo>, Error> {
+    let mut devices: Vec<DeviceInfo> = match config {
+        _ if !selectors.is_empty() => {
+            let mut found = Vec::new();
+            for selector in selectors {
+                match devices::find_device(selector) {
+                    Ok(device) => found.push(device),
+                    Err(Error::NoDevice(_)) => log::warn!("no input device matches `{}` yet, waiting for it", selector),
+                    Err(err) => return Err(err),
+                }
+            }
+            found
+        }
+        Some(config) if !config.devices.is_empty() => {
+            let matched: Vec<DeviceInfo> = devices::list_devices()?
+                .into_iter()
+                .filter(|device| device.is_keyboard && config.device_keymap(device).is_some())
+                .collect();
+            if matched.is_empty() {
+                log::warn!("no keyboard matches the configured devices yet, waiting for one");
+            }
+            matched
+        }
+        _ => devices::choose_devices()?,
+    };
 
-    let mut runtime = Runtime::new(event_iter, virtual_dev, keyboard, Duration::from_millis(100)).unwrap();
-    runtime.run()
+    // the same device may be matched by different selectors
+    let mut seen = HashSet::new();
+    devices.retain(|device| seen.insert(device.path.clone()));
+    Ok(devices)
+}
+
+/// Whether a device plugged while running is selected by `selector`.
+/// Devices which aren't keyboards (eg the mouse of a wireless receiver) must be selected by path.
+fn is_selected_by(selector: &DeviceSelector, device: &DeviceInfo) -> bool {
+    selector.matches(device) && (device.is_keyboard || matches!(selector, DeviceSelector::Path(_)))
+}
+
+/// Devices with a keymap of their own run an independent keyboard,
+/// the other devices feed the shared keyboard using the default keymap.
+fn attach_for(config: Option<&Config>, device: &DeviceInfo) -> Attach<KeymapKeyboard> {
+    match config.and_then(|config| config.device_keymap(device)) {
+        Some(keymap) => Attach::Own(keymap.build_keyboard()),
+        None => Attach::Shared,
+    }
 }
 
 fn build_mapper() -> MapOrEchoMapper<EV_KEY> {
@@ -53,6 +374,7 @@ fn build_mapper() -> MapOrEchoMapper<EV_KEY> {
             keys::HoldKeyConf { 
                 tap: keys::KeyActionSet::Single(keys::KeyAction::SendKey(EV_KEY::KEY_ESC)),
                 hold: keys::KeyActionSet::Single(keys::KeyAction::SendKey(EV_KEY::KEY_LEFTCTRL)),
+                retro_tap: false,
         })
     );
 
@@ -94,11 +416,153 @@ fn build_mapper() -> MapOrEchoMapper<EV_KEY> {
     MapOrEchoMapper(map)
 }
 
-fn open_dev(path: &str) -> fs::File {
-    unsafe {
-        let flags = libc::O_NONBLOCK | libc::O_RDONLY;
-        let path = CString::new(path).unwrap();
-        let fd = libc::open(path.as_ptr(), flags);
-        fs::File::from_raw_fd(fd)
+/// Print every key with its code and aliases,
+/// or only the key matching the `name` argument.
+fn list_keys(matches: &ArgMatches) {
+    let keys: Vec<EV_KEY> = match matches.value_of("name") {
+        Some(name) => vec![or_exit(keynames::parse(name))],
+        None => keynames::all_keys().collect(),
+    };
+
+    for key in keys {
+        let aliases: Vec<&str> = keynames::aliases(key).collect();
+        println!("{:<24} {:>4}  {}", keynames::name(key), key as u32, aliases.join(", "));
+    }
+}
+
+/// Print every input device, one per line
+fn list_devices() {
+    match devices::list_devices() {
+        Ok(devices) => {
+            for device in devices {
+                println!("{}", device);
+                for link in device.by_id.iter() {
+                    println!("{:<20} {}", "", link.display());
+                }
+            }
+        }
+        Err(err) => {
+            log::error!("failed to list input devices: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Check the permissions needed to run as the current user, and how to get the missing ones.
+/// Exits with an error status if any check failed.
+fn doctor(matches: &ArgMatches) {
+    let paths: Vec<PathBuf> = match matches.values_of("device") {
+        Some(selectors) => selectors
+            .map(|selector| match selector.parse().unwrap() {
+                DeviceSelector::Path(path) => path,
+                selector => or_exit(devices::find_device(&selector)).path,
+            })
+            .collect(),
+        // devices can't be told apart without read access, every one of them is checked then
+        None => match devices::list_devices() {
+            Ok(devices) => devices.into_iter()
+                .filter(|device| device.is_keyboard)
+                .map(|device| device.path)
+                .collect(),
+            Err(_) => or_exit(devices::event_files()),
+        },
+    };
+
+    let user = doctor::current_user().unwrap_or_else(|| "the current user".to_string());
+    println!("checking the permissions of {}", user);
+    let mut checks = doctor::run_checks(&paths);
+    if let Some(dropped_user) = matches.value_of("user") {
+        checks.extend(doctor::check_user(dropped_user));
+    }
+    for check in checks.iter() {
+        println!("{}", check);
+    }
+
+    if checks.iter().all(|check| check.passed) {
+        println!("vkwrty can run as {}", user);
+    } else {
+        process::exit(1);
+    }
+}
+
+/// Record the events of the device given by the `record` subcommand until it's unplugged or vkwrty is interrupted.
+fn record(matches: &ArgMatches) {
+    let selector: DeviceSelector = matches.value_of("device").unwrap().parse().unwrap();
+    let device = or_exit(devices::find_device(&selector));
+    log::info!("recording {}, press Ctrl+C to stop", device);
+
+    let result = match matches.value_of("output") {
+        Some(path) => {
+            let mut file = or_exit(File::create(path).map_err(|err| format!("failed to create {}: {}", path, err)));
+            recording::record(&device, &mut file)
+        }
+        None => recording::record(&device, &mut io::stdout()),
+    };
+    or_exit(result);
+}
+
+/// Replay a recording through the configured keymap, or the built-in one,
+/// and print every event fed to the keyboard with the actions it resulted in.
+fn replay(matches: &ArgMatches) {
+    let recording = or_exit(Recording::load(Path::new(matches.value_of("recording").unwrap())));
+    let config = matches.value_of("config").map(|path| or_exit(Config::load(Path::new(path))));
+    let mut keyboard = match config {
+        Some(config) => config.keymap_for(&recording.device()).build_keyboard(),
+        None => SMKeyboard::new(0, build_mapper(), SMKeyboardSettings::default()),
+    };
+    let clock = SimulatedClock::new(Instant::now());
+    keyboard.set_clock(clock.clone());
+
+    for step in recording::replay(&recording, &mut keyboard, &clock, POLL_PERIOD) {
+        println!("{}", step);
     }
 }
+
+/// Send the request given by the `ctl` subcommand to a running vkwrty and print its response.
+/// Exits with an error status if the request failed.
+fn ctl(matches: &ArgMatches) {
+    let request = match matches.subcommand() {
+        ("status", _) => Request::Status,
+        ("set-layer", Some(matches)) => Request::SetLayer {
+            layer: or_exit(matches.value_of("layer").unwrap().parse()
+                .map_err(|_| "LAYER must be a number from 0 to 255")),
+            keyboard: matches.value_of("keyboard")
+                .map(|index| or_exit(index.parse().map_err(|_| "INDEX must be a number"))),
+        },
+        ("pause", _) => Request::Pause,
+        ("resume", _) => Request::Resume,
+        ("reload", _) => Request::Reload,
+        ("type", Some(matches)) => Request::Type {
+            keys: matches.values_of("keys").unwrap().map(str::to_string).collect(),
+        },
+        ("subscribe", _) => {
+            subscribe(&socket_path(matches));
+            return;
+        }
+        _ => {
+            log::error!("{}", matches.usage());
+            process::exit(1);
+        }
+    };
+
+    let path = socket_path(matches);
+    let response = or_exit(control::send_request(&path, &request)
+        .map_err(|err| format!("failed to reach vkwrty at {}: {}", path.display(), err)));
+    println!("{}", serde_json::to_string(&response).unwrap());
+    if !response.ok {
+        process::exit(1);
+    }
+}
+
+/// Print the status events of a running vkwrty as they come, one per line.
+/// Stops once vkwrty or the reader of the output goes away.
+fn subscribe(path: &Path) {
+    let stdout = io::stdout();
+    or_exit(control::subscribe(path, |event| {
+        let mut stdout = stdout.lock();
+        writeln!(stdout, "{}", serde_json::to_string(&event).unwrap())
+            .and_then(|_| stdout.flush())
+            .is_ok()
+    }).map_err(|err| format!("failed to reach vkwrty at {}: {}", path.display(), err)));
+}
+
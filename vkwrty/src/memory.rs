@@ -0,0 +1,179 @@
+//! In memory input sources and output sinks.
+//!
+//! They stand in for input devices and the uinput device, so the runtime can be driven
+//! without access to `/dev/input` or `/dev/uinput` (eg in tests).
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::RawFd;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+
+use evdev_rs::enums::EV_KEY;
+use evdev_rs::enums::int_to_ev_key;
+use keywerty::keyboard::{Action, Event};
+
+use crate::devices::DeviceInfo;
+use crate::io::{InputSource, OutputSink};
+use crate::{Error, Result};
+
+
+/// Size of an encoded event: its kind followed by the key code
+const FRAME_LEN: usize = 5;
+const PRESS: u8 = 1;
+const RELEASE: u8 = 0;
+
+
+/// Input source fed through an `InputSender`.
+///
+/// Events travel through a socket, so that the source can be monitored through epoll
+/// like an input device. The source is disconnected once its sender is dropped.
+pub struct MemoryInput {
+    stream: UnixStream,
+    events: VecDeque<Event<EV_KEY>>,
+    /// Bytes of a partially received event
+    pending: Vec<u8>,
+    disconnected: bool,
+}
+
+/// Sends key events to a `MemoryInput`, dropping it disconnects the input.
+pub struct InputSender {
+    stream: UnixStream,
+}
+
+impl MemoryInput {
+    pub fn new() -> io::Result<(MemoryInput, InputSender)> {
+        let (stream, sender) = UnixStream::pair()?;
+        stream.set_nonblocking(true)?;
+        let input = MemoryInput { stream, events: VecDeque::new(), pending: Vec::new(), disconnected: false };
+        Ok((input, InputSender { stream: sender }))
+    }
+
+    /// Read every event sent so far.
+    fn read_events(&mut self) {
+        let mut buffer = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.disconnected = true;
+                    return;
+                }
+                Ok(read) => self.pending.extend_from_slice(&buffer[..read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock {
+                        self.disconnected = true;
+                    }
+                    return;
+                }
+            }
+
+            while self.pending.len() >= FRAME_LEN {
+                let frame: Vec<u8> = self.pending.drain(..FRAME_LEN).collect();
+                let code = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+                let key = match int_to_ev_key(code) {
+                    Some(key) => key,
+                    None => continue,
+                };
+                self.events.push_back(match frame[0] {
+                    PRESS => Event::KeyPress(key),
+                    _ => Event::KeyRelease(key),
+                });
+            }
+        }
+    }
+}
+
+impl InputSource for MemoryInput {
+    /// Memory inputs aren't backed by a device, they must be attached with `Runtime::attach_source`.
+    fn open(device: &DeviceInfo) -> Result<Self> {
+        let err = io::Error::new(ErrorKind::Unsupported, "memory inputs can't be opened");
+        Err(Error::DeviceOpen(device.path.clone(), err))
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+}
+
+impl Iterator for MemoryInput {
+    type Item = Event<EV_KEY>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.events.is_empty() && !self.disconnected {
+            self.read_events();
+        }
+        self.events.pop_front()
+    }
+}
+
+impl AsRawFd for MemoryInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+impl InputSender {
+    pub fn press(&mut self, key: EV_KEY) -> io::Result<()> {
+        self.send(PRESS, key)
+    }
+
+    pub fn release(&mut self, key: EV_KEY) -> io::Result<()> {
+        self.send(RELEASE, key)
+    }
+
+    fn send(&mut self, kind: u8, key: EV_KEY) -> io::Result<()> {
+        let mut frame = [kind; FRAME_LEN];
+        frame[1..].copy_from_slice(&(key as u32).to_le_bytes());
+        self.stream.write_all(&frame)
+    }
+}
+
+
+/// Output sink forwarding the emitted actions to a channel.
+pub struct MemoryOutput {
+    sender: Sender<Action<EV_KEY>>,
+}
+
+impl MemoryOutput {
+    /// Return the sink along with the receiving end of its actions.
+    pub fn new() -> (MemoryOutput, Receiver<Action<EV_KEY>>) {
+        let (sender, receiver) = mpsc::channel();
+        (MemoryOutput { sender }, receiver)
+    }
+}
+
+impl OutputSink for MemoryOutput {
+    fn emit_events(&mut self, actions: &[Action<EV_KEY>]) -> Result<()> {
+        // nobody is listening once the receiver is dropped, which isn't an error for the runtime
+        for action in actions {
+            let _ = self.sender.send(action.clone());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_input_receives_events_until_disconnected() {
+        let (mut input, mut sender) = MemoryInput::new().unwrap();
+        assert_eq!(input.next(), None);
+
+        sender.press(EV_KEY::KEY_A).unwrap();
+        sender.release(EV_KEY::KEY_A).unwrap();
+        let events: Vec<Event<EV_KEY>> = (&mut input).collect();
+        assert_eq!(events, vec![Event::KeyPress(EV_KEY::KEY_A), Event::KeyRelease(EV_KEY::KEY_A)]);
+        assert!(!input.is_disconnected());
+
+        drop(sender);
+        assert_eq!(input.next(), None);
+        assert!(input.is_disconnected());
+    }
+}
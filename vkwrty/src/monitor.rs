@@ -1,22 +1,149 @@
+use std::collections::VecDeque;
 use std::io;
-use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::RawFd;
+use std::time::Duration;
+use std::time::Instant;
 
+use evdev_rs::DeviceWrapper;
 use evdev_rs::ReadFlag;
+use evdev_rs::ReadStatus;
 use evdev_rs::Device;
 use evdev_rs::InputEvent;
 use evdev_rs::enums::EventCode;
 use evdev_rs::enums::EV_KEY;
+use evdev_rs::enums::EV_MSC;
+use evdev_rs::enums::EV_SYN;
+use evdev_rs::enums::int_to_ev_key;
 use keywerty::keyboard::Event;
 
+use crate::virtual_dev::PassthroughDevice;
+use crate::{Error, Result};
+
+
+/// Source of input events, implemented by evdev's `Device`.
+pub trait EventDevice {
+    /// Read the next event, as `Device::next_event`.
+    fn next_event(&self, flags: ReadFlag) -> io::Result<(ReadStatus, InputEvent)>;
+
+    /// Whether `key` is down, according to the events read so far.
+    fn is_key_down(&self, key: EV_KEY) -> bool;
+
+    /// Keys down according to the kernel, including the events not read yet.
+    fn keys_down(&self) -> io::Result<Vec<EV_KEY>>;
+
+    /// Grab or release the device for the exclusive use of vkwrty.
+    fn set_grab(&self, grab: bool) -> io::Result<()>;
+}
+
+impl EventDevice for Device {
+    fn next_event(&self, flags: ReadFlag) -> io::Result<(ReadStatus, InputEvent)> {
+        Device::next_event(self, flags)
+    }
+
+    fn is_key_down(&self, key: EV_KEY) -> bool {
+        self.event_value(&EventCode::EV_KEY(key)).is_some_and(|value| value != 0)
+    }
+
+    fn keys_down(&self) -> io::Result<Vec<EV_KEY>> {
+        let mut state = [0u8; KEY_STATE_LEN];
+        if unsafe { libc::ioctl(self.file().as_raw_fd(), EVIOCGKEY as _, state.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(keys_down(&state))
+    }
+
+    fn set_grab(&self, grab: bool) -> io::Result<()> {
+        set_grab(self.file().as_raw_fd(), grab)
+    }
+}
+
+
+/// Destination of the events which aren't key events, see `EventIter::set_passthrough`.
+pub trait EventSink {
+    /// Write a frame of events, followed by a `SYN_REPORT`.
+    fn write_frame(&self, events: &[InputEvent]) -> io::Result<()>;
+}
+
+/// Event read from a device, queued until returned by the `EventIter`
+enum Queued {
+    Key(Event<EV_KEY>),
+    /// Events of a frame which aren't key events, to pass through
+    Passthrough(Vec<InputEvent>),
+}
+
+
+/// Size of the key state bitmask, `KEY_MAX / 8 + 1`
+const KEY_STATE_LEN: usize = 0x2ff / 8 + 1;
+
+/// `EVIOCGKEY(KEY_STATE_LEN)`, reads the key state bitmask of a device
+const EVIOCGKEY: u32 = (2 << 30) | ((KEY_STATE_LEN as u32) << 16) | ((b'E' as u32) << 8) | 0x18;
+
+/// `EVIOCGRAB`, grabs (1) or releases (0) a device for the exclusive use of the caller
+const EVIOCGRAB: u32 = (1 << 30) | ((std::mem::size_of::<libc::c_int>() as u32) << 16) | ((b'E' as u32) << 8) | 0x90;
+
+/// Grab or release the device at `fd`.
+pub(crate) fn set_grab(fd: RawFd, grab: bool) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd, EVIOCGRAB as _, grab as libc::c_int) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Keys set in a key state bitmask, as read through `EVIOCGKEY`
+fn keys_down(state: &[u8]) -> Vec<EV_KEY> {
+    (0..state.len() * 8)
+        .filter(|code| state[code / 8] & (1 << (code % 8)) != 0)
+        .filter_map(|code| int_to_ev_key(code as u32))
+        .collect()
+}
+
+/// Whether `key` is a button of a pointer, joystick or tablet (`BTN_MISC` to `BTN_GEAR_UP`)
+fn is_button(key: EV_KEY) -> bool {
+    (EV_KEY::BTN_MISC as u32..=EV_KEY::BTN_GEAR_UP as u32).contains(&(key as u32))
+}
+
+/// Keys down when a device is opened are considered stuck after this long,
+/// they don't keep the device from being grabbed anymore
+const GRAB_WAIT_LIMIT: Duration = Duration::from_secs(2);
+
+/// Whether the device is grabbed by the `EventIter`
+#[derive(Debug)]
+enum Grab {
+    /// Never grabbed, its events reach the OS as well (eg dry runs)
+    Never,
+    /// Waiting for `keys`, down when the device was opened, to be released
+    Pending { keys: Vec<EV_KEY>, deadline: Instant },
+    Grabbed,
+}
+
 
 /// Iterator that returns an Evdev event for a give device file.
 /// Calling `next` will perform a device read, which in turn will
 /// return an event.
-pub struct EventIter {
-    device: Device,
-    events: Vec<Event<EV_KEY>>
+///
+/// Events are returned once their frame is complete (ie on `SYN_REPORT`).
+/// When the kernel drops events (`SYN_DROPPED`), the device state is resynchronized
+/// and the keys pressed or released in the meantime are reported as such.
+///
+/// Events other than key events, as well as buttons, are written to the passthrough sink,
+/// if any, in frames of their own. They're written as the iterator reaches them, so that they're passed through
+/// in order with the actions resulting from the key events before them.
+pub struct EventIter<D = Device> {
+    device: D,
+    events: VecDeque<Queued>,
+    /// Events of the current frame, not yet terminated by a `SYN_REPORT`
+    frame: Vec<Event<EV_KEY>>,
+    /// Events of the current frame to pass through
+    passthrough_frame: Vec<InputEvent>,
+    passthrough: Option<Box<dyn EventSink>>,
+    /// Keys reported as pressed and not yet released, in the order they were pressed
+    pressed: Vec<EV_KEY>,
+    grab: Grab,
+    disconnected: bool,
 }
 
 impl AsRawFd for EventIter {
@@ -25,66 +152,280 @@ impl AsRawFd for EventIter {
     }
 }
 
-// TODO use log facade for debug log
-// https://rust-lang-nursery.github.io/rust-cookbook/development_tools/debugging/config_log.html
-// https://docs.rs/log/latest/log/
 impl EventIter {
 
-    pub fn new(file: File) -> io::Result<Self> {
-        let device = Device::new_from_file(file)?;
-        
-        // FIXME grab that from the linux header. figure out how to do that through rust
-        let EVIOCGRAB = 1074021776;
-
-        unsafe {
-            let fd = device.file().as_raw_fd();
-            let rv = libc::ioctl(fd, EVIOCGRAB, 1);
-            if rv == -1 {
-                return Err(io::Error::last_os_error());
+    /// Open the event device at `path` and grab it, once none of its keys is down.
+    ///
+    /// Grabbing a device while a key is down (eg Enter, used to start vkwrty) would deliver
+    /// its release to vkwrty rather than to the OS, which would then see the key as stuck.
+    /// Rather than blocking, the device is grabbed when it's read after its keys are released,
+    /// see `grab_once_released`.
+    /// Events read before the device is grabbed have already been handled by the OS and are discarded.
+    ///
+    /// Devices with relative, absolute, switch or sound events (eg a keyboard with a trackpoint)
+    /// get a `PassthroughDevice`, those events would be lost otherwise.
+    pub fn open(path: &Path) -> Result<Self> {
+        let device = open_device(path)?;
+
+        let keys = device.keys_down().map_err(|err| Error::device_open(path, err))?;
+        if keys.is_empty() {
+            device.set_grab(true).map_err(|err| Error::Grab(path.to_path_buf(), err))?;
+            while device.next_event(ReadFlag::NORMAL).is_ok() {}
+        } else {
+            log::info!("waiting for keys to be released before grabbing {}: {:?}", path.display(), keys);
+        }
+
+        let passthrough = match PassthroughDevice::has_passthrough_events(&device) {
+            true => match PassthroughDevice::mirror(&device, path) {
+                Ok(passthrough) => Some(passthrough),
+                Err(err) => {
+                    log::warn!("failed to create the passthrough device of {}, only its keys will work: {}", path.display(), err);
+                    None
+                }
+            },
+            false => None,
+        };
+        let mut events = Self::from_device(device);
+        if let Some(passthrough) = passthrough {
+            events.set_passthrough(Box::new(passthrough));
+        }
+        if !keys.is_empty() {
+            events.grab_once_released(keys, Instant::now() + GRAB_WAIT_LIMIT);
+        }
+        Ok(events)
+    }
+
+    /// Open the event device at `path` without grabbing it, its events keep reaching the OS.
+    pub fn open_ungrabbed(path: &Path) -> Result<Self> {
+        let mut events = Self::from_device(open_device(path)?);
+        events.grab = Grab::Never;
+        Ok(events)
+    }
+}
+
+/// Open the event device at `path` for non blocking reads.
+fn open_device(path: &Path) -> Result<Device> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .map_err(|err| Error::device_open(path, err))?;
+    Device::new_from_file(file).map_err(|err| Error::device_open(path, err))
+}
+
+impl<D: EventDevice> EventIter<D> {
+
+    /// Read the events of `device`, which is expected to be grabbed already.
+    pub fn from_device(device: D) -> Self {
+        Self {
+            device,
+            events: VecDeque::new(),
+            frame: Vec::new(),
+            passthrough_frame: Vec::new(),
+            passthrough: None,
+            pressed: Vec::new(),
+            grab: Grab::Grabbed,
+            disconnected: false,
+        }
+    }
+
+    /// Leave the device to the OS until `keys`, which are down, are released. The keys still
+    /// down after `deadline` are considered stuck and don't keep the device from being grabbed.
+    pub fn grab_once_released(&mut self, keys: Vec<EV_KEY>, deadline: Instant) {
+        self.grab = Grab::Pending { keys, deadline };
+    }
+
+    /// Release the grab, handing the device back to the OS.
+    pub fn ungrab(&mut self) -> io::Result<()> {
+        match self.grab {
+            Grab::Grabbed => self.device.set_grab(false),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the events which aren't key events to `sink` rather than dropping them.
+    pub fn set_passthrough(&mut self, sink: Box<dyn EventSink>) {
+        self.passthrough = Some(sink);
+    }
+
+    /// Whether the device was disconnected (eg unplugged), in which case no more events will be read.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Grab the device if it's waiting to be, once none of its keys is down except the stuck ones.
+    /// Return whether the device's events are for vkwrty.
+    fn try_grab(&mut self) -> bool {
+        let (keys, deadline) = match &mut self.grab {
+            Grab::Pending { keys, deadline } => (keys, *deadline),
+            _ => return true,
+        };
+        let keys_down = match self.device.keys_down() {
+            Ok(keys_down) => keys_down,
+            Err(err) => {
+                log::error!("failed to read the keys down of the event device: {}", err);
+                return false;
             }
+        };
+
+        // a key released since the device was opened isn't stuck, if pressed again it's waited for
+        keys.retain(|key| keys_down.contains(key));
+        let is_stuck = |key: &EV_KEY| Instant::now() >= deadline && keys.contains(key);
+        if !keys_down.iter().all(is_stuck) {
+            return false;
+        }
+        if !keys_down.is_empty() {
+            log::warn!("grabbing the event device while keys are stuck down: {:?}", keys_down);
         }
 
-        Ok(Self {
-           device,
-           events: Vec::new()
-       })
+        if let Err(err) = self.device.set_grab(true) {
+            log::error!("failed to grab the event device, is another program grabbing it? {}", err);
+            self.disconnected = true;
+            return false;
+        }
+        log::debug!("grabbed the event device");
+        self.grab = Grab::Grabbed;
+        true
+    }
+
+    /// Read and drop the pending events, which have been handled by the OS.
+    fn discard_events(&mut self) {
+        loop {
+            match self.device.next_event(ReadFlag::NORMAL) {
+                Ok(_) => (),
+                Err(error) if error.raw_os_error() == Some(libc::ENODEV) => {
+                    log::info!("event device disconnected");
+                    self.disconnected = true;
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
     }
 
     fn read_all_events(&mut self) {
-        // FIXME this implementation completely ignores the SYN_DROPPED
-        // events from evdev and must be revisted.
-        // See:
-        // - https://www.freedesktop.org/software/libevdev/doc/latest/syn_dropped.html
-        // - https://docs.rs/evdev-rs/latest/evdev_rs/struct.Device.html#method.next_event
+        if matches!(self.grab, Grab::Pending { .. }) {
+            // the events read before grabbing, including the one leading to the grab, are the OS's
+            let is_grabbed = self.try_grab();
+            self.discard_events();
+            if !is_grabbed {
+                return;
+            }
+        }
         loop {
             match self.device.next_event(ReadFlag::NORMAL) {
+                // the kernel dropped events, the device must be resynced
+                Ok((ReadStatus::Sync, _)) => {
+                    log::warn!("event device dropped events, resyncing");
+                    self.resync();
+                },
+                Ok((_, InputEvent { event_code: EventCode::EV_SYN(EV_SYN::SYN_REPORT), .. })) => {
+                    self.end_frame();
+                },
                 Ok((_, input_event)) => {
-                    eprintln!("read event: {:?}", input_event);
-                    if let Some(event) = self.map_event(input_event) {
-                        self.events.push(event);
-                    }
+                    log::trace!("read event: {:?}", input_event);
+                    self.map_event(input_event);
                 },
+                // there are no more events to read
+                Err(error) if error.raw_os_error() == Some(libc::EAGAIN) => return,
+                Err(error) if error.raw_os_error() == Some(libc::ENODEV) => {
+                    log::info!("event device disconnected");
+                    self.disconnected = true;
+                    return;
+                }
                 Err(error) => {
-                    eprintln!("error reading event device: {:?}", error);
+                    log::error!("error reading event device: {}", error);
                     return;
                 }
             }
         }
     }
 
-    fn map_event(&mut self, input_event: InputEvent) -> Option<Event<EV_KEY>> {
+    /// Queue the events of the complete frame, the events to pass through first.
+    fn end_frame(&mut self) {
+        if !self.passthrough_frame.is_empty() {
+            let passthrough_frame = std::mem::take(&mut self.passthrough_frame);
+            self.events.push_back(Queued::Passthrough(passthrough_frame));
+        }
+        for event in self.frame.drain(..) {
+            match event {
+                Event::KeyPress(key) if !self.pressed.contains(&key) => self.pressed.push(key),
+                Event::KeyRelease(key) => self.pressed.retain(|pressed| *pressed != key),
+                _ => (),
+            }
+            self.events.push_back(Queued::Key(event));
+        }
+    }
+
+    /// Resynchronize the device state after `SYN_DROPPED`, see
+    /// https://www.freedesktop.org/software/libevdev/doc/latest/syn_dropped.html
+    ///
+    /// The incomplete frame is discarded, then the keys whose state differs from the one
+    /// reported so far are released or pressed.
+    fn resync(&mut self) {
+        let mut changed_keys: Vec<EV_KEY> = self.frame.drain(..)
+            .filter_map(|event| event.get_key_id().copied())
+            .collect();
+        self.passthrough_frame.clear();
+
+        // sync events are read until the device is in sync, signaled by EAGAIN
+        while let Ok((_, input_event)) = self.device.next_event(ReadFlag::SYNC) {
+            match input_event.event_code {
+                EventCode::EV_KEY(key) if !self.passes_through(key) => changed_keys.push(key),
+                _ => (),
+            }
+        }
+
+        let released: Vec<EV_KEY> = self.pressed.iter()
+            .copied()
+            .filter(|key| !self.device.is_key_down(*key))
+            .collect();
+        for key in released {
+            self.pressed.retain(|pressed| *pressed != key);
+            self.events.push_back(Queued::Key(Event::KeyRelease(key)));
+        }
+
+        for key in changed_keys {
+            if !self.pressed.contains(&key) && self.device.is_key_down(key) {
+                self.pressed.push(key);
+                self.events.push_back(Queued::Key(Event::KeyPress(key)));
+            }
+        }
+    }
+
+    /// Add `input_event` to the current frame, as a key event or an event to pass through.
+    /// Key repeats are dropped, repeating keys is up to the keyboards, and so are scan codes
+    /// (meaningless once keys are remapped) and LED events.
+    fn map_event(&mut self, input_event: InputEvent) {
         match &input_event {
-            InputEvent { event_code: EventCode::EV_KEY(ev_key), value: 0, .. } => Some(Event::KeyRelease(*ev_key)),
-            InputEvent { event_code: EventCode::EV_KEY(ev_key), value: 1, .. } => Some(Event::KeyPress(*ev_key)),
-            ev => {
-                eprintln!("dropped input event: {:?}", input_event);
-                None
+            InputEvent { event_code: EventCode::EV_KEY(ev_key), .. } if self.passes_through(*ev_key) => self.passthrough_frame.push(input_event),
+            InputEvent { event_code: EventCode::EV_KEY(ev_key), value: 0, .. } => self.frame.push(Event::KeyRelease(*ev_key)),
+            InputEvent { event_code: EventCode::EV_KEY(ev_key), value: 1, .. } => self.frame.push(Event::KeyPress(*ev_key)),
+            InputEvent { event_code: EventCode::EV_KEY(_), .. } => log::trace!("dropped input event: {:?}", input_event),
+            InputEvent { event_code: EventCode::EV_MSC(EV_MSC::MSC_SCAN) | EventCode::EV_LED(_), .. } => log::trace!("dropped input event: {:?}", input_event),
+            _ if self.passthrough.is_some() => self.passthrough_frame.push(input_event),
+            _ => log::trace!("dropped input event: {:?}", input_event),
+        }
+    }
+
+    /// Whether `key` is passed through rather than fed into the keyboards: buttons of devices
+    /// with a passthrough sink (eg the buttons of a trackpoint) go along with their motion,
+    /// as the OS ignores clicks coming from a device which doesn't move.
+    fn passes_through(&self, key: EV_KEY) -> bool {
+        self.passthrough.is_some() && is_button(key)
+    }
+
+    /// Write `events` to the passthrough sink.
+    fn pass_through(&self, events: &[InputEvent]) {
+        if let Some(passthrough) = self.passthrough.as_ref() {
+            if let Err(err) = passthrough.write_frame(events) {
+                log::error!("failed to pass {:?} through: {}", events, err);
             }
         }
     }
 }
 
-impl Iterator for EventIter {
+impl<D: EventDevice> Iterator for EventIter<D> {
     type Item = Event<EV_KEY>;
 
     /// Return the next event from the event queue.
@@ -93,21 +434,240 @@ impl Iterator for EventIter {
     /// Note that reading the device will not block, therefore it should be paired
     /// epoll to avoid busy looping.
     fn next(&mut self) -> Option<Self::Item> {
-        if self.events.is_empty() {
+        if self.events.is_empty() && !self.disconnected {
             self.read_all_events();
         }
-        self.events.pop()
+        while let Some(queued) = self.events.pop_front() {
+            match queued {
+                Queued::Key(event) => return Some(event),
+                Queued::Passthrough(events) => self.pass_through(&events),
+            }
+        }
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use evdev_rs::TimeVal;
+    use evdev_rs::enums::EV_REL;
+
+    /// Device replaying scripted reads, reporting EAGAIN once they're exhausted.
+    struct FakeDevice {
+        reads: RefCell<VecDeque<io::Result<(ReadStatus, InputEvent)>>>,
+        sync_reads: RefCell<VecDeque<InputEvent>>,
+        keys_down: Vec<EV_KEY>,
+        grabbed: Cell<bool>,
+    }
+
+    impl FakeDevice {
+        fn new() -> Self {
+            Self {
+                reads: RefCell::new(VecDeque::new()),
+                sync_reads: RefCell::new(VecDeque::new()),
+                keys_down: Vec::new(),
+                grabbed: Cell::new(false),
+            }
+        }
+
+        fn read(&self, code: EventCode, value: i32) {
+            let event = InputEvent::new(&TimeVal::new(0, 0), &code, value);
+            self.reads.borrow_mut().push_back(Ok((ReadStatus::Success, event)));
+        }
+
+        fn key(&self, key: EV_KEY, value: i32) {
+            self.read(EventCode::EV_KEY(key), value);
+            self.read(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        }
+
+        fn drop_events(&self, synced_keys: &[(EV_KEY, i32)]) {
+            let dropped = InputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_SYN(EV_SYN::SYN_DROPPED), 0);
+            self.reads.borrow_mut().push_back(Ok((ReadStatus::Sync, dropped)));
+            for (key, value) in synced_keys {
+                let event = InputEvent::new(&TimeVal::new(0, 0), &EventCode::EV_KEY(*key), *value);
+                self.sync_reads.borrow_mut().push_back(event);
+            }
+        }
+    }
+
+    impl EventDevice for FakeDevice {
+        fn next_event(&self, flags: ReadFlag) -> io::Result<(ReadStatus, InputEvent)> {
+            let again = || io::Error::from_raw_os_error(libc::EAGAIN);
+            if flags == ReadFlag::SYNC {
+                self.sync_reads.borrow_mut().pop_front()
+                    .map(|event| (ReadStatus::Sync, event))
+                    .ok_or_else(again)
+            } else {
+                self.reads.borrow_mut().pop_front().unwrap_or_else(|| Err(again()))
+            }
+        }
+
+        fn is_key_down(&self, key: EV_KEY) -> bool {
+            self.keys_down.contains(&key)
+        }
+
+        fn keys_down(&self) -> io::Result<Vec<EV_KEY>> {
+            Ok(self.keys_down.clone())
+        }
+
+        fn set_grab(&self, grab: bool) -> io::Result<()> {
+            self.grabbed.set(grab);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_iterator_returns_complete_frames_in_order() {
+        let device = FakeDevice::new();
+        device.key(EV_KEY::KEY_A, 1);
+        device.key(EV_KEY::KEY_A, 2);
+        device.key(EV_KEY::KEY_A, 0);
+        device.read(EventCode::EV_KEY(EV_KEY::KEY_B), 1);
+        let mut events = EventIter::from_device(device);
+
+        assert_eq!(events.by_ref().collect::<Vec<_>>(), vec![
+            Event::KeyPress(EV_KEY::KEY_A),
+            Event::KeyRelease(EV_KEY::KEY_A),
+        ]);
+
+        // the frame is completed by a later read
+        events.device.read(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        assert_eq!(events.next(), Some(Event::KeyPress(EV_KEY::KEY_B)));
+    }
+
+    #[test]
+    fn test_syn_dropped_resyncs_pressed_keys() {
+        let mut device = FakeDevice::new();
+        device.key(EV_KEY::KEY_LEFTSHIFT, 1);
+        device.key(EV_KEY::KEY_A, 1);
+        // the frame interrupted by SYN_DROPPED is discarded
+        device.read(EventCode::EV_KEY(EV_KEY::KEY_B), 1);
+        device.drop_events(&[(EV_KEY::KEY_LEFTSHIFT, 0), (EV_KEY::KEY_C, 1)]);
+        device.key(EV_KEY::KEY_D, 1);
+        device.keys_down = vec![EV_KEY::KEY_A, EV_KEY::KEY_B, EV_KEY::KEY_C];
+
+        let events: Vec<_> = EventIter::from_device(device).collect();
+
+        assert_eq!(events, vec![
+            Event::KeyPress(EV_KEY::KEY_LEFTSHIFT),
+            Event::KeyPress(EV_KEY::KEY_A),
+            Event::KeyRelease(EV_KEY::KEY_LEFTSHIFT),
+            Event::KeyPress(EV_KEY::KEY_B),
+            Event::KeyPress(EV_KEY::KEY_C),
+            Event::KeyPress(EV_KEY::KEY_D),
+        ]);
+    }
+
+    /// Sink recording the frames written to it.
+    struct FakeSink(Rc<RefCell<Vec<Vec<EventCode>>>>);
+
+    impl EventSink for FakeSink {
+        fn write_frame(&self, events: &[InputEvent]) -> io::Result<()> {
+            self.0.borrow_mut().push(events.iter().map(|event| event.event_code).collect());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_other_events_are_passed_through_in_order() {
+        let device = FakeDevice::new();
+        device.read(EventCode::EV_REL(EV_REL::REL_WHEEL), 1);
+        device.read(EventCode::EV_MSC(EV_MSC::MSC_SCAN), 0x1e);
+        device.key(EV_KEY::KEY_A, 1);
+        device.read(EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP), 8000);
+        device.read(EventCode::EV_KEY(EV_KEY::BTN_RIGHT), 1);
+        device.read(EventCode::EV_REL(EV_REL::REL_X), 3);
+        device.read(EventCode::EV_REL(EV_REL::REL_Y), -1);
+        device.read(EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        device.key(EV_KEY::KEY_A, 0);
+        // the frame interrupted by SYN_DROPPED is discarded
+        device.read(EventCode::EV_REL(EV_REL::REL_X), 1);
+        device.drop_events(&[]);
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        let mut events = EventIter::from_device(device);
+        events.set_passthrough(Box::new(FakeSink(frames.clone())));
+
+        // the frame is passed through before the key events read after it are returned,
+        // scan codes are dropped
+        assert_eq!(events.next(), Some(Event::KeyPress(EV_KEY::KEY_A)));
+        assert_eq!(*frames.borrow(), vec![vec![EventCode::EV_REL(EV_REL::REL_WHEEL)]]);
+        assert_eq!(events.next(), Some(Event::KeyRelease(EV_KEY::KEY_A)));
+        assert_eq!(frames.borrow().len(), 2);
+        // buttons go along with the motion
+        assert_eq!(frames.borrow()[1], vec![
+            EventCode::EV_MSC(EV_MSC::MSC_TIMESTAMP),
+            EventCode::EV_KEY(EV_KEY::BTN_RIGHT),
+            EventCode::EV_REL(EV_REL::REL_X),
+            EventCode::EV_REL(EV_REL::REL_Y),
+        ]);
+        assert_eq!(events.next(), None);
+        assert_eq!(frames.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_device_is_grabbed_once_keys_are_released() {
+        let mut device = FakeDevice::new();
+        device.keys_down = vec![EV_KEY::KEY_ENTER];
+        device.key(EV_KEY::KEY_A, 1);
+        let mut events = EventIter::from_device(device);
+        events.grab_once_released(vec![EV_KEY::KEY_ENTER], Instant::now() + Duration::from_secs(60));
+
+        // events read while keys are down are left to the OS
+        assert_eq!(events.next(), None);
+        assert!(!events.device.grabbed.get());
+
+        // the release leading to the grab is the OS's as well
+        events.device.keys_down.clear();
+        events.device.key(EV_KEY::KEY_ENTER, 0);
+        assert_eq!(events.next(), None);
+        assert!(events.device.grabbed.get());
+
+        events.device.key(EV_KEY::KEY_B, 1);
+        assert_eq!(events.next(), Some(Event::KeyPress(EV_KEY::KEY_B)));
+    }
+
+    #[test]
+    fn test_keys_stuck_down_dont_keep_device_from_being_grabbed() {
+        let mut device = FakeDevice::new();
+        device.keys_down = vec![EV_KEY::KEY_F13, EV_KEY::KEY_A];
+        device.key(EV_KEY::KEY_A, 1);
+        let mut events = EventIter::from_device(device);
+        events.grab_once_released(vec![EV_KEY::KEY_F13], Instant::now());
+
+        // keys pressed since the device was opened are waited for
+        assert_eq!(events.next(), None);
+        assert!(!events.device.grabbed.get());
+
+        events.device.keys_down = vec![EV_KEY::KEY_F13];
+        events.device.key(EV_KEY::KEY_A, 0);
+        assert_eq!(events.next(), None);
+        assert!(events.device.grabbed.get());
+    }
 
     #[test]
-    fn test_iterator_return_elements() {
-        // TODO research how to mock `Device`
-        // or crate trait and implement for `Device`
-        // such that I can use a stub interface.
+    fn test_keys_down_decodes_key_state_bitmask() {
+        let mut state = [0u8; KEY_STATE_LEN];
+        assert!(keys_down(&state).is_empty());
+
+        // KEY_ESC = 1, KEY_ENTER = 28, BTN_RIGHT = 0x111
+        state[0] = 0b10;
+        state[3] = 0b10000;
+        state[0x111 / 8] = 1 << (0x111 % 8);
+        assert_eq!(keys_down(&state), vec![EV_KEY::KEY_ESC, EV_KEY::KEY_ENTER, EV_KEY::BTN_RIGHT]);
     }
 
+    #[test]
+    fn test_disconnected_device_stops_iteration() {
+        let device = FakeDevice::new();
+        device.key(EV_KEY::KEY_A, 1);
+        device.reads.borrow_mut().push_back(Err(io::Error::from_raw_os_error(libc::ENODEV)));
+        let mut events = EventIter::from_device(device);
+
+        assert_eq!(events.next(), Some(Event::KeyPress(EV_KEY::KEY_A)));
+        assert_eq!(events.next(), None);
+        assert!(events.is_disconnected());
+    }
 }
@@ -0,0 +1,77 @@
+//! Dropping root privileges once the devices are open.
+//!
+//! Open devices stay usable after the process switched to another user, so vkwrty can be
+//! started as root and then run as a regular user. Devices plugged afterwards (and the
+//! configuration file) must be readable by that user though, and the passthrough devices of
+//! the devices plugged afterwards need write access to `/dev/uinput`.
+//! Without them, hotplug and passthrough devices only work for the devices open beforehand,
+//! `doctor::check_user` tells whether a user has them.
+
+use std::ffi::{CStr, CString};
+use std::io;
+
+use crate::{Error, Result};
+
+
+/// Switch the process to `user`, along with its primary and supplementary groups.
+///
+/// Groups are changed first, since a process which isn't root anymore can't change them.
+/// Fails if root privileges could be regained afterwards.
+pub fn drop_privileges(user: &str) -> Result<()> {
+    let name = CString::new(user).map_err(|_| Error::UnknownUser(user.to_string()))?;
+    let (uid, gid) = user_ids(&name).ok_or_else(|| Error::UnknownUser(user.to_string()))?;
+
+    unsafe {
+        if libc::initgroups(name.as_ptr(), gid) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if libc::setgid(gid) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if uid != 0 && libc::setuid(0) == 0 {
+            return Err(io::Error::other("root privileges could be regained").into());
+        }
+    }
+
+    log::info!("running as {} (uid {}, gid {})", user, uid, gid);
+    Ok(())
+}
+
+/// Id of `user`, `None` if there's no such user.
+pub fn user_id(user: &str) -> Option<libc::uid_t> {
+    let name = CString::new(user).ok()?;
+    user_ids(&name).map(|(uid, _)| uid)
+}
+
+/// Ids of the groups of `user`, including its primary group, as set by `drop_privileges`.
+pub fn user_groups(user: &str) -> Option<Vec<libc::gid_t>> {
+    let name = CString::new(user).ok()?;
+    let (_, gid) = user_ids(&name)?;
+
+    let mut count: libc::c_int = 32;
+    loop {
+        let mut groups = vec![0; count as usize];
+        let previous_count = count;
+        let result = unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if result >= 0 {
+            groups.truncate(count.max(0) as usize);
+            return Some(groups);
+        }
+        // the list was too short, count is the number of groups now
+        if count <= previous_count {
+            return None;
+        }
+    }
+}
+
+fn user_ids(name: &CStr) -> Option<(libc::uid_t, libc::gid_t)> {
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        None
+    } else {
+        Some(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) })
+    }
+}
@@ -0,0 +1,479 @@
+//! Recording of the raw events of an input device, and their replay through a keyboard.
+//!
+//! Recordings are text files in a format close to the one of `evemu-record`, so they can be
+//! read by people and by evemu's tools:
+//!
+//! ```text
+//! # EVEMU 1.3
+//! N: AT Translated Set 2 keyboard
+//! I: 0000 0001 0001 0000
+//! E: 0.000000 0004 0004 0058
+//! E: 0.000000 0001 003a 0001
+//! E: 0.000000 0000 0000 0000
+//! ```
+//!
+//! `N` is the device name, `I` its bus, vendor, product and version ids, and every `E` line
+//! an event: its time in seconds since the first event, its type, code and value.
+//! Unlike evemu, the capabilities of the device aren't recorded.
+
+use std::error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use evdev_rs::Device;
+use evdev_rs::DeviceWrapper;
+use evdev_rs::InputEvent;
+use evdev_rs::ReadFlag;
+use evdev_rs::ReadStatus;
+use evdev_rs::TimeVal;
+use evdev_rs::enums::EventCode;
+use evdev_rs::enums::EV_KEY;
+use evdev_rs::enums::EV_SYN;
+use evdev_rs::enums::int_to_ev_key;
+use keywerty::keyboard::Action;
+use keywerty::keyboard::Clock;
+use keywerty::keyboard::Event;
+use keywerty::keyboard::Keyboard;
+use keywerty::keyboard::SimulatedClock;
+
+use crate::devices::DeviceInfo;
+use crate::dry_run::describe_event;
+use crate::{Error, Result};
+
+
+/// `EV_SYN` event type
+const EV_SYN_TYPE: u16 = 0;
+/// `EV_KEY` event type
+const EV_KEY_TYPE: u16 = 1;
+
+/// Shortest time between two polls of a replay, so that overdue deadlines don't stall it
+const MIN_WAKE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// An input event, as read from the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Time elapsed since the first event of the recording
+    pub time: Duration,
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl RecordedEvent {
+    /// Event recorded from `input_event`, `start` being the time of the first event.
+    pub fn from_input_event(input_event: &InputEvent, start: &TimeVal) -> Self {
+        let (event_type, code) = raw_code(&input_event.event_code);
+        let micros = (input_event.time.tv_sec - start.tv_sec) * 1_000_000 + (input_event.time.tv_usec - start.tv_usec);
+        Self {
+            time: Duration::from_micros(micros.max(0) as u64),
+            event_type,
+            code,
+            value: input_event.value,
+        }
+    }
+
+    /// Whether the event ends a frame (`SYN_REPORT`).
+    pub fn is_syn_report(&self) -> bool {
+        self.event_type == EV_SYN_TYPE && self.code == EV_SYN::SYN_REPORT as u16
+    }
+
+    /// Keyboard event of a key press or release, key repeats are ignored as by `EventIter`.
+    pub fn key_event(&self) -> Option<Event<EV_KEY>> {
+        if self.event_type != EV_KEY_TYPE {
+            return None;
+        }
+        let key = int_to_ev_key(self.code as u32)?;
+        match self.value {
+            0 => Some(Event::KeyRelease(key)),
+            1 => Some(Event::KeyPress(key)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RecordedEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E: {}.{:06} {:04x} {:04x} {:04}",
+               self.time.as_secs(), self.time.subsec_micros(), self.event_type, self.code, self.value)
+    }
+}
+
+/// Type and code of `code`, as numbered by the kernel.
+fn raw_code(code: &EventCode) -> (u16, u16) {
+    match code {
+        EventCode::EV_SYN(code) => (0x00, *code as u16),
+        EventCode::EV_KEY(code) => (0x01, *code as u16),
+        EventCode::EV_REL(code) => (0x02, *code as u16),
+        EventCode::EV_ABS(code) => (0x03, *code as u16),
+        EventCode::EV_MSC(code) => (0x04, *code as u16),
+        EventCode::EV_SW(code) => (0x05, *code as u16),
+        EventCode::EV_LED(code) => (0x11, *code as u16),
+        EventCode::EV_SND(code) => (0x12, *code as u16),
+        EventCode::EV_REP(code) => (0x14, *code as u16),
+        EventCode::EV_FF(code) => (0x15, *code as u16),
+        EventCode::EV_PWR => (0x16, 0),
+        EventCode::EV_FF_STATUS(code) => (0x17, *code as u16),
+        EventCode::EV_UNK { event_type, event_code } => (*event_type as u16, *event_code as u16),
+        EventCode::EV_MAX => (0x1f, 0),
+    }
+}
+
+
+/// Error parsing a recording, at the given line (starting at 1).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordingError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid recording at line {}: {}", self.line, self.reason)
+    }
+}
+
+impl error::Error for RecordingError {}
+
+
+/// Events recorded from a device.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub events: Vec<RecordedEvent>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|err| Error::DeviceOpen(path.to_path_buf(), err))?;
+        Ok(content.parse()?)
+    }
+
+    /// Device the events were recorded from, as far as the recording tells,
+    /// used to pick the keymap of the device.
+    pub fn device(&self) -> DeviceInfo {
+        DeviceInfo {
+            path: Default::default(),
+            name: self.name.clone(),
+            phys: None,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            has_keys: true,
+            is_keyboard: true,
+            by_id: Vec::new(),
+        }
+    }
+
+    /// Write the recording, starting with its header.
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        write_header(out, &self.name, self.vendor_id, self.product_id)?;
+        for event in self.events.iter() {
+            writeln!(out, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = RecordingError;
+
+    fn from_str(content: &str) -> std::result::Result<Self, Self::Err> {
+        let mut recording = Recording::default();
+        for (index, line) in content.lines().enumerate() {
+            let error = |reason: &str| RecordingError { line: index + 1, reason: reason.to_string() };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, fields) = line.split_once(':').ok_or_else(|| error("expected `<kind>: <fields>`"))?;
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            match kind {
+                "N" => recording.name = fields.join(" "),
+                "I" => {
+                    let ids = fields.iter()
+                        .map(|field| u16::from_str_radix(field, 16))
+                        .collect::<std::result::Result<Vec<u16>, _>>()
+                        .map_err(|_| error("invalid id"))?;
+                    if ids.len() != 4 {
+                        return Err(error("expected bus, vendor, product and version ids"));
+                    }
+                    recording.vendor_id = ids[1];
+                    recording.product_id = ids[2];
+                }
+                "E" => {
+                    if fields.len() != 4 {
+                        return Err(error("expected time, type, code and value"));
+                    }
+                    let time = parse_time(fields[0]).ok_or_else(|| error("invalid time"))?;
+                    let event_type = u16::from_str_radix(fields[1], 16).map_err(|_| error("invalid event type"))?;
+                    let code = u16::from_str_radix(fields[2], 16).map_err(|_| error("invalid event code"))?;
+                    let value = fields[3].parse().map_err(|_| error("invalid event value"))?;
+                    // replaying the events runs a clock, which can't go backwards
+                    if recording.events.last().is_some_and(|previous| time < previous.time) {
+                        return Err(error("time is earlier than the previous event's"));
+                    }
+                    recording.events.push(RecordedEvent { time, event_type, code, value });
+                }
+                // the capabilities written by evemu-record aren't needed to replay key events
+                _ => (),
+            }
+        }
+        Ok(recording)
+    }
+}
+
+/// Parse a time written as `<seconds>.<microseconds>`.
+fn parse_time(time: &str) -> Option<Duration> {
+    let (secs, micros) = time.split_once('.')?;
+    if micros.len() != 6 {
+        return None;
+    }
+    Some(Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(micros.parse().ok()?))
+}
+
+fn write_header(out: &mut impl Write, name: &str, vendor_id: u16, product_id: u16) -> io::Result<()> {
+    writeln!(out, "# EVEMU 1.3")?;
+    writeln!(out, "# Recorded by vkwrty, bus and version ids are unknown")?;
+    writeln!(out, "N: {}", name)?;
+    writeln!(out, "I: 0000 {:04x} {:04x} 0000", vendor_id, product_id)
+}
+
+
+/// Write the events of `device` to `out` as they're read, until the device is disconnected.
+///
+/// The device isn't grabbed, its events keep reaching the OS, but the device must not be
+/// grabbed by another program (eg vkwrty itself) which would get its events instead.
+/// Every frame is flushed once complete, so that recordings interrupted by Ctrl+C are complete.
+pub fn record(device: &DeviceInfo, out: &mut impl Write) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&device.path)
+        .map_err(|err| Error::device_open(&device.path, err))?;
+    let input = Device::new_from_file(file).map_err(|err| Error::device_open(&device.path, err))?;
+    write_header(out, &device.name, input.vendor_id(), input.product_id())?;
+    out.flush()?;
+
+    let mut start = None;
+    loop {
+        match input.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING) {
+            Ok((ReadStatus::Sync, _)) => {
+                log::warn!("event device dropped events, the recording is missing some");
+                while input.next_event(ReadFlag::SYNC).is_ok() {}
+            }
+            Ok((_, input_event)) => {
+                let start = start.get_or_insert(input_event.time);
+                let event = RecordedEvent::from_input_event(&input_event, start);
+                writeln!(out, "{}", event)?;
+                if event.is_syn_report() {
+                    out.flush()?;
+                }
+            }
+            Err(error) if error.raw_os_error() == Some(libc::EAGAIN) => (),
+            Err(error) if error.raw_os_error() == Some(libc::ENODEV) => {
+                log::info!("event device disconnected");
+                return Ok(());
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+
+/// Event fed to the keyboard during a replay, and the actions it resulted in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayStep {
+    /// Time elapsed since the start of the recording
+    pub time: Duration,
+    pub event: Event<EV_KEY>,
+    pub actions: Vec<Action<EV_KEY>>,
+}
+
+impl fmt::Display for ReplayStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>4}.{:06}  {:<28} {:?}",
+               self.time.as_secs(), self.time.subsec_micros(), describe_event(&self.event), self.actions)
+    }
+}
+
+/// Feed the key events of `recording` into `keyboard`, which must run on `clock`,
+/// the way the runtime would have fed them.
+///
+/// The keyboard is polled every `poll_period`, or earlier when it has a deadline, and whenever
+/// a frame of events is read. Time only moves on `clock`, so replays are deterministic and
+/// as fast as the keyboard allows. Polls are only returned when they resulted in actions.
+pub fn replay<K>(recording: &Recording, keyboard: &mut K, clock: &SimulatedClock, poll_period: Duration) -> Vec<ReplayStep>
+where
+    K: Keyboard<EV_KEY, EV_KEY>
+{
+    let mut replayer = Replayer {
+        keyboard,
+        clock,
+        start: clock.now(),
+        poll_period,
+        last_wake: Duration::ZERO,
+        steps: Vec::new(),
+    };
+    let mut frame = Vec::new();
+
+    for event in recording.events.iter() {
+        if let Some(key_event) = event.key_event() {
+            frame.push(key_event);
+        }
+        if event.is_syn_report() {
+            replayer.wake_at(event.time);
+            for key_event in frame.drain(..) {
+                replayer.feed(event.time, key_event);
+            }
+        }
+    }
+
+    // timeouts still running at the end of the recording are let expire
+    let end = replayer.last_wake + poll_period;
+    replayer.wake_at(end);
+    replayer.steps
+}
+
+/// State of a replay, see `replay`.
+struct Replayer<'a, K> {
+    keyboard: &'a mut K,
+    clock: &'a SimulatedClock,
+    start: Instant,
+    poll_period: Duration,
+    /// Time the keyboard was last polled at
+    last_wake: Duration,
+    steps: Vec<ReplayStep>,
+}
+
+impl<'a, K: Keyboard<EV_KEY, EV_KEY>> Replayer<'a, K> {
+
+    /// Poll the keyboard at the times the runtime would have until `time`, then at `time`.
+    /// Times earlier than the last wake, which parsed recordings don't have, are replayed at the last wake.
+    fn wake_at(&mut self, time: Duration) {
+        let time = time.max(self.last_wake);
+        loop {
+            let mut wake = self.last_wake + self.poll_period;
+            if let Some(deadline) = self.keyboard.next_deadline() {
+                wake = wake.min(deadline.saturating_duration_since(self.start));
+            }
+            // a deadline which is already due is handled on the next wake
+            let wake = wake.max(self.last_wake + MIN_WAKE_INTERVAL);
+            if wake >= time {
+                break;
+            }
+            self.feed(wake, Event::Poll);
+        }
+        self.feed(time, Event::Poll);
+    }
+
+    fn feed(&mut self, time: Duration, event: Event<EV_KEY>) {
+        if event == Event::Poll {
+            self.clock.set(self.start + time);
+            self.last_wake = time;
+        }
+        let actions = self.keyboard.transition(event);
+        if event != Event::Poll || !actions.is_empty() {
+            self.steps.push(ReplayStep { time, event, actions });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const RECORDING: &str = "\
+# EVEMU 1.3
+N: AT Translated Set 2 keyboard
+I: 0011 0001 0001 ab41
+E: 0.000000 0004 0004 0058
+E: 0.000000 0001 003a 0001
+E: 0.000000 0000 0000 0000
+E: 0.500000 0001 003a 0002
+E: 0.500000 0000 0000 0000
+E: 0.600000 0001 003a 0000
+E: 0.600000 0000 0000 0000
+";
+
+    #[test]
+    fn test_parse_and_write_round_trip() {
+        let recording: Recording = RECORDING.parse().unwrap();
+
+        assert_eq!(recording.name, "AT Translated Set 2 keyboard");
+        assert_eq!((recording.vendor_id, recording.product_id), (1, 1));
+        assert_eq!(recording.events.len(), 7);
+        assert_eq!(recording.events[3], RecordedEvent {
+            time: Duration::from_millis(500),
+            event_type: 1,
+            code: EV_KEY::KEY_CAPSLOCK as u16,
+            value: 2,
+        });
+        assert_eq!(recording.events[1].key_event(), Some(Event::KeyPress(EV_KEY::KEY_CAPSLOCK)));
+        assert_eq!(recording.events[3].key_event(), None);
+        assert!(recording.events[2].is_syn_report());
+
+        let mut written = Vec::new();
+        recording.write(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.contains("E: 0.500000 0001 003a 0002\n"), "{}", written);
+        assert_eq!(written.parse::<Recording>().unwrap(), recording);
+    }
+
+    #[test]
+    fn test_parse_reports_invalid_line() {
+        let err = "N: keyboard\nE: 0.000000 0001 zz 0001\n".parse::<Recording>().unwrap_err();
+
+        assert_eq!(err.line, 2);
+        assert_eq!(err.reason, "invalid event code");
+
+        let err = "E: 0.500000 0000 0000 0000\nE: 0.400000 0000 0000 0000\n".parse::<Recording>().unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.reason, "time is earlier than the previous event's");
+    }
+
+    #[test]
+    fn test_replay_resolves_hold_on_simulated_time() {
+        let config: Config = r#"
+            [settings]
+            hold_ksm_delay = 250
+
+            [[layers]]
+            id = 0
+            [layers.keys]
+            KEY_CAPSLOCK = { behavior = "hold", tap = "KEY_ESC", hold = "KEY_LEFTCTRL" }
+        "#.parse().unwrap();
+        let recording: Recording = RECORDING.parse().unwrap();
+        let mut keyboard = config.keymap_for(&recording.device()).build_keyboard();
+        let clock = SimulatedClock::new(Instant::now());
+        keyboard.set_clock(clock.clone());
+
+        let steps = replay(&recording, &mut keyboard, &clock, Duration::from_millis(100));
+
+        assert_eq!(steps, vec![
+            ReplayStep {
+                time: Duration::ZERO,
+                event: Event::KeyPress(EV_KEY::KEY_CAPSLOCK),
+                actions: vec![],
+            },
+            // the hold is resolved by the first poll after the hold delay
+            ReplayStep {
+                time: Duration::from_millis(300),
+                event: Event::Poll,
+                actions: vec![Action::SendCode(EV_KEY::KEY_LEFTCTRL)],
+            },
+            ReplayStep {
+                time: Duration::from_millis(600),
+                event: Event::KeyRelease(EV_KEY::KEY_CAPSLOCK),
+                actions: vec![Action::Stop(EV_KEY::KEY_LEFTCTRL)],
+            },
+        ]);
+        assert_eq!(steps[1].to_string(), "   0.300000  poll                         [SendCode(KEY_LEFTCTRL)]");
+    }
+}
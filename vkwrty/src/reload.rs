@@ -0,0 +1,208 @@
+//! Configuration hot reload.
+//!
+//! A reload is requested either by sending `SIGHUP` to the process or by
+//! writing to the configuration file.
+//! Both are exposed as file descriptors so they can be monitored through epoll
+//! alongside the input devices.
+
+use std::ffi::OsString;
+use std::io::{Error, Result};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::prelude::RawFd;
+use std::path::Path;
+
+use libc;
+
+use crate::inotify::Inotify;
+
+
+/// Receives signals (eg `SIGHUP`) through a `signalfd`.
+///
+/// The signals are blocked for the calling thread so that they're only delivered
+/// through the file descriptor, hence the `SignalFd` should be created
+/// before any other thread is spawned.
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    pub fn new(signals: &[libc::c_int]) -> Result<Self> {
+        unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            for signal in signals {
+                libc::sigaddset(&mut mask, *signal);
+            }
+
+            let rv = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            if rv != 0 {
+                return Err(Error::from_raw_os_error(rv));
+            }
+
+            let fd = libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(Self { fd })
+        }
+    }
+
+    /// Consume pending signals, return whether any was received.
+    pub fn drain(&mut self) -> bool {
+        let mut received = false;
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+
+        loop {
+            let read = unsafe {
+                libc::read(self.fd, &mut info as *mut _ as *mut libc::c_void, size)
+            };
+            if read < size as isize {
+                return received;
+            }
+            received = true;
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+
+/// Watches a file for modifications through `inotify`.
+///
+/// The parent directory is watched rather than the file itself,
+/// since editors often save files by replacing them.
+/// Files are only reported once written and closed (or moved in place), a file just created
+/// being empty yet.
+pub struct FileWatcher {
+    inotify: Inotify,
+    file_name: OsString,
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Result<Self> {
+        let file_name = path.file_name()
+            .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidInput, "watched path is not a file"))?
+            .to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut inotify = Inotify::new()?;
+        inotify.add_watch(dir, libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO)?;
+        Ok(Self { inotify, file_name })
+    }
+
+    /// Consume pending notifications, return whether any of them concerns the watched file.
+    pub fn drain(&mut self) -> bool {
+        self.inotify.read_events()
+            .iter()
+            .any(|(_, name)| *name == self.file_name)
+    }
+}
+
+impl AsRawFd for FileWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+
+/// Reloads the configuration of keyboard `K`, see `Reloader`.
+pub type ReloadFn<K> = Box<dyn FnMut(&mut K) -> crate::Result<()>>;
+
+/// Reloads a keyboard's configuration on `SIGHUP` or when the configuration file is written.
+///
+/// The reload itself is delegated to a closure, which is expected to validate the new
+/// configuration and leave the keyboard untouched on errors.
+pub struct Reloader<K> {
+    signal: SignalFd,
+    watcher: FileWatcher,
+    reload: ReloadFn<K>,
+}
+
+impl<K> Reloader<K> {
+    pub fn new(path: &Path, reload: impl FnMut(&mut K) -> crate::Result<()> + 'static) -> Result<Self> {
+        Ok(Self {
+            signal: SignalFd::new(&[libc::SIGHUP])?,
+            watcher: FileWatcher::new(path)?,
+            reload: Box::new(reload),
+        })
+    }
+
+    /// File descriptors which must be monitored for reload requests.
+    pub fn fds(&self) -> [RawFd; 2] {
+        [self.signal.as_raw_fd(), self.watcher.as_raw_fd()]
+    }
+
+    /// Handle pending reload requests, reloading the keyboard at most once.
+    /// Return whether the keyboard was reloaded.
+    pub fn handle_requests(&mut self, keyboard: &mut K) -> bool {
+        let signaled = self.signal.drain();
+        let changed = self.watcher.drain();
+        if !(signaled || changed) {
+            return false;
+        }
+
+        match self.reload(keyboard) {
+            Ok(()) => true,
+            Err(err) => {
+                log::error!("failed to reload configuration, keeping the current one: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Reload the keyboard right away, eg when asked through the control socket.
+    pub fn reload(&mut self, keyboard: &mut K) -> crate::Result<()> {
+        (self.reload)(keyboard)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file_watcher_detects_writes_to_watched_file_only() {
+        let dir = std::env::temp_dir().join(format!("vkwrty-reload-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keymap.toml");
+        fs::write(&path, "").unwrap();
+
+        let mut watcher = FileWatcher::new(&path).unwrap();
+        assert!(!watcher.drain());
+
+        fs::write(dir.join("other.toml"), "").unwrap();
+        assert!(!watcher.drain());
+
+        fs::write(&path, "default_layer = 1").unwrap();
+        assert!(watcher.drain());
+        assert!(!watcher.drain());
+
+        // a file created anew is reported once closed
+        fs::remove_file(&path).unwrap();
+        let file = fs::File::create(&path).unwrap();
+        assert!(!watcher.drain());
+        drop(file);
+        assert!(watcher.drain());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
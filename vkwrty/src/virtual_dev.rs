@@ -1,17 +1,52 @@
 /// Represents a virtual uinput device.
 /// Initializes device and emits events
 
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use std::time::SystemTime;
 use std::iter::once;
 
-use evdev_rs::{TimeVal, UInputDevice, InputEvent, UninitDevice, DeviceWrapper};
-use evdev_rs::enums::{EV_SYN, EV_KEY, EventType, EventCode, int_to_ev_key};
+use evdev_rs::{Device, TimeVal, UInputDevice, InputEvent, UninitDevice, DeviceWrapper, EnableCodeData};
+use evdev_rs::enums::{EV_SYN, EV_KEY, EV_MSC, EventType, EventCode, int_to_event_code, int_to_input_prop};
 use keywerty::keyboard::Action;
 
+use crate::devices::DeviceInfo;
+use crate::keynames;
+use crate::monitor::EventSink;
 use crate::Result;
 use crate::Error;
 
 
+/// Device of the kernel's `uinput` module, through which virtual devices are created
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+/// Name prefix of the passthrough devices, see `PassthroughDevice`
+pub const PASSTHROUGH_NAME: &str = "vkwrty passthrough";
+
+/// Writes fail transiently when the kernel's buffer is full,
+/// they're attempted this many times before giving up
+const WRITE_ATTEMPTS: u32 = 5;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Events for which devices get a mirror, see `PassthroughDevice`.
+/// The `EV_MSC` events of those devices are passed through as well, except for scan codes,
+/// the keys being remapped.
+const PASSTHROUGH_EVENTS: [EventType; 4] = [EventType::EV_REL, EventType::EV_ABS, EventType::EV_SW, EventType::EV_SND];
+
+/// Events of the devices copied to their mirror, along with their highest code
+/// as defined in linux's `input-event-codes.h` header.
+const MIRRORED_EVENTS: &[(EventType, u32)] = &[
+    (EventType::EV_KEY, 0x2ff),
+    (EventType::EV_REL, 0x0f),
+    (EventType::EV_ABS, 0x3f),
+    (EventType::EV_MSC, 0x07),
+    (EventType::EV_SW, 0x10),
+    (EventType::EV_SND, 0x07),
+];
+const INPUT_PROP_MAX: u32 = 0x1f;
+
 /// Models a Virtual Linux device, based on the kernel's `uinput` module.
 /// The virtual device is used to emit IO Events, allowing us to create "virtual" keyboard / mouses
 /// and etc.
@@ -32,13 +67,14 @@ impl UInputKeyboard {
         dev.set_name(name);
         dev.enable(&EventType::EV_KEY)?;
 
-        Self::get_ev_keys()
+        keynames::all_keys()
             .map(|key_code| EventCode::EV_KEY(key_code))
             .map(|event_code| dev.enable(&event_code))
             .fold(Ok(()), |acc, result| acc.and(result))?;
                 
-        let uinput_dev = UInputDevice::create_from_device(&dev)?;
-        eprintln!("created uinput device: {:?}", uinput_dev.syspath().unwrap());
+        let uinput_dev = UInputDevice::create_from_device(&dev)
+            .map_err(|err| Error::device_open(Path::new(UINPUT_PATH), err))?;
+        log::info!("created uinput device: {}", uinput_dev.syspath().unwrap_or("unknown syspath"));
         Ok(Self { dev: uinput_dev })
     }
 
@@ -46,7 +82,7 @@ impl UInputKeyboard {
     ///
     /// Reports are chain of events terminated with a `SYN_REPORT` event.
     pub fn emit_events(&mut self, actions: &[Action<EV_KEY>]) -> Result<()> {
-        let timeval = Self::build_timeval();
+        let timeval = Self::build_timeval()?;
 
         // According to the examples in the docs, `SYN_REPORT` events should
         // have 0 as the value.
@@ -63,14 +99,18 @@ impl UInputKeyboard {
         actions.iter()
             .map(|action| Self::action_to_input_event(&timeval, action))
             .chain(once(report_event))
-            .map(|input_event| {
-                eprintln!("emitting event: {:?}", input_event);
-                self.dev.write_event(&input_event)
+            .try_for_each(|input_event| {
+                log::trace!("emitting event: {:?}", input_event);
+                self.write_event(&input_event)
             })
-            .fold(Ok(()), |acc, result| acc.and(result))
             .map_err(|e| e.into())
     }
 
+    /// Write `event` to the uinput device, retrying transient failures.
+    fn write_event(&self, event: &InputEvent) -> io::Result<()> {
+        write_event(&self.dev, event)
+    }
+
     fn action_to_input_event(timeval: &TimeVal, action: &Action<EV_KEY>) -> InputEvent {
         match action {
             Action::SendCode(ev_key) => InputEvent::new(&timeval, &EventCode::EV_KEY(*ev_key), 1),
@@ -79,23 +119,105 @@ impl UInputKeyboard {
     }
 
     /// Return an evdev `TimeVal` for the current instant
-    fn build_timeval() -> TimeVal {
+    fn build_timeval() -> Result<TimeVal> {
         let now = SystemTime::now();
-        TimeVal::try_from(now).unwrap()
+        Ok(TimeVal::try_from(now)?)
+    }
+}
+
+
+/// Virtual device mirroring the capabilities of an input device, through which the events
+/// vkwrty doesn't remap (eg the motion of a trackpoint or the turns of a volume knob) are
+/// passed through unchanged, along with the buttons of the device (eg the clicks of the trackpoint).
+///
+/// Mirrors are named after `PASSTHROUGH_NAME` so that vkwrty doesn't intercept them.
+pub struct PassthroughDevice {
+    dev: UInputDevice
+}
+
+impl PassthroughDevice {
+
+    /// Create the mirror of `device`, whose event file is at `path`.
+    ///
+    /// The mirror has the keys, properties and passthrough events of `device`, so that it's
+    /// handled like it (eg as a pointer), but not its scan codes and LEDs.
+    pub fn mirror(device: &Device, path: &Path) -> Result<Self> {
+        let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let dev = UninitDevice::new().ok_or(Error::DeviceInit)?;
+        dev.set_name(&format!("{} {}", PASSTHROUGH_NAME, file_name));
+        dev.set_bustype(device.bustype());
+        dev.set_vendor_id(device.vendor_id());
+        dev.set_product_id(device.product_id());
+        dev.set_version(device.version());
+
+        for prop in (0..=INPUT_PROP_MAX).filter_map(int_to_input_prop) {
+            if device.has_property(&prop) {
+                dev.enable_property(&prop)?;
+            }
+        }
+        for (event_type, max_code) in MIRRORED_EVENTS {
+            if !device.has_event_type(event_type) {
+                continue;
+            }
+            dev.enable_event_type(event_type)?;
+            for code in 0..=*max_code {
+                let event_code = int_to_event_code(*event_type as u32, code);
+                if !device.has_event_code(&event_code) || event_code == EventCode::EV_MSC(EV_MSC::MSC_SCAN) {
+                    continue;
+                }
+                // absolute axes can't be enabled without their range
+                let data = device.abs_info(&event_code).map(EnableCodeData::AbsInfo);
+                dev.enable_event_code(&event_code, data)?;
+            }
+        }
+
+        let uinput_dev = UInputDevice::create_from_device(&dev)
+            .map_err(|err| Error::device_open(Path::new(UINPUT_PATH), err))?;
+        log::info!("created passthrough device for {}: {}", path.display(), uinput_dev.devnode().unwrap_or("unknown devnode"));
+        Ok(Self { dev: uinput_dev })
     }
 
-    /// Return iterator with every EV_KEY variant
-    fn get_ev_keys() -> impl Iterator<Item=EV_KEY> {
-        // This is kinda bad but...
-        // For some reason the evdev crate does not provide a method that returns
-        // all variants for `EV_KEY`
-        // EV_KEY events apparently range from 0 to ~750.
-        //
-        // Anyway, so instead copying the definition and goin line by line we can waste a few CPU
-        // cycles here and not repeat the whole thing
-        (0..1000)
-            .map(int_to_ev_key)
-            .filter(|res| res.is_some())
-            .map(|res| res.unwrap())
+    /// Whether `device` is worth mirroring, ie whether it has events which aren't handled by
+    /// the keyboards and which the OS needs (eg motion or switches).
+    pub fn has_passthrough_events(device: &Device) -> bool {
+        PASSTHROUGH_EVENTS.iter().any(|event_type| device.has_event_type(event_type))
+    }
+}
+
+impl EventSink for PassthroughDevice {
+    fn write_frame(&self, events: &[InputEvent]) -> io::Result<()> {
+        let time = events.last().map(|event| event.time).unwrap_or_else(|| TimeVal::new(0, 0));
+        let report_event = InputEvent::new(&time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+        events.iter()
+            .chain(once(&report_event))
+            .try_for_each(|event| {
+                log::trace!("passing event through: {:?}", event);
+                write_event(&self.dev, event)
+            })
     }
 }
+
+/// Whether `device` is a passthrough device created by vkwrty.
+pub fn is_passthrough_device(device: &DeviceInfo) -> bool {
+    device.name.starts_with(PASSTHROUGH_NAME)
+}
+
+/// Write `event` to `dev`, retrying transient failures.
+fn write_event(dev: &UInputDevice, event: &InputEvent) -> io::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match dev.write_event(event) {
+            Err(err) if attempt < WRITE_ATTEMPTS && is_transient(&err) => {
+                log::debug!("failed to write event, retrying: {}", err);
+                thread::sleep(WRITE_RETRY_DELAY);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+        || err.raw_os_error() == Some(libc::ENOBUFS)
+}
@@ -0,0 +1,84 @@
+//! Watchdog handing the input devices back to the OS when the runtime hangs.
+//!
+//! A grabbed device only sends its events to vkwrty, so a runtime stuck in a loop
+//! (or a deadlock) leaves the keyboard unusable until vkwrty is killed.
+
+use std::io;
+use std::os::unix::prelude::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::monitor;
+
+
+struct State {
+    heartbeat: Mutex<Instant>,
+    /// File descriptors of the grabbed devices
+    grabbed: Mutex<Vec<RawFd>>,
+    fired: AtomicBool,
+}
+
+/// Ungrabs the devices once the runtime went `timeout` without a heartbeat.
+///
+/// The watchdog runs in its own thread, which ends when the `Watchdog` is dropped.
+/// Signals handled through a `SignalFd` must be blocked before the watchdog is started,
+/// since threads inherit the signal mask of their parent.
+pub struct Watchdog {
+    state: Arc<State>,
+}
+
+impl Watchdog {
+    pub fn start(timeout: Duration) -> io::Result<Self> {
+        let state = Arc::new(State {
+            heartbeat: Mutex::new(Instant::now()),
+            grabbed: Mutex::new(Vec::new()),
+            fired: AtomicBool::new(false),
+        });
+
+        let watched = Arc::downgrade(&state);
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || watch(watched, timeout))?;
+        Ok(Self { state })
+    }
+
+    /// Signal that the runtime is making progress.
+    pub fn beat(&self) {
+        *self.state.heartbeat.lock().unwrap() = Instant::now();
+    }
+
+    /// Set the file descriptors of the devices to ungrab if the runtime hangs.
+    pub fn set_grabbed(&self, fds: Vec<RawFd>) {
+        *self.state.grabbed.lock().unwrap() = fds;
+    }
+
+    /// Whether the devices were ungrabbed, in which case the runtime should stop.
+    pub fn has_fired(&self) -> bool {
+        self.state.fired.load(Ordering::SeqCst)
+    }
+}
+
+fn watch(state: Weak<State>, timeout: Duration) {
+    loop {
+        thread::sleep(timeout / 4);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let stalled = state.heartbeat.lock().unwrap().elapsed();
+        if stalled >= timeout && !state.fired.load(Ordering::SeqCst) {
+            log::error!("runtime made no progress for {:?}, releasing devices", stalled);
+            // the lock is held while ungrabbing so the descriptors can't be closed meanwhile
+            let grabbed = state.grabbed.lock().unwrap();
+            for fd in grabbed.iter() {
+                if let Err(err) = monitor::set_grab(*fd, false) {
+                    log::error!("failed to release device: {}", err);
+                }
+            }
+            state.fired.store(true, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Runs the daemon loop over in memory devices.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use evdev_rs::enums::EV_KEY;
+use keywerty::keyboard::{Action, Event, SMKeyboard, SMKeyboardSettings};
+use keywerty::keys::{HoldKeyConf, KeyAction, KeyActionSet, KeyConf};
+use keywerty::mapper::MapOrEchoMapper;
+
+use vkwrty::Attach;
+use vkwrty::Runtime;
+use vkwrty::control;
+use vkwrty::control::{KeyboardStatus, Request, Response, StatusEvent};
+use vkwrty::devices::DeviceInfo;
+use vkwrty::emergency::{EmergencyAction, EmergencyConf};
+use vkwrty::memory::{MemoryInput, MemoryOutput};
+
+type TestKeyboard = SMKeyboard<EV_KEY, EV_KEY, MapOrEchoMapper<EV_KEY>>;
+type TestRuntime = Runtime<TestKeyboard, MemoryInput, MemoryOutput>;
+
+const HOLD_DELAY: Duration = Duration::from_millis(50);
+
+
+/// Keyboard echoing every key but caps lock, which is escape on tap and control on hold
+fn build_keyboard() -> TestKeyboard {
+    let mut map = HashMap::new();
+    map.insert((0, EV_KEY::KEY_CAPSLOCK), KeyConf::Hold(HoldKeyConf {
+        tap: KeyActionSet::Single(KeyAction::SendKey(EV_KEY::KEY_ESC)),
+        hold: KeyActionSet::Single(KeyAction::SendKey(EV_KEY::KEY_LEFTCTRL)),
+        retro_tap: false,
+    }));
+    let settings = SMKeyboardSettings { hold_ksm_delay: HOLD_DELAY, ..SMKeyboardSettings::default() };
+    SMKeyboard::new(0, MapOrEchoMapper(map), settings)
+}
+
+fn build_runtime() -> (TestRuntime, Receiver<Action<EV_KEY>>) {
+    let (output, actions) = MemoryOutput::new();
+    let runtime = Runtime::new(output, build_keyboard(), Duration::from_millis(10)).unwrap();
+    (runtime, actions)
+}
+
+fn device(name: &str) -> DeviceInfo {
+    DeviceInfo {
+        path: PathBuf::from(format!("/dev/input/{}", name)),
+        name: name.to_string(),
+        phys: None,
+        vendor_id: 0,
+        product_id: 0,
+        has_keys: true,
+        is_keyboard: true,
+        by_id: Vec::new(),
+    }
+}
+
+/// Run the runtime until `count` actions were emitted, or a second went by.
+fn run_until(runtime: &mut TestRuntime, actions: &Receiver<Action<EV_KEY>>, count: usize) -> Vec<Action<EV_KEY>> {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut emitted = Vec::new();
+    while emitted.len() < count && Instant::now() < deadline {
+        runtime.run_once().unwrap();
+        emitted.extend(actions.try_iter());
+    }
+    emitted
+}
+
+/// Socket path unique to the test `name`
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("vkwrty-test-{}-{}.sock", process::id(), name))
+}
+
+/// Send `request` to the control socket at `path`, running the runtime until it answered.
+fn send_request(runtime: &mut TestRuntime, path: &Path, request: Request) -> Response {
+    let path = path.to_path_buf();
+    let client = thread::spawn(move || control::send_request(&path, &request));
+    while !client.is_finished() {
+        runtime.run_once().unwrap();
+    }
+    client.join().unwrap().unwrap()
+}
+
+
+#[test]
+fn test_runtime_echoes_keys() {
+    let (mut runtime, actions) = build_runtime();
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    sender.press(EV_KEY::KEY_A).unwrap();
+    sender.release(EV_KEY::KEY_A).unwrap();
+
+    assert_eq!(run_until(&mut runtime, &actions, 2), vec![
+        Action::SendCode(EV_KEY::KEY_A),
+        Action::Stop(EV_KEY::KEY_A),
+    ]);
+}
+
+#[test]
+fn test_tracer_reports_events_with_their_outcome() {
+    let (mut runtime, actions) = build_runtime();
+    let traces = Rc::new(RefCell::new(Vec::new()));
+    let tracer_traces = Rc::clone(&traces);
+    runtime.set_tracer(move |trace| {
+        let device = trace.device.map(|device| device.name.clone());
+        tracer_traces.borrow_mut().push((device, trace.event, trace.layer, trace.status.to_vec(), trace.actions.to_vec()));
+    });
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    sender.press(EV_KEY::KEY_CAPSLOCK).unwrap();
+    sender.press(EV_KEY::KEY_A).unwrap();
+    run_until(&mut runtime, &actions, 2);
+
+    let device = Some("event0".to_string());
+    assert_eq!(*traces.borrow(), vec![
+        (device.clone(), Event::KeyPress(EV_KEY::KEY_CAPSLOCK), Some(0), vec![
+            StatusEvent::BehaviorPending { keyboard: 0, key: "KEY_CAPSLOCK".to_string() },
+        ], vec![]),
+        (device, Event::KeyPress(EV_KEY::KEY_A), Some(0), vec![
+            StatusEvent::BehaviorResolved { keyboard: 0, key: "KEY_CAPSLOCK".to_string(), behavior: Some("hold".to_string()) },
+        ], vec![Action::SendCode(EV_KEY::KEY_LEFTCTRL), Action::SendCode(EV_KEY::KEY_A)]),
+    ]);
+}
+
+#[test]
+fn test_runtime_polls_keyboards_for_holds() {
+    let (mut runtime, actions) = build_runtime();
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    let pressed_at = Instant::now();
+    sender.press(EV_KEY::KEY_CAPSLOCK).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::SendCode(EV_KEY::KEY_LEFTCTRL)]);
+    assert!(pressed_at.elapsed() >= HOLD_DELAY);
+
+    sender.release(EV_KEY::KEY_CAPSLOCK).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::Stop(EV_KEY::KEY_LEFTCTRL)]);
+}
+
+#[test]
+fn test_runtime_resets_keyboards_of_disconnected_devices() {
+    let (mut runtime, actions) = build_runtime();
+    let (shared_input, mut shared_sender) = MemoryInput::new().unwrap();
+    let (own_input, mut own_sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(shared_input, device("event0"), Attach::Shared).unwrap();
+    runtime.attach_source(own_input, device("event1"), Attach::Own(build_keyboard())).unwrap();
+
+    shared_sender.press(EV_KEY::KEY_LEFTSHIFT).unwrap();
+    own_sender.press(EV_KEY::KEY_RIGHTALT).unwrap();
+    let mut emitted = run_until(&mut runtime, &actions, 2);
+    emitted.sort_by_key(|action| format!("{:?}", action));
+    assert_eq!(emitted, vec![
+        Action::SendCode(EV_KEY::KEY_LEFTSHIFT),
+        Action::SendCode(EV_KEY::KEY_RIGHTALT),
+    ]);
+
+    // only the keyboard fed by the unplugged device is reset
+    drop(own_sender);
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::Stop(EV_KEY::KEY_RIGHTALT)]);
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![]);
+
+    shared_sender.release(EV_KEY::KEY_LEFTSHIFT).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::Stop(EV_KEY::KEY_LEFTSHIFT)]);
+}
+
+#[test]
+fn test_runtime_shutdown_releases_held_outputs() {
+    let (mut runtime, actions) = build_runtime();
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    sender.press(EV_KEY::KEY_LEFTSHIFT).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::SendCode(EV_KEY::KEY_LEFTSHIFT)]);
+
+    runtime.stop();
+    assert!(!runtime.is_running());
+    runtime.shutdown().unwrap();
+    assert_eq!(actions.try_iter().collect::<Vec<_>>(), vec![Action::Stop(EV_KEY::KEY_LEFTSHIFT)]);
+}
+
+#[test]
+fn test_emergency_chord_stops_runtime() {
+    let (mut runtime, actions) = build_runtime();
+    runtime.set_emergency(EmergencyConf { chord: vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_ESC], action: EmergencyAction::Exit });
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    sender.press(EV_KEY::KEY_LEFTCTRL).unwrap();
+    sender.press(EV_KEY::KEY_ESC).unwrap();
+    sender.press(EV_KEY::KEY_A).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::SendCode(EV_KEY::KEY_LEFTCTRL)]);
+    assert!(!runtime.is_running());
+
+    runtime.shutdown().unwrap();
+    assert_eq!(actions.try_iter().collect::<Vec<_>>(), vec![Action::Stop(EV_KEY::KEY_LEFTCTRL)]);
+}
+
+#[test]
+fn test_emergency_chord_toggles_passthrough() {
+    let (mut runtime, actions) = build_runtime();
+    runtime.set_emergency(EmergencyConf { chord: vec![EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_ESC], action: EmergencyAction::Passthrough });
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+
+    // caps lock is sent as is instead of being held as control
+    sender.press(EV_KEY::KEY_LEFTCTRL).unwrap();
+    sender.press(EV_KEY::KEY_ESC).unwrap();
+    sender.release(EV_KEY::KEY_ESC).unwrap();
+    sender.release(EV_KEY::KEY_LEFTCTRL).unwrap();
+    sender.press(EV_KEY::KEY_CAPSLOCK).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 3), vec![
+        Action::SendCode(EV_KEY::KEY_LEFTCTRL),
+        Action::Stop(EV_KEY::KEY_LEFTCTRL),
+        Action::SendCode(EV_KEY::KEY_CAPSLOCK),
+    ]);
+
+    // leaving the passthrough mode releases the keys it held
+    sender.press(EV_KEY::KEY_LEFTCTRL).unwrap();
+    sender.press(EV_KEY::KEY_ESC).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 3), vec![
+        Action::SendCode(EV_KEY::KEY_LEFTCTRL),
+        Action::Stop(EV_KEY::KEY_CAPSLOCK),
+        Action::Stop(EV_KEY::KEY_LEFTCTRL),
+    ]);
+    assert!(runtime.is_running());
+}
+
+#[test]
+fn test_control_socket_commands() {
+    let (mut runtime, actions) = build_runtime();
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+    let path = socket_path("commands");
+    runtime.listen(&path).unwrap();
+
+    let response = send_request(&mut runtime, &path, Request::SetLayer { layer: 1, keyboard: None });
+    assert_eq!(response, Response::ok());
+    let response = send_request(&mut runtime, &path, Request::Status);
+    assert_eq!(response.paused, Some(false));
+    assert_eq!(response.keyboards, vec![KeyboardStatus { keyboard: 0, device: None, layer: Some(1) }]);
+    assert!(!send_request(&mut runtime, &path, Request::SetLayer { layer: 1, keyboard: Some(3) }).ok);
+
+    let response = send_request(&mut runtime, &path, Request::Type { keys: vec!["h".to_string(), "leftshift+i".to_string()] });
+    assert_eq!(response, Response::ok());
+    assert_eq!(actions.try_iter().collect::<Vec<_>>(), vec![
+        Action::SendCode(EV_KEY::KEY_H),
+        Action::Stop(EV_KEY::KEY_H),
+        Action::SendCode(EV_KEY::KEY_LEFTSHIFT),
+        Action::SendCode(EV_KEY::KEY_I),
+        Action::Stop(EV_KEY::KEY_I),
+        Action::Stop(EV_KEY::KEY_LEFTSHIFT),
+    ]);
+    assert!(!send_request(&mut runtime, &path, Request::Type { keys: vec!["nope".to_string()] }).ok);
+    assert!(!send_request(&mut runtime, &path, Request::Reload).ok);
+
+    // caps lock is sent as is while paused
+    assert_eq!(send_request(&mut runtime, &path, Request::Pause), Response::ok());
+    sender.press(EV_KEY::KEY_CAPSLOCK).unwrap();
+    assert_eq!(run_until(&mut runtime, &actions, 1), vec![Action::SendCode(EV_KEY::KEY_CAPSLOCK)]);
+    assert_eq!(send_request(&mut runtime, &path, Request::Resume), Response::ok());
+    assert_eq!(actions.try_iter().collect::<Vec<_>>(), vec![Action::Stop(EV_KEY::KEY_CAPSLOCK)]);
+
+    runtime.shutdown().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_control_socket_subscription() {
+    let (mut runtime, actions) = build_runtime();
+    let path = socket_path("subscription");
+    runtime.listen(&path).unwrap();
+
+    // the subscriber forwards every line it reads, starting with the response
+    let (lines, received) = mpsc::channel();
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"{\"command\":\"subscribe\"}\n").unwrap();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            if lines.send(line.unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+    let receive = |runtime: &mut TestRuntime, count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut received_lines = Vec::new();
+        while received_lines.len() < count && Instant::now() < deadline {
+            runtime.run_once().unwrap();
+            received_lines.extend(received.try_iter());
+        }
+        received_lines
+    };
+    assert_eq!(receive(&mut runtime, 1), vec![r#"{"ok":true}"#]);
+
+    let (input, mut sender) = MemoryInput::new().unwrap();
+    runtime.attach_source(input, device("event0"), Attach::Shared).unwrap();
+    sender.press(EV_KEY::KEY_CAPSLOCK).unwrap();
+    let events: Vec<StatusEvent> = receive(&mut runtime, 3).iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events, vec![
+        StatusEvent::DeviceAttached { device: "event0".to_string(), path: PathBuf::from("/dev/input/event0") },
+        StatusEvent::BehaviorPending { keyboard: 0, key: "KEY_CAPSLOCK".to_string() },
+        StatusEvent::BehaviorResolved { keyboard: 0, key: "KEY_CAPSLOCK".to_string(), behavior: Some("hold".to_string()) },
+    ]);
+    assert_eq!(actions.try_iter().collect::<Vec<_>>(), vec![Action::SendCode(EV_KEY::KEY_LEFTCTRL)]);
+
+    runtime.set_paused(true);
+    drop(sender);
+    let events: Vec<StatusEvent> = receive(&mut runtime, 2).iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events, vec![
+        StatusEvent::Paused { paused: true },
+        StatusEvent::DeviceDetached { device: "event0".to_string(), path: PathBuf::from("/dev/input/event0") },
+    ]);
+}
+
+#[test]
+fn test_control_socket_drops_clients_sending_long_lines() {
+    let (mut runtime, _) = build_runtime();
+    let path = socket_path("long-lines");
+    runtime.listen(&path).unwrap();
+
+    let stream = UnixStream::connect(&path).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        // the write fails once the client is dropped
+        let mut requests = b"{\"command\":\"pause\"}\n".to_vec();
+        requests.extend(vec![b' '; control::MAX_REQUEST_LEN + 1]);
+        let _ = writer.write_all(&requests);
+    });
+    let (lines, received) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = lines.send(BufReader::new(stream).lines().collect::<Result<Vec<_>, _>>());
+    });
+
+    // the client is told why, then its socket is closed, its other requests being ignored
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let lines = loop {
+        runtime.run_once().unwrap();
+        if let Ok(lines) = received.try_recv() {
+            break lines.unwrap();
+        }
+        assert!(Instant::now() < deadline, "the client wasn't disconnected");
+    };
+    assert_eq!(lines.len(), 1);
+    let response: Response = serde_json::from_str(&lines[0]).unwrap();
+    assert!(!response.ok);
+    assert!(response.error.unwrap().contains("too long"));
+}
@@ -22,6 +22,7 @@ pub fn build_mapper() -> MapOrEchoMapper<EV_KEY> {
             keys::HoldKeyConf { 
                 tap: keys::KeyActionSet::Single(keys::KeyAction::SendKey(EV_KEY::KEY_ESC)),
                 hold: keys::KeyActionSet::Single(keys::KeyAction::SendKey(EV_KEY::KEY_LEFTCTRL)),
+                retro_tap: false,
         })
     );
 